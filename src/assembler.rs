@@ -0,0 +1,1165 @@
+//! Assembler for Octo (`.8o`) sources.
+//!
+//! Handles labels, `:alias`, `:const`, `:calc`, `:macro`, `:org`, `:byte`,
+//! `:next`, `:unpack` and `:call`, every statement of the language and the
+//! structured `if .. then`, `if .. begin .. else .. end` and
+//! `loop .. while .. again` forms. As in Octo, `:calc` expressions have no
+//! operator precedence: they are evaluated right to left.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::constants::{MEMORY_SIZE, START_ADDRESS};
+use crate::instruction::Instruction;
+
+// Stops a macro that expands to itself
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+
+const UNARY_OPERATORS: [&str; 14] = [
+    "-", "~", "!", "@", "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign", "ceil", "floor",
+];
+
+const BINARY_OPERATORS: [&str; 19] = [
+    "+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", ">", "<=", ">=",
+    "==", "!=",
+];
+
+/// An error in the source, with the 1-based position of the offending token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> AssembleError {
+        AssembleError {
+            line: self.line,
+            column: self.column,
+            message,
+        }
+    }
+}
+
+// Whitespace separated words, `#` starts a comment even inside a word
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    for (line, text) in source.lines().enumerate() {
+        let mut word = String::new();
+        let mut start = 0;
+
+        for (column, c) in text.chars().enumerate() {
+            if c.is_whitespace() {
+                if !word.is_empty() {
+                    tokens.push(Token {
+                        text: std::mem::take(&mut word),
+                        line: line + 1,
+                        column: start + 1,
+                    });
+                }
+            } else if c == '#' {
+                break;
+            } else {
+                if word.is_empty() {
+                    start = column;
+                }
+                word.push(c);
+            }
+        }
+
+        if !word.is_empty() {
+            tokens.push(Token {
+                text: word,
+                line: line + 1,
+                column: start + 1,
+            });
+        }
+    }
+
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+
+    if digit.len() != 1 {
+        return None;
+    }
+
+    u8::from_str_radix(digit, 16).ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FixupKind {
+    // Low 12 bits of the instruction at the offset
+    Address12,
+    // The word after `F000`
+    Address16,
+    // `:unpack`, the nibble goes above the address' high nibble
+    UnpackHigh(u8),
+    UnpackLow,
+}
+
+// A reference to a label that wasn't defined yet
+struct Fixup {
+    offset: usize,
+    kind: FixupKind,
+    token: Token,
+}
+
+enum Block {
+    If {
+        jump: usize,
+        token: Token,
+    },
+    Loop {
+        start: usize,
+        breaks: Vec<usize>,
+        token: Token,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Key,
+    NotKey,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn negate(self) -> Comparison {
+        match self {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Key => Comparison::NotKey,
+            Comparison::NotKey => Comparison::Key,
+            Comparison::Less => Comparison::GreaterOrEqual,
+            Comparison::Greater => Comparison::LessOrEqual,
+            Comparison::LessOrEqual => Comparison::Greater,
+            Comparison::GreaterOrEqual => Comparison::Less,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+struct Condition {
+    x: u8,
+    comparison: Comparison,
+    operand: Option<Operand>,
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Assembler {
+    tokens: Vec<Token>,
+    position: usize,
+    // The last token read, errors point at it
+    last: Token,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    macro_expansions: usize,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    // The first instruction is a jump to `main`, unless `main` comes first
+    jump_to_main: bool,
+}
+
+/// Assembles an Octo program, loaded at `START_ADDRESS`.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let mut assembler = Assembler {
+        tokens: tokenize(source),
+        position: 0,
+        last: Token {
+            text: String::new(),
+            line: 1,
+            column: 1,
+        },
+        rom: Vec::new(),
+        here: START_ADDRESS as usize,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        macro_expansions: 0,
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        jump_to_main: true,
+    };
+
+    assembler.emit(Instruction::Jp(0))?;
+    while let Some(token) = assembler.next() {
+        assembler.statement(token)?;
+    }
+    assembler.finish()
+}
+
+impl Assembler {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position)?.clone();
+        self.position += 1;
+        self.last = token.clone();
+
+        Some(token)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens
+            .get(self.position)
+            .map(|token| token.text.as_str())
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, AssembleError> {
+        Err(self.last.error(message))
+    }
+
+    fn expect_token(&mut self, what: &str) -> Result<Token, AssembleError> {
+        match self.next() {
+            Some(token) => Ok(token),
+            None => self.error(format!("expected {}, found the end of the file", what)),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AssembleError> {
+        let token = self.expect_token(&format!("'{}'", text))?;
+
+        if token.text != text {
+            return self.error(format!("expected '{}', found '{}'", text, token.text));
+        }
+
+        Ok(())
+    }
+
+    fn offset(&self) -> usize {
+        self.here - START_ADDRESS as usize
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), AssembleError> {
+        if self.here >= MEMORY_SIZE {
+            return self.error("the program doesn't fit in memory".to_string());
+        }
+
+        let offset = self.offset();
+        if self.rom.len() <= offset {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Result<(), AssembleError> {
+        for byte in instruction.encode().to_be_bytes() {
+            self.emit_byte(byte)?;
+        }
+
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: &Token, address: usize) -> Result<(), AssembleError> {
+        self.check_name(name)?;
+
+        if self.labels.contains_key(&name.text) {
+            return self.error(format!("label '{}' is already defined", name.text));
+        }
+        if address > u16::MAX as usize {
+            return self.error(format!("label '{}' is past the end of memory", name.text));
+        }
+
+        self.labels.insert(name.text.clone(), address as u16);
+
+        Ok(())
+    }
+
+    fn check_name(&self, name: &Token) -> Result<(), AssembleError> {
+        if parse_number(&name.text).is_some() || self.register_index(&name.text).is_some() {
+            return self.error(format!("'{}' can't be used as a name", name.text));
+        }
+
+        Ok(())
+    }
+
+    fn register_index(&self, text: &str) -> Option<u8> {
+        self.aliases
+            .get(text)
+            .copied()
+            .or_else(|| parse_register(text))
+    }
+
+    fn register(&mut self) -> Result<u8, AssembleError> {
+        let token = self.expect_token("a register")?;
+
+        match self.register_index(&token.text) {
+            Some(x) => Ok(x),
+            None => self.error(format!("expected a register, found '{}'", token.text)),
+        }
+    }
+
+    // The value of a number, constant, label or `{ expression }`, or None
+    // for a name that isn't defined (yet)
+    fn resolve(&mut self, token: &Token) -> Result<Option<f64>, AssembleError> {
+        if token.text == "{" {
+            let expression = self.braced_tokens()?;
+            return self.evaluate(&expression).map(Some);
+        }
+
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(Some(value));
+        }
+
+        let value = self
+            .constants
+            .get(&token.text)
+            .copied()
+            .or_else(|| self.labels.get(&token.text).map(|address| *address as f64));
+
+        Ok(value)
+    }
+
+    fn value(&mut self, token: &Token) -> Result<i64, AssembleError> {
+        match self.resolve(token)? {
+            Some(value) => Ok(value as i64),
+            None => self.error(format!("'{}' is not defined", token.text)),
+        }
+    }
+
+    fn byte(&mut self, token: &Token) -> Result<u8, AssembleError> {
+        let value = self.value(token)?;
+
+        if !(-128..=255).contains(&value) {
+            return self.error(format!("{} doesn't fit in a byte", value));
+        }
+
+        Ok(value as u8)
+    }
+
+    fn next_byte(&mut self) -> Result<u8, AssembleError> {
+        let token = self.expect_token("a value")?;
+        self.byte(&token)
+    }
+
+    fn next_nibble(&mut self) -> Result<u8, AssembleError> {
+        let token = self.expect_token("a value")?;
+        let value = self.value(&token)?;
+
+        if !(0..=0xF).contains(&value) {
+            return self.error(format!("{} doesn't fit in a nibble", value));
+        }
+
+        Ok(value as u8)
+    }
+
+    // An address operand for an instruction about to be emitted at the
+    // current offset. Undefined names become fixups.
+    fn address(&mut self, kind: FixupKind) -> Result<u16, AssembleError> {
+        let token = self.expect_token("an address")?;
+        let offset = self.offset();
+
+        let Some(value) = self.resolve(&token)? else {
+            self.check_name(&token)?;
+            self.fixups.push(Fixup {
+                offset,
+                kind,
+                token,
+            });
+            return Ok(0);
+        };
+
+        let max = match kind {
+            FixupKind::Address16 => 0xFFFF,
+            _ => 0xFFF,
+        };
+        if !(0.0..=max as f64).contains(&value) {
+            return self.error(format!("address {} is out of range", value));
+        }
+
+        Ok(value as u16)
+    }
+
+    // Tokens up to the `}` matching a `{` that was just read
+    fn braced_tokens(&mut self) -> Result<Vec<Token>, AssembleError> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+
+        loop {
+            let token = self.expect_token("'}'")?;
+
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(tokens),
+                "}" => depth -= 1,
+                _ => {}
+            }
+
+            tokens.push(token);
+        }
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AssembleError> {
+        if let Some(x) = self.register_index(&token.text) {
+            return self.register_statement(x);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.expect_token("a label name")?;
+
+                // A program that starts with main doesn't need the jump
+                if name.text == "main"
+                    && self.jump_to_main
+                    && self.here == START_ADDRESS as usize + 2
+                {
+                    self.jump_to_main = false;
+                    self.rom.clear();
+                    self.here = START_ADDRESS as usize;
+                }
+
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.expect_token("a label name")?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.expect_token("an alias name")?;
+                self.check_name(&name)?;
+                let x = self.register()?;
+                self.aliases.insert(name.text, x);
+            }
+            ":const" => {
+                let name = self.expect_token("a constant name")?;
+                self.check_name(&name)?;
+                let token = self.expect_token("a value")?;
+                let value = self.value(&token)?;
+                self.constants.insert(name.text, value as f64);
+            }
+            ":calc" => {
+                let name = self.expect_token("a constant name")?;
+                self.check_name(&name)?;
+                self.expect("{")?;
+                let expression = self.braced_tokens()?;
+                let value = self.evaluate(&expression)?;
+                self.constants.insert(name.text, value);
+            }
+            ":org" => {
+                let token = self.expect_token("an address")?;
+                let address = self.value(&token)?;
+
+                if !(START_ADDRESS as i64..MEMORY_SIZE as i64).contains(&address) {
+                    return self.error(format!("{:#X} is outside the program space", address));
+                }
+                self.here = address as usize;
+            }
+            ":byte" => {
+                let byte = self.next_byte()?;
+                self.emit_byte(byte)?;
+            }
+            ":call" => {
+                let address = self.address(FixupKind::Address12)?;
+                self.emit(Instruction::Call(address))?;
+            }
+            ":unpack" => {
+                let nibble = self.next_nibble()?;
+                let pending = self.fixups.len();
+                let address = self.address(FixupKind::UnpackHigh(nibble))?;
+
+                // A forward reference patches both instructions
+                if self.fixups.len() > pending {
+                    let token = self.fixups[pending].token.clone();
+                    self.fixups.push(Fixup {
+                        offset: self.offset() + 2,
+                        kind: FixupKind::UnpackLow,
+                        token,
+                    });
+                }
+
+                let high = self.aliases.get("unpack-hi").copied().unwrap_or(0);
+                let low = self.aliases.get("unpack-lo").copied().unwrap_or(1);
+                self.emit(Instruction::LdByte {
+                    x: high,
+                    kk: nibble << 4 | (address >> 8) as u8,
+                })?;
+                self.emit(Instruction::LdByte {
+                    x: low,
+                    kk: address as u8,
+                })?;
+            }
+            ":macro" => self.define_macro()?,
+            ":breakpoint" => {
+                self.expect_token("a breakpoint name")?;
+            }
+            ":monitor" => {
+                self.expect_token("an address")?;
+                self.expect_token("a length")?;
+            }
+            ";" | "return" => self.emit(Instruction::Ret)?,
+            "clear" => self.emit(Instruction::Cls)?,
+            "hires" => self.emit(Instruction::High)?,
+            "lores" => self.emit(Instruction::Low)?,
+            "exit" => self.emit(Instruction::Exit)?,
+            "scroll-left" => self.emit(Instruction::Scl)?,
+            "scroll-right" => self.emit(Instruction::Scr)?,
+            "audio" => self.emit(Instruction::Audio)?,
+            "scroll-down" => {
+                let n = self.next_nibble()?;
+                self.emit(Instruction::Scd(n))?;
+            }
+            "scroll-up" => {
+                let n = self.next_nibble()?;
+                self.emit(Instruction::Scu(n))?;
+            }
+            "plane" => {
+                let n = self.next_nibble()?;
+                self.emit(Instruction::Plane(n))?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdBVx(x))?;
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdRVx(x))?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdVxR(x))?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let save = token.text == "save";
+
+                let instruction = if self.peek() == Some("-") {
+                    self.next();
+                    let y = self.register()?;
+                    if save {
+                        Instruction::Save { x, y }
+                    } else {
+                        Instruction::Load { x, y }
+                    }
+                } else if save {
+                    Instruction::LdIVx(x)
+                } else {
+                    Instruction::LdVxI(x)
+                };
+                self.emit(instruction)?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.next_nibble()?;
+                self.emit(Instruction::Drw { x, y, n })?;
+            }
+            "jump" => {
+                let address = self.address(FixupKind::Address12)?;
+                self.emit(Instruction::Jp(address))?;
+            }
+            "jump0" => {
+                let address = self.address(FixupKind::Address12)?;
+                self.emit(Instruction::JpV0(address))?;
+            }
+            "native" => {
+                let address = self.address(FixupKind::Address12)?;
+                self.emit(Instruction::Sys(address))?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(match token.text.as_str() {
+                    "delay" => Instruction::LdDtVx(x),
+                    "buzzer" => Instruction::LdStVx(x),
+                    _ => Instruction::Pitch(x),
+                })?;
+            }
+            "i" => self.i_statement()?,
+            "if" => self.if_statement(token)?,
+            "else" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => {
+                    let else_jump = self.offset();
+                    self.emit(Instruction::Jp(0))?;
+                    self.patch_jump(jump)?;
+                    self.blocks.push(Block::If {
+                        jump: else_jump,
+                        token,
+                    });
+                }
+                _ => return self.error("'else' without 'if .. begin'".to_string()),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => self.patch_jump(jump)?,
+                _ => return self.error("'end' without 'if .. begin'".to_string()),
+            },
+            "loop" => self.blocks.push(Block::Loop {
+                start: self.here,
+                breaks: Vec::new(),
+                token,
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                self.emit_skip(&condition, true)?;
+
+                let jump = self.offset();
+                let Some(breaks) = self.blocks.iter_mut().rev().find_map(|block| match block {
+                    Block::Loop { breaks, .. } => Some(breaks),
+                    _ => None,
+                }) else {
+                    return self.error("'while' outside of a loop".to_string());
+                };
+                breaks.push(jump);
+                self.emit(Instruction::Jp(0))?;
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, breaks, .. }) => {
+                    let start = self.jump_target(start)?;
+                    self.emit(Instruction::Jp(start))?;
+                    for jump in breaks {
+                        self.patch_jump(jump)?;
+                    }
+                }
+                _ => return self.error("'again' without 'loop'".to_string()),
+            },
+            "then" | "begin" => {
+                return self.error(format!("'{}' without 'if'", token.text));
+            }
+            name if self.macros.contains_key(name) => self.expand_macro(&token)?,
+            name if self.labels.contains_key(name) => {
+                let address = self.labels[name];
+                self.emit(Instruction::Call(address))?;
+            }
+            _ => match self.resolve(&token)? {
+                Some(_) => {
+                    let byte = self.byte(&token)?;
+                    self.emit_byte(byte)?;
+                }
+                // A call to a label defined further down
+                None => {
+                    self.check_name(&token)?;
+                    self.fixups.push(Fixup {
+                        offset: self.offset(),
+                        kind: FixupKind::Address12,
+                        token,
+                    });
+                    self.emit(Instruction::Call(0))?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), AssembleError> {
+        let operator = self.expect_token("an operator")?;
+        let token = self.expect_token("an operand")?;
+        let y = self.register_index(&token.text);
+
+        let instruction = match (operator.text.as_str(), y) {
+            (":=", Some(y)) => Instruction::LdReg { x, y },
+            (":=", None) => match token.text.as_str() {
+                "delay" => Instruction::LdVxDt(x),
+                "key" => Instruction::LdVxK(x),
+                "random" => Instruction::Rnd {
+                    x,
+                    kk: self.next_byte()?,
+                },
+                _ => Instruction::LdByte {
+                    x,
+                    kk: self.byte(&token)?,
+                },
+            },
+            ("+=", Some(y)) => Instruction::AddReg { x, y },
+            ("+=", None) => Instruction::AddByte {
+                x,
+                kk: self.byte(&token)?,
+            },
+            ("-=", Some(y)) => Instruction::Sub { x, y },
+            ("-=", None) => Instruction::AddByte {
+                x,
+                kk: self.byte(&token)?.wrapping_neg(),
+            },
+            ("=-", Some(y)) => Instruction::Subn { x, y },
+            ("|=", Some(y)) => Instruction::Or { x, y },
+            ("&=", Some(y)) => Instruction::And { x, y },
+            ("^=", Some(y)) => Instruction::Xor { x, y },
+            (">>=", Some(y)) => Instruction::Shr { x, y },
+            ("<<=", Some(y)) => Instruction::Shl { x, y },
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", None) => {
+                return self.error(format!("'{}' needs a register", operator.text));
+            }
+            _ => return Err(operator.error(format!("unknown operator '{}'", operator.text))),
+        };
+
+        self.emit(instruction)
+    }
+
+    fn i_statement(&mut self) -> Result<(), AssembleError> {
+        let operator = self.expect_token("an operator")?;
+
+        match operator.text.as_str() {
+            ":=" => match self.peek() {
+                Some("long") => {
+                    self.next();
+                    self.emit(Instruction::LdILong)?;
+                    let address = self.address(FixupKind::Address16)?;
+                    for byte in address.to_be_bytes() {
+                        self.emit_byte(byte)?;
+                    }
+                }
+                Some("hex") => {
+                    self.next();
+                    let x = self.register()?;
+                    self.emit(Instruction::LdFVx(x))?;
+                }
+                Some("bighex") => {
+                    self.next();
+                    let x = self.register()?;
+                    self.emit(Instruction::LdHfVx(x))?;
+                }
+                _ => {
+                    let address = self.address(FixupKind::Address12)?;
+                    self.emit(Instruction::LdIAddr(address))?;
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(Instruction::AddIVx(x))?;
+            }
+            _ => return self.error(format!("unknown operator '{}'", operator.text)),
+        }
+
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AssembleError> {
+        let x = self.register()?;
+        let token = self.expect_token("a comparison")?;
+
+        let comparison = match token.text.as_str() {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "key" => Comparison::Key,
+            "-key" => Comparison::NotKey,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessOrEqual,
+            ">=" => Comparison::GreaterOrEqual,
+            _ => return self.error(format!("unknown comparison '{}'", token.text)),
+        };
+
+        let operand = match comparison {
+            Comparison::Key | Comparison::NotKey => None,
+            _ => {
+                let token = self.expect_token("a register or a value")?;
+                Some(match self.register_index(&token.text) {
+                    Some(y) => Operand::Register(y),
+                    None => Operand::Byte(self.byte(&token)?),
+                })
+            }
+        };
+
+        Ok(Condition {
+            x,
+            comparison,
+            operand,
+        })
+    }
+
+    // Emits instructions that skip the next one when the condition is
+    // false, or when it is true if `negated`
+    fn emit_skip(&mut self, condition: &Condition, negated: bool) -> Result<(), AssembleError> {
+        let x = condition.x;
+        let comparison = if negated {
+            condition.comparison.negate()
+        } else {
+            condition.comparison
+        };
+
+        let (operand, comparison) = match (condition.operand, comparison) {
+            (_, Comparison::Key) => return self.emit(Instruction::Sknp(x)),
+            (_, Comparison::NotKey) => return self.emit(Instruction::Skp(x)),
+            (Some(Operand::Register(y)), Comparison::Equal) => {
+                return self.emit(Instruction::SneReg { x, y });
+            }
+            (Some(Operand::Byte(kk)), Comparison::Equal) => {
+                return self.emit(Instruction::SneByte { x, kk });
+            }
+            (Some(Operand::Register(y)), Comparison::NotEqual) => {
+                return self.emit(Instruction::SeReg { x, y });
+            }
+            (Some(Operand::Byte(kk)), Comparison::NotEqual) => {
+                return self.emit(Instruction::SeByte { x, kk });
+            }
+            (Some(operand), comparison) => (operand, comparison),
+            (None, _) => return self.error("comparison needs an operand".to_string()),
+        };
+
+        // Ordering goes through a subtraction into a scratch register
+        let temp = self.aliases.get("compare-temp").copied().unwrap_or(0xF);
+        self.emit(match operand {
+            Operand::Register(y) => Instruction::LdReg { x: temp, y },
+            Operand::Byte(kk) => Instruction::LdByte { x: temp, kk },
+        })?;
+        self.emit(match comparison {
+            Comparison::Greater | Comparison::LessOrEqual => Instruction::Sub { x: temp, y: x },
+            _ => Instruction::Subn { x: temp, y: x },
+        })?;
+        self.emit(match comparison {
+            Comparison::Greater | Comparison::Less => Instruction::SeByte { x: 0xF, kk: 1 },
+            _ => Instruction::SneByte { x: 0xF, kk: 1 },
+        })
+    }
+
+    fn if_statement(&mut self, token: Token) -> Result<(), AssembleError> {
+        let condition = self.condition()?;
+        let keyword = self.expect_token("'then' or 'begin'")?;
+
+        match keyword.text.as_str() {
+            "then" => self.emit_skip(&condition, false),
+            "begin" => {
+                self.emit_skip(&condition, true)?;
+                self.blocks.push(Block::If {
+                    jump: self.offset(),
+                    token,
+                });
+                self.emit(Instruction::Jp(0))
+            }
+            _ => self.error(format!(
+                "expected 'then' or 'begin', found '{}'",
+                keyword.text
+            )),
+        }
+    }
+
+    fn jump_target(&self, address: usize) -> Result<u16, AssembleError> {
+        if address > 0xFFF {
+            return self.error(format!("jump target {:#X} is out of range", address));
+        }
+
+        Ok(address as u16)
+    }
+
+    // Points the placeholder jump at `offset` to the current address
+    fn patch_jump(&mut self, offset: usize) -> Result<(), AssembleError> {
+        let target = self.jump_target(self.here)?;
+
+        let jump = Instruction::Jp(target).encode().to_be_bytes();
+        self.rom[offset..offset + 2].copy_from_slice(&jump);
+
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> Result<(), AssembleError> {
+        let name = self.expect_token("a macro name")?;
+        self.check_name(&name)?;
+
+        let mut parameters = Vec::new();
+        loop {
+            let token = self.expect_token("'{'")?;
+            if token.text == "{" {
+                break;
+            }
+            parameters.push(token.text);
+        }
+
+        let body = self.braced_tokens()?;
+        self.macros.insert(name.text, Macro { parameters, body });
+
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &Token) -> Result<(), AssembleError> {
+        self.macro_expansions += 1;
+        if self.macro_expansions > MAX_MACRO_EXPANSIONS {
+            return self.error(format!("too many expansions of macro '{}'", name.text));
+        }
+
+        let parameter_count = self.macros[&name.text].parameters.len();
+        let mut arguments = HashMap::new();
+        for i in 0..parameter_count {
+            let argument = self.expect_token("a macro argument")?;
+            let parameter = self.macros[&name.text].parameters[i].clone();
+            arguments.insert(parameter, argument);
+        }
+
+        let expansion: Vec<Token> = self.macros[&name.text]
+            .body
+            .iter()
+            .map(|token| arguments.get(&token.text).unwrap_or(token).clone())
+            .collect();
+
+        self.tokens.splice(self.position..self.position, expansion);
+
+        Ok(())
+    }
+
+    fn evaluate(&self, tokens: &[Token]) -> Result<f64, AssembleError> {
+        let mut position = 0;
+        let value = self.expression(tokens, &mut position)?;
+
+        if let Some(token) = tokens.get(position) {
+            return Err(token.error(format!("unexpected '{}' in expression", token.text)));
+        }
+
+        Ok(value)
+    }
+
+    fn expression(&self, tokens: &[Token], position: &mut usize) -> Result<f64, AssembleError> {
+        let Some(token) = tokens.get(*position) else {
+            return self.error("expression ends too early".to_string());
+        };
+        *position += 1;
+
+        let text = token.text.as_str();
+        let value = if text == "(" {
+            let value = self.expression(tokens, position)?;
+            match tokens.get(*position) {
+                Some(token) if token.text == ")" => *position += 1,
+                _ => return Err(token.error("unclosed '('".to_string())),
+            }
+            value
+        } else if UNARY_OPERATORS.contains(&text) {
+            let value = self.expression(tokens, position)?;
+            return Ok(self.unary(text, value));
+        } else {
+            self.calc_value(token)?
+        };
+
+        match tokens.get(*position) {
+            Some(operator) if BINARY_OPERATORS.contains(&operator.text.as_str()) => {
+                *position += 1;
+                let rhs = self.expression(tokens, position)?;
+                Ok(binary(&operator.text, value, rhs))
+            }
+            _ => Ok(value),
+        }
+    }
+
+    fn calc_value(&self, token: &Token) -> Result<f64, AssembleError> {
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(value);
+        }
+
+        let value = match token.text.as_str() {
+            "HERE" => Some(self.here as f64),
+            "PI" => Some(std::f64::consts::PI),
+            "E" => Some(std::f64::consts::E),
+            name => self
+                .constants
+                .get(name)
+                .copied()
+                .or_else(|| self.labels.get(name).map(|address| *address as f64)),
+        };
+
+        value.ok_or_else(|| token.error(format!("'{}' is not defined", token.text)))
+    }
+
+    fn unary(&self, operator: &str, value: f64) -> f64 {
+        match operator {
+            "-" => -value,
+            "~" => !(value as i64) as f64,
+            "!" => (value == 0.0) as u8 as f64,
+            // Byte already assembled at an address
+            "@" => {
+                let offset = (value as usize).wrapping_sub(START_ADDRESS as usize);
+                self.rom.get(offset).copied().unwrap_or(0) as f64
+            }
+            "sin" => value.sin(),
+            "cos" => value.cos(),
+            "tan" => value.tan(),
+            "exp" => value.exp(),
+            "log" => value.ln(),
+            "abs" => value.abs(),
+            "sqrt" => value.sqrt(),
+            "sign" => value.signum(),
+            "ceil" => value.ceil(),
+            _ => value.floor(),
+        }
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, AssembleError> {
+        if let Some(block) = self.blocks.last() {
+            let (Block::If { token, .. } | Block::Loop { token, .. }) = block;
+            let message = match block {
+                Block::If { .. } => "'if .. begin' without 'end'",
+                Block::Loop { .. } => "'loop' without 'again'",
+            };
+            return Err(token.error(message.to_string()));
+        }
+
+        if self.jump_to_main {
+            let Some(&main) = self.labels.get("main") else {
+                return self.error("the program has no 'main' label".to_string());
+            };
+            self.rom[..2].copy_from_slice(&Instruction::Jp(main).encode().to_be_bytes());
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let name = &fixup.token.text;
+            let Some(&address) = self.labels.get(name) else {
+                return Err(fixup.token.error(format!("'{}' is not defined", name)));
+            };
+
+            let offset = fixup.offset;
+            match fixup.kind {
+                FixupKind::Address12 | FixupKind::UnpackHigh(_) if address > 0xFFF => {
+                    return Err(fixup.token.error(format!("'{}' is out of range", name)));
+                }
+                FixupKind::Address12 => {
+                    self.rom[offset] |= (address >> 8) as u8;
+                    self.rom[offset + 1] = address as u8;
+                }
+                FixupKind::Address16 => {
+                    self.rom[offset..offset + 2].copy_from_slice(&address.to_be_bytes());
+                }
+                FixupKind::UnpackHigh(nibble) => {
+                    self.rom[offset + 1] = nibble << 4 | (address >> 8) as u8
+                }
+                FixupKind::UnpackLow => self.rom[offset + 1] = address as u8,
+            }
+        }
+
+        Ok(self.rom)
+    }
+}
+
+fn binary(operator: &str, lhs: f64, rhs: f64) -> f64 {
+    let (a, b) = (lhs as i64, rhs as i64);
+
+    match operator {
+        "+" => lhs + rhs,
+        "-" => lhs - rhs,
+        "*" => lhs * rhs,
+        "/" => lhs / rhs,
+        "%" => lhs % rhs,
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.wrapping_shl(b as u32) as f64,
+        ">>" => a.wrapping_shr(b as u32) as f64,
+        "pow" => lhs.powf(rhs),
+        "min" => lhs.min(rhs),
+        "max" => lhs.max(rhs),
+        "<" => (lhs < rhs) as u8 as f64,
+        ">" => (lhs > rhs) as u8 as f64,
+        "<=" => (lhs <= rhs) as u8 as f64,
+        ">=" => (lhs >= rhs) as u8 as f64,
+        "==" => (lhs == rhs) as u8 as f64,
+        _ => (lhs != rhs) as u8 as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> AssembleError {
+        assemble(source).unwrap_err()
+    }
+
+    #[test]
+    fn assembles_test_opcode() {
+        let rom = assemble(include_str!("../test_opcode.8o")).unwrap();
+
+        assert_eq!(rom, include_bytes!("../test_opcode.ch8"));
+    }
+
+    #[test]
+    fn ends_a_word_at_a_comment() {
+        assert_eq!(
+            assemble(": main\n  v1 := 2#note\n  v2 := 3 # note").unwrap(),
+            [0x61, 0x02, 0x62, 0x03]
+        );
+    }
+
+    #[test]
+    fn reports_an_undefined_label() {
+        let e = error(": main\n  jump nowhere");
+
+        assert_eq!((e.line, e.column), (2, 8));
+        assert_eq!(e.message, "'nowhere' is not defined");
+    }
+
+    #[test]
+    fn reports_a_byte_out_of_range() {
+        let e = error(": main v0 := 300");
+
+        assert_eq!((e.line, e.column), (1, 14));
+        assert_eq!(e.message, "300 doesn't fit in a byte");
+    }
+
+    #[test]
+    fn reports_unbalanced_blocks() {
+        assert_eq!(error(": main end").message, "'end' without 'if .. begin'");
+        assert_eq!(
+            error(": main loop v0 += 1").message,
+            "'loop' without 'again'"
+        );
+    }
+
+    #[test]
+    fn reports_a_duplicate_label() {
+        assert_eq!(
+            error(": main : main").message,
+            "label 'main' is already defined"
+        );
+    }
+
+    #[test]
+    fn stops_a_recursive_macro() {
+        assert_eq!(
+            error(":macro m { m }\n: main m").message,
+            "too many expansions of macro 'm'"
+        );
+    }
+}
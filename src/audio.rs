@@ -0,0 +1,252 @@
+//! Beeper driven by the sound timer.
+//!
+//! The machine beeps for as long as `sound_timer > 0`. [`Beeper`] turns that
+//! into samples, fading in and out over a few milliseconds so starting and
+//! stopping don't click. The tone comes from [`BeeperSettings`], or from the
+//! XO-CHIP audio pattern once the program has loaded one. Frontends feed it to their audio device, or to
+//! [`write_wav`] to check the sound without one.
+
+use std::f32::consts::TAU;
+use std::io::{self, Write};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// Time to go from silence to full volume and back
+const FADE_SECONDS: f32 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Waveform {
+    #[default]
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+impl Waveform {
+    /// Names accepted by [`Waveform::from_name`].
+    pub const NAMES: [&'static str; 4] = ["square", "triangle", "sawtooth", "sine"];
+
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name.to_ascii_lowercase().as_str() {
+            "square" => Some(Waveform::Square),
+            "triangle" => Some(Waveform::Triangle),
+            "sawtooth" => Some(Waveform::Sawtooth),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+
+    // Value at `phase` in [0, 1), between -1 and 1
+    fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Sine => (phase * TAU).sin(),
+        }
+    }
+}
+
+/// An XO-CHIP audio pattern: 128 one-bit samples, most significant bit
+/// first, played in a loop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioPattern {
+    pub bits: [u8; 16],
+    /// Samples per second
+    pub rate: f32,
+}
+
+impl AudioPattern {
+    // Value at `phase` in [0, 1) through the whole pattern, -1 or 1
+    fn sample(&self, phase: f32) -> f32 {
+        let bit = ((phase * 128.0) as usize).min(127);
+
+        if self.bits[bit / 8] & (0x80 >> (bit % 8)) != 0 {
+            1.0
+        } else {
+            -1.0
+        }
+    }
+}
+
+/// What the beep sounds like.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeeperSettings {
+    /// Tone frequency in Hz
+    pub frequency: f32,
+    /// From 0 (silent) to 1 (full scale)
+    pub volume: f32,
+    pub waveform: Waveform,
+}
+
+impl Default for BeeperSettings {
+    fn default() -> Self {
+        BeeperSettings {
+            frequency: 440.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+pub struct Beeper {
+    pub settings: BeeperSettings,
+    pub sample_rate: u32,
+    /// Set from `sound_timer > 0` once per frame
+    pub active: bool,
+    /// Played instead of the tone when set, updated once per frame
+    pub pattern: Option<AudioPattern>,
+    pub muted: bool,
+    phase: f32,
+    gain: f32,
+}
+
+impl Beeper {
+    pub fn new(settings: BeeperSettings, sample_rate: u32) -> Self {
+        Beeper {
+            settings,
+            sample_rate,
+            active: false,
+            pattern: None,
+            muted: false,
+            phase: 0.0,
+            gain: 0.0,
+        }
+    }
+
+    /// Fills `out` with the next mono samples.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let target = if self.active && !self.muted {
+            self.settings.volume.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let fade_step = 1.0 / (FADE_SECONDS * self.sample_rate as f32);
+        // A pattern's phase runs through all of its samples
+        let phase_step = match &self.pattern {
+            Some(pattern) => pattern.rate / 128.0 / self.sample_rate as f32,
+            None => self.settings.frequency / self.sample_rate as f32,
+        };
+
+        for sample in out {
+            if self.gain < target {
+                self.gain = (self.gain + fade_step).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - fade_step).max(target);
+            }
+
+            let value = match &self.pattern {
+                Some(pattern) => pattern.sample(self.phase),
+                None => self.settings.waveform.sample(self.phase),
+            };
+            *sample = value * self.gain;
+            self.phase = (self.phase + phase_step).fract();
+        }
+    }
+}
+
+/// Writes mono samples as a 16-bit PCM WAV file.
+pub fn write_wav<W: Write>(mut writer: W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?; // PCM
+    writer.write_all(&1u16.to_le_bytes())?; // Mono
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * 2).to_le_bytes())?; // Bytes per second
+    writer.write_all(&2u16.to_le_bytes())?; // Bytes per sample
+    writer.write_all(&16u16.to_le_bytes())?; // Bits per sample
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_all(&value.to_le_bytes())?;
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beeper(pattern: Option<AudioPattern>) -> Beeper {
+        let settings = BeeperSettings {
+            volume: 1.0,
+            ..BeeperSettings::default()
+        };
+        let mut beeper = Beeper::new(settings, 4000);
+        beeper.active = true;
+        beeper.pattern = pattern;
+        beeper
+    }
+
+    #[test]
+    fn plays_the_audio_pattern_at_its_rate() {
+        let mut bits = [0; 16];
+        bits[0] = 0xF0;
+        bits[1] = 0x0F;
+        // One pattern sample per output sample
+        let mut beeper = beeper(Some(AudioPattern { bits, rate: 4000.0 }));
+
+        let mut out = [0.0; 256];
+        beeper.fill(&mut out);
+
+        let signs: Vec<bool> = out.iter().map(|sample| *sample > 0.0).collect();
+        let mut expected = vec![false; 128];
+        expected[..4].fill(true);
+        expected[12..16].fill(true);
+        // Looped
+        assert_eq!(signs[..128], expected);
+        assert_eq!(signs[128..], expected);
+    }
+
+    #[test]
+    fn plays_the_tone_without_a_pattern() {
+        let mut beeper = beeper(None);
+
+        // 440 Hz at 4000 samples per second: a period is about 9.1 samples
+        let mut out = [0.0; 11];
+        beeper.fill(&mut out);
+
+        let signs: Vec<bool> = out.iter().map(|sample| *sample > 0.0).collect();
+        assert_eq!(
+            signs,
+            [
+                true, true, true, true, true, false, false, false, false, false, true
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_a_wav_header() {
+        let mut data = Vec::new();
+        write_wav(&mut data, 8000, &[0.0, 1.0, -1.0]).unwrap();
+
+        assert_eq!(data.len(), 44 + 6);
+        assert_eq!(data[..4], *b"RIFF");
+        assert_eq!(data[4..8], (36u32 + 6).to_le_bytes());
+        assert_eq!(data[8..16], *b"WAVEfmt ");
+        // PCM, mono, 8000 Hz, 16000 bytes per second, 2 bytes per sample,
+        // 16 bits
+        assert_eq!(
+            data[20..36],
+            [1, 0, 1, 0, 0x40, 0x1F, 0, 0, 0x80, 0x3E, 0, 0, 2, 0, 16, 0]
+        );
+        assert_eq!(data[36..44], [b'd', b'a', b't', b'a', 6, 0, 0, 0]);
+        assert_eq!(data[44..], [0, 0, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
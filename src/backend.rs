@@ -0,0 +1,226 @@
+//! Choice of CPU backend.
+//!
+//! The interpreter runs one instruction at a time through
+//! [`Chip8::step`]. The block backend ([`BlockEngine`]) is faster for long
+//! headless runs. Lockstep runs both and stops at the first difference,
+//! to check that they really agree.
+
+use crate::blocks::BlockEngine;
+use crate::chip8::Chip8;
+use crate::error::Chip8Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    Interpreter,
+    Blocks,
+    /// Blocks, compared with the interpreter after every block
+    Lockstep,
+}
+
+impl Backend {
+    /// Names accepted by [`Backend::from_name`].
+    pub const NAMES: [&'static str; 3] = ["interpreter", "blocks", "lockstep"];
+
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name.to_ascii_lowercase().as_str() {
+            "interpreter" => Some(Backend::Interpreter),
+            "blocks" => Some(Backend::Blocks),
+            "lockstep" => Some(Backend::Lockstep),
+            _ => None,
+        }
+    }
+}
+
+/// Runs frames of a [`Chip8`] on the selected backend.
+pub struct Cpu {
+    pub backend: Backend,
+    blocks: BlockEngine,
+}
+
+impl Cpu {
+    pub fn new(backend: Backend) -> Self {
+        Cpu {
+            backend,
+            blocks: BlockEngine::new(),
+        }
+    }
+
+    /// Like [`Chip8::run_frame`].
+    pub fn run_frame(
+        &mut self,
+        chip8: &mut Chip8,
+        instructions_per_frame: u32,
+    ) -> Result<(), Chip8Error> {
+        self.run_frame_traced(chip8, instructions_per_frame, |_| {})
+    }
+
+    /// Like [`Chip8::run_frame_traced`].
+    pub fn run_frame_traced<F: FnMut(&Chip8)>(
+        &mut self,
+        chip8: &mut Chip8,
+        instructions_per_frame: u32,
+        trace: F,
+    ) -> Result<(), Chip8Error> {
+        match self.backend {
+            Backend::Interpreter => chip8.run_frame_traced(instructions_per_frame, trace),
+            Backend::Blocks => self
+                .blocks
+                .run_frame_traced(chip8, instructions_per_frame, trace),
+            Backend::Lockstep => self.run_lockstep(chip8, instructions_per_frame, trace),
+        }
+    }
+
+    // Runs the frame with blocks, and the same number of instructions on a
+    // copy with the interpreter after each block
+    fn run_lockstep<F: FnMut(&Chip8)>(
+        &mut self,
+        chip8: &mut Chip8,
+        instructions_per_frame: u32,
+        trace: F,
+    ) -> Result<(), Chip8Error> {
+        let mut reference = Box::new(chip8.clone());
+        reference.signal_vblank();
+
+        self.blocks.run_frame_checked(
+            chip8,
+            instructions_per_frame,
+            trace,
+            |chip8, pc, executed| {
+                for _ in 0..executed {
+                    reference.step().map_err(|_| mismatch(pc, "errors"))?;
+                }
+
+                compare_registers(chip8, &reference).map_err(|what| mismatch(pc, what))
+            },
+        )?;
+
+        reference.tick_timers();
+
+        // Memory and display only once per frame, they are much larger
+        if chip8.save_state() != reference.save_state()
+            || chip8.unknown_opcodes != reference.unknown_opcodes
+        {
+            return Err(mismatch(chip8.pc, "machine state at the end of the frame"));
+        }
+
+        Ok(())
+    }
+}
+
+fn mismatch(pc: u16, what: &'static str) -> Chip8Error {
+    Chip8Error::BackendMismatch { pc, what }
+}
+
+// The CPU state, which is cheap to compare after every block
+fn compare_registers(chip8: &Chip8, reference: &Chip8) -> Result<(), &'static str> {
+    if chip8.pc != reference.pc {
+        Err("pc")
+    } else if chip8.registers != reference.registers {
+        Err("registers")
+    } else if chip8.index != reference.index {
+        Err("I")
+    } else if chip8.sp != reference.sp || chip8.stack != reference.stack {
+        Err("stack")
+    } else if chip8.delay_timer != reference.delay_timer
+        || chip8.sound_timer != reference.sound_timer
+    {
+        Err("timers")
+    } else if chip8.opcode != reference.opcode {
+        Err("opcode")
+    } else if chip8.halted != reference.halted {
+        Err("halted")
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler;
+    use crate::quirks::Quirks;
+    use crate::rng::Rng;
+
+    const FRAMES: usize = 300;
+    const INSTRUCTIONS_PER_FRAME: u32 = 20;
+
+    // Stores into the code it is about to run, twice with `save` and once
+    // with `bcd`, after the blocks there have been translated
+    const SELF_MODIFYING: &str = "
+        : main
+          target
+          v3 := v0
+          i := target
+          v0 := 0x60
+          v1 := 0x07
+          save v1
+          target
+          v4 := v0
+          i := target
+          v6 := 1
+          i += v6
+          v0 := 9
+          bcd v0
+          target
+          v5 := v0
+          loop again
+        : target
+          v0 := 1
+          v1 := 1
+          return
+    ";
+
+    fn run(program: &[u8], quirks: Quirks, backend: Backend) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.quirks = quirks;
+        chip8.rng = Rng::new(1);
+        chip8.load_program(program).unwrap();
+
+        let mut cpu = Cpu::new(backend);
+        for _ in 0..FRAMES {
+            cpu.run_frame(&mut chip8, INSTRUCTIONS_PER_FRAME).unwrap();
+        }
+
+        chip8
+    }
+
+    fn assert_backends_agree(program: &[u8], quirks: Quirks) -> Chip8 {
+        let interpreter = run(program, quirks, Backend::Interpreter);
+        let blocks = run(program, quirks, Backend::Blocks);
+
+        assert_eq!(compare_registers(&blocks, &interpreter), Ok(()));
+        assert_eq!(blocks.active_display(), interpreter.active_display());
+        assert!(blocks.save_state() == interpreter.save_state());
+
+        blocks
+    }
+
+    #[test]
+    fn backends_agree_on_the_bundled_roms() {
+        let roms: [&[u8]; 3] = [
+            include_bytes!("../Pong.ch8"),
+            include_bytes!("../Tetris.ch8"),
+            include_bytes!("../test_opcode.ch8"),
+        ];
+
+        for rom in roms {
+            for name in Quirks::PRESET_NAMES {
+                assert_backends_agree(rom, Quirks::from_name(name).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_self_modifying_code() {
+        let program = assembler::assemble(SELF_MODIFYING).unwrap();
+
+        for name in Quirks::PRESET_NAMES {
+            let chip8 = assert_backends_agree(&program, Quirks::from_name(name).unwrap());
+
+            // What each call ran: the original code, then `v0 := 7` after
+            // the save, then `v0 := 0` after the bcd
+            assert_eq!(chip8.registers[3..6], [1, 7, 0]);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Basic block execution.
+//!
+//! A run of instructions up to the next jump is translated once into a
+//! chain of closures with their operands already decoded, then executed in
+//! one go. Every closure leaves the machine exactly as [`Chip8::step`]
+//! would, so both give the same results.
+//!
+//! A program waiting for the vblank or a key, or stuck in a jump to itself,
+//! can't change anything before the frame ends, so those retries are
+//! counted without being run.
+//!
+//! A block is dropped when memory under it is written. If the program
+//! itself wrote it, the bytes are self-modifying code and are left to the
+//! interpreter from then on.
+
+use std::ops::Range;
+
+use crate::chip8::Chip8;
+use crate::constants::MEMORY_SIZE;
+use crate::error::Chip8Error;
+use crate::instruction::Instruction;
+
+// Instructions in the longest block
+const MAX_BLOCK_LENGTH: usize = 64;
+// Bytes in the longest block, if every instruction is F000 nnnn
+const MAX_BLOCK_BYTES: usize = MAX_BLOCK_LENGTH * 4;
+
+// A translated instruction. `run` gets the instruction itself, so only
+// non-capturing closures are needed and the block stays one flat array.
+#[derive(Clone, Copy)]
+struct Compiled {
+    run: fn(&mut Chip8, &Compiled) -> Result<(), Chip8Error>,
+    opcode: u16,
+    instruction: Instruction,
+    // Operands of the inlined instructions
+    x: u8,
+    y: u8,
+    kk: u8,
+    nnn: u16,
+    address: u16,
+    // The pc left when the instruction doesn't branch
+    next: u16,
+}
+
+struct Block {
+    // Address after the last instruction
+    end: usize,
+    ops: Box<[Compiled]>,
+}
+
+pub struct BlockEngine {
+    // Indexed by start address
+    blocks: Vec<Option<Block>>,
+    // Bytes the program wrote while a block covered them
+    self_modified: Vec<bool>,
+}
+
+impl BlockEngine {
+    pub fn new() -> Self {
+        BlockEngine {
+            blocks: (0..MEMORY_SIZE).map(|_| None).collect(),
+            self_modified: vec![false; MEMORY_SIZE],
+        }
+    }
+
+    /// Like [`Chip8::run_frame`].
+    pub fn run_frame(
+        &mut self,
+        chip8: &mut Chip8,
+        instructions_per_frame: u32,
+    ) -> Result<(), Chip8Error> {
+        self.run_frame_traced(chip8, instructions_per_frame, |_| {})
+    }
+
+    /// Like [`Chip8::run_frame_traced`].
+    pub fn run_frame_traced<F: FnMut(&Chip8)>(
+        &mut self,
+        chip8: &mut Chip8,
+        instructions_per_frame: u32,
+        trace: F,
+    ) -> Result<(), Chip8Error> {
+        self.run_frame_checked(chip8, instructions_per_frame, trace, |_, _, _| Ok(()))
+    }
+
+    /// Like [`BlockEngine::run_frame_traced`], calling `check` after each
+    /// block with the machine, the address of the block and the number of
+    /// instructions it ran.
+    pub fn run_frame_checked<F, C>(
+        &mut self,
+        chip8: &mut Chip8,
+        instructions_per_frame: u32,
+        mut trace: F,
+        mut check: C,
+    ) -> Result<(), Chip8Error>
+    where
+        F: FnMut(&Chip8),
+        C: FnMut(&Chip8, u16, usize) -> Result<(), Chip8Error>,
+    {
+        chip8.signal_vblank();
+
+        // Written by the frontend since the last frame, e.g. a loaded state
+        if let Some(range) = chip8.take_code_writes() {
+            self.invalidate(range, false);
+        }
+
+        let mut remaining = instructions_per_frame as usize;
+        while remaining > 0 && !chip8.halted {
+            let pc = chip8.pc;
+            let executed = self.run_block(chip8, remaining, &mut trace)?;
+            remaining -= executed;
+
+            if let Some(range) = chip8.take_code_writes() {
+                self.invalidate(range, true);
+            }
+
+            check(chip8, pc, executed)?;
+        }
+
+        chip8.tick_timers();
+
+        Ok(())
+    }
+
+    // Runs at most `budget` instructions from `pc`, translating them first
+    // if needed. Returns how many ran.
+    fn run_block<F: FnMut(&Chip8)>(
+        &mut self,
+        chip8: &mut Chip8,
+        budget: usize,
+        trace: &mut F,
+    ) -> Result<usize, Chip8Error> {
+        let pc = chip8.pc as usize;
+
+        if self.blocks[pc].is_none() {
+            self.blocks[pc] = compile(chip8, pc, &self.self_modified);
+        }
+
+        let Some(block) = &self.blocks[pc] else {
+            // Self-modifying code, or nothing left to fetch
+            trace(chip8);
+            chip8.step()?;
+            return Ok(1);
+        };
+
+        let mut executed = 0;
+        let mut ops = block.ops.iter();
+        while executed < budget
+            && let Some(op) = ops.next()
+        {
+            trace(chip8);
+            executed += 1;
+            (op.run)(chip8, op)?;
+
+            if chip8.pc == op.next {
+                continue;
+            }
+
+            if chip8.pc == op.address && idles(op.instruction) {
+                // Nothing changes until the next frame, so the retries that
+                // would fill the rest of it can be counted straight away
+                while executed < budget {
+                    trace(chip8);
+                    executed += 1;
+                }
+                break;
+            }
+
+            // Took a skip over the next instruction in the block
+            match ops.next() {
+                Some(skipped) if chip8.pc == skipped.next => {}
+                _ => break,
+            }
+        }
+
+        Ok(executed)
+    }
+
+    // Drops the blocks overlapping `range`. `by_program` marks the bytes as
+    // self-modifying code if any of them held a block.
+    fn invalidate(&mut self, range: Range<usize>, by_program: bool) {
+        let first = range.start.saturating_sub(MAX_BLOCK_BYTES);
+        let mut overlapped = false;
+
+        for slot in &mut self.blocks[first..range.end] {
+            if slot.as_ref().is_some_and(|block| block.end > range.start) {
+                *slot = None;
+                overlapped = true;
+            }
+        }
+
+        if by_program && overlapped {
+            self.self_modified[range].fill(true);
+        }
+    }
+}
+
+impl Default for BlockEngine {
+    fn default() -> Self {
+        BlockEngine::new()
+    }
+}
+
+// The instructions from `start` up to the first one that jumps or writes
+// memory, stopping before self-modifying code
+fn compile(chip8: &Chip8, start: usize, self_modified: &[bool]) -> Option<Block> {
+    let mut ops = Vec::new();
+    let mut address = start;
+
+    while ops.len() < MAX_BLOCK_LENGTH
+        && address + 1 < MEMORY_SIZE
+        && !self_modified[address]
+        && !self_modified[address + 1]
+    {
+        let opcode = u16::from_be_bytes([chip8.memory[address], chip8.memory[address + 1]]);
+        let instruction = Instruction::decode_lenient(opcode);
+        let length = instruction.size() as usize;
+
+        ops.push(translate(
+            opcode,
+            instruction,
+            address as u16,
+            (address + length) as u16,
+        ));
+        address += length;
+
+        if ends_block(instruction) {
+            break;
+        }
+    }
+
+    (!ops.is_empty()).then(|| Block {
+        end: address.min(MEMORY_SIZE),
+        ops: ops.into_boxed_slice(),
+    })
+}
+
+// Jumps, and writes to memory that could change the rest of the block
+fn ends_block(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Ret
+            | Instruction::Exit
+            | Instruction::Jp(_)
+            | Instruction::Call(_)
+            | Instruction::JpV0(_)
+            | Instruction::Save { .. }
+            | Instruction::LdBVx(_)
+            | Instruction::LdIVx(_)
+    )
+}
+
+// Instructions that leave pc on themselves while waiting for a key or the
+// vblank, or for a jump to itself, and do the same again until a new frame
+fn idles(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Jp(_) | Instruction::Drw { .. } | Instruction::LdVxK(_)
+    )
+}
+
+// An instruction doing what `Chip8::step` does for `opcode` at `address`.
+// The most common simple instructions are inlined, the rest go through
+// `Chip8::execute`.
+fn translate(opcode: u16, instruction: Instruction, address: u16, next: u16) -> Compiled {
+    let mut compiled = Compiled {
+        run: |chip8, op| {
+            chip8.opcode = op.opcode;
+            chip8.pc = op.address.wrapping_add(2);
+            chip8.execute(op.instruction)
+        },
+        opcode,
+        instruction,
+        x: 0,
+        y: 0,
+        kk: 0,
+        nnn: 0,
+        address,
+        next,
+    };
+
+    match instruction {
+        //JP addr
+        Instruction::Jp(nnn) => {
+            compiled.nnn = nnn;
+            compiled.run = |chip8, op| {
+                chip8.opcode = op.opcode;
+                chip8.pc = op.nnn;
+                Ok(())
+            };
+        }
+        //LD Vx, byte
+        Instruction::LdByte { x, kk } => {
+            (compiled.x, compiled.kk) = (x, kk);
+            compiled.run = |chip8, op| {
+                chip8.opcode = op.opcode;
+                chip8.pc = op.next;
+                chip8.registers[op.x as usize] = op.kk;
+                Ok(())
+            };
+        }
+        //ADD Vx, byte
+        Instruction::AddByte { x, kk } => {
+            (compiled.x, compiled.kk) = (x, kk);
+            compiled.run = |chip8, op| {
+                chip8.opcode = op.opcode;
+                chip8.pc = op.next;
+                chip8.registers[op.x as usize] = chip8.registers[op.x as usize].wrapping_add(op.kk);
+                Ok(())
+            };
+        }
+        //LD Vx, Vy
+        Instruction::LdReg { x, y } => {
+            (compiled.x, compiled.y) = (x, y);
+            compiled.run = |chip8, op| {
+                chip8.opcode = op.opcode;
+                chip8.pc = op.next;
+                chip8.registers[op.x as usize] = chip8.registers[op.y as usize];
+                Ok(())
+            };
+        }
+        //LD I, addr
+        Instruction::LdIAddr(nnn) => {
+            compiled.nnn = nnn;
+            compiled.run = |chip8, op| {
+                chip8.opcode = op.opcode;
+                chip8.pc = op.next;
+                chip8.index = op.nnn as u32;
+                Ok(())
+            };
+        }
+        _ => {}
+    }
+
+    compiled
+}
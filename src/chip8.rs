@@ -1,123 +1,424 @@
-use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
-use rand::Rng;
-
-use crate::constants::{FONTSET, FONTSET_SIZE, FONTSET_START_ADDRESS, START_ADDRESS, VIDEO_HEIGHT, VIDEO_WIDTH};
-
-#[derive(Debug)]
+#![allow(non_snake_case)]
+
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::assembler;
+use crate::audio::AudioPattern;
+
+use crate::constants::{
+    AUDIO_PATTERN_SIZE, DEFAULT_PITCH, FONTSET, FONTSET_START_ADDRESS, HIRES_VIDEO_HEIGHT,
+    HIRES_VIDEO_WIDTH, LARGE_FONTSET, LARGE_FONTSET_START_ADDRESS, MEMORY_SIZE, RPL_FLAGS_SIZE,
+    STACK_SIZE, START_ADDRESS, VIDEO_HEIGHT, VIDEO_WIDTH,
+};
+use crate::error::Chip8Error;
+use crate::framebuffer::FrameBuffer;
+use crate::instruction::Instruction;
+use crate::quirks::{LoadStore, Quirks};
+use crate::rng::Rng;
+use crate::unknown::{UnknownOpcodePolicy, UnknownOpcodeReport};
+
+#[derive(Debug, Clone)]
 pub struct Chip8 {
     pub registers: [u8; 16],
-    pub memory: [u8; 4096],
+    pub memory: [u8; MEMORY_SIZE],
     pub index: u32,
     pub pc: u16,
-    pub stack: [u16; 16],
+    pub stack: [u16; STACK_SIZE],
     pub sp: u16,
     pub delay_timer: u8,
     pub sound_timer: u8,
     pub keypad: [u8; 16],
-    pub display: [u32; 64 * 32],
+    // Sized for the largest resolution, low resolution uses the top-left
+    // corner. Pixel values hold one bit per bitplane (bit 0 = plane 1,
+    // bit 1 = plane 2).
+    pub display: FrameBuffer,
+    pub hires: bool,
+    // Bitplanes affected by drawing, clearing and scrolling (XO-CHIP Fn01)
+    pub selected_planes: u8,
+    pub audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    // Set by F002, from then on the pattern replaces the beeper's tone
+    pub audio_pattern_loaded: bool,
+    pub pitch: u8,
+    pub rpl_flags: [u8; RPL_FLAGS_SIZE],
+    // Set by 00FD, the program asked the interpreter to exit
+    pub halted: bool,
+    pub opcode: u16,
+
+    pub quirks: Quirks,
+    pub rng: Rng,
+    // SHA-1 of the loaded program, ties save states and movies to a ROM
+    pub rom_hash: [u8; 20],
+    // Set by the frontend on every vertical blank, consumed by `OP_Dxyn`
+    // when the display wait quirk is enabled.
+    pub vblank: bool,
+
+    pub unknown_opcode_policy: UnknownOpcodePolicy,
+    pub unknown_opcodes: UnknownOpcodeReport,
+
+    // Call `invalidate_code` after writing to `memory` from outside the
+    // instruction handlers.
+    pub code_cache: CodeCache,
+    // First and last byte passed to `invalidate_code` since the last
+    // `take_code_writes`, for backends that keep their own translations
+    pub code_writes: Option<(usize, usize)>,
+}
+
+/// SHA-1 of a ROM image.
+pub fn hash_rom(program: &[u8]) -> [u8; 20] {
+    sha1_smol::Sha1::from(program).digest().bytes()
+}
+
+/// Reads a program from disk: `.8o` files are assembled, anything else is
+/// taken as a binary ROM.
+pub fn read_program(file_path: &str) -> Result<Vec<u8>, Chip8Error> {
+    if Path::new(file_path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("8o"))
+    {
+        let source = fs::read_to_string(file_path)?;
+        return Ok(assembler::assemble(&source)?);
+    }
+
+    // Open the file and go to the last position, to get the file size
+    // and then goes back to the first position.
+    let mut file = File::open(file_path)?;
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+
+    //Save the binary value in the buffer
+    let mut buffer: Vec<u8> = vec![0; size as usize];
+    file.read_exact(&mut buffer)?;
+
+    Ok(buffer)
+}
+
+/// An opcode along with the instruction it runs as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedOp {
     pub opcode: u16,
+    pub instruction: Instruction,
+}
 
-    //tables
-    pub table: [OpFunction; 16],
-    pub table_0: [OpFunction; 0xE + 1],
-    pub table_8: [OpFunction; 0xE + 1],
-    pub table_e: [OpFunction; 0xE + 1],
-    pub table_f: [OpFunction; 0x65 + 1],
+/// The decoded instruction at each address, filled the first time the
+/// address is executed. A clone starts out empty, so snapshots of the
+/// machine don't copy it around.
+#[derive(Debug, Default)]
+pub struct CodeCache {
+    // Allocated on the first insert
+    entries: Option<Box<[Option<DecodedOp>]>>,
 }
 
-pub type OpFunction = fn(&mut Chip8);
-
-pub fn config_chip8_tables(chip8: &mut Chip8) {
-    chip8.table[0x0] = Chip8::table_0_fn;
-    chip8.table[0x1] = Chip8::OP_1nnn;
-    chip8.table[0x2] = Chip8::OP_2nnn;
-    chip8.table[0x3] = Chip8::OP_3xkk;
-    chip8.table[0x4] = Chip8::OP_4xkk;
-    chip8.table[0x5] = Chip8::OP_5xy0;
-    chip8.table[0x6] = Chip8::OP_6xkk;
-    chip8.table[0x7] = Chip8::OP_7xkk;
-    chip8.table[0x8] = Chip8::table_8_fn;
-    chip8.table[0x9] = Chip8::OP_9xy0;
-    chip8.table[0xA] = Chip8::OP_Annn;
-    chip8.table[0xB] = Chip8::OP_Bnnn;
-    chip8.table[0xC] = Chip8::OP_Cxkk;
-    chip8.table[0xD] = Chip8::OP_Dxyn;
-    chip8.table[0xE] = Chip8::table_e_fn;
-    chip8.table[0xF] = Chip8::table_f_fn;
-
-    for i in 0..=0xE {
-        chip8.table_0[i] = Chip8::OP_null;
-        chip8.table_8[i] = Chip8::OP_null;
-        chip8.table_e[i] = Chip8::OP_null;
-    }
-
-    chip8.table_0[0x0] = Chip8::OP_00E0;
-    chip8.table_0[0xE] = Chip8::OP_00EE;
-
-    chip8.table_8[0x0] = Chip8::OP_8xy0;
-    chip8.table_8[0x1] = Chip8::OP_8xy1;
-    chip8.table_8[0x2] = Chip8::OP_8xy2;
-    chip8.table_8[0x3] = Chip8::OP_8xy3;
-    chip8.table_8[0x4] = Chip8::OP_8xy4;
-    chip8.table_8[0x5] = Chip8::OP_8xy5;
-    chip8.table_8[0x6] = Chip8::OP_8xy6;
-    chip8.table_8[0x7] = Chip8::OP_8xy7;
-    chip8.table_8[0xE] = Chip8::OP_8xyE;
-
-    chip8.table_e[0x1] = Chip8::OP_ExA1;
-    chip8.table_e[0xE] = Chip8::OP_Ex9E;
-
-    for i in 0..=0x65 {
-        chip8.table_f[i as usize] = Chip8::OP_null;
-    }
-
-    chip8.table_f[0x07] = Chip8::OP_Fx07;
-    chip8.table_f[0x0A] = Chip8::OP_Fx0A;
-    chip8.table_f[0x15] = Chip8::OP_Fx15;
-    chip8.table_f[0x18] = Chip8::OP_Fx18;
-    chip8.table_f[0x1E] = Chip8::OP_Fx1E;
-    chip8.table_f[0x29] = Chip8::OP_Fx29;
-    chip8.table_f[0x33] = Chip8::OP_Fx33;
-    chip8.table_f[0x55] = Chip8::OP_Fx55;
-    chip8.table_f[0x65] = Chip8::OP_Fx65;
+impl Clone for CodeCache {
+    fn clone(&self) -> Self {
+        CodeCache::default()
+    }
+}
+
+impl CodeCache {
+    pub fn get(&self, address: u16) -> Option<DecodedOp> {
+        self.entries.as_ref()?[address as usize]
+    }
+
+    pub fn insert(&mut self, address: u16, decoded: DecodedOp) {
+        let entries = self
+            .entries
+            .get_or_insert_with(|| vec![None; MEMORY_SIZE].into_boxed_slice());
+
+        entries[address as usize] = Some(decoded);
+    }
+
+    pub fn invalidate(&mut self, range: Range<usize>) {
+        if let Some(entries) = &mut self.entries {
+            entries[range].fill(None);
+        }
+    }
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 //Main functions
 impl Chip8 {
-    pub fn load_rom(&mut self, file_path: &str) -> io::Result<()> {
-        // Open the file and go to the last position, to get the file size
-        // and then goes back to the first position.
-        let mut file = File::open(file_path)?;
-        let size = file.seek(SeekFrom::End(0))?;
-        file.seek(SeekFrom::Start(0))?;
+    /// Creates a machine ready to run: fontset loaded and `pc` pointing at
+    /// `START_ADDRESS`.
+    pub fn new() -> Self {
+        let mut chip8 = Chip8 {
+            registers: [0; 16],
+            memory: [0; MEMORY_SIZE],
+            index: 0,
+            pc: START_ADDRESS,
+            stack: [0; STACK_SIZE],
+            sp: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+            keypad: [0; 16],
+            display: FrameBuffer::new(),
+            hires: false,
+            selected_planes: 0x1,
+            audio_pattern: [0; AUDIO_PATTERN_SIZE],
+            audio_pattern_loaded: false,
+            pitch: DEFAULT_PITCH,
+            rpl_flags: [0; RPL_FLAGS_SIZE],
+            halted: false,
+            opcode: 0,
+
+            quirks: Quirks::default(),
+            rng: Rng::from_entropy(),
+            rom_hash: [0; 20],
+            vblank: false,
+
+            unknown_opcode_policy: UnknownOpcodePolicy::default(),
+            unknown_opcodes: UnknownOpcodeReport::default(),
+
+            code_cache: CodeCache::default(),
+            code_writes: None,
+        };
+
+        chip8.load_fontset();
+
+        chip8
+    }
 
-        //Save the binary value in the buffer
-        let mut buffer: Vec<u8> = vec![0; size as usize];
-        file.read_exact(&mut buffer)?;
+    pub fn load_rom(&mut self, file_path: &str) -> Result<(), Chip8Error> {
+        let program = read_program(file_path)?;
 
-        //Copy the buffer value to the memory
-        for (i, byte) in buffer.iter().enumerate() {
-            self.memory[START_ADDRESS as usize + i] = *byte;
+        self.load_program(&program)
+    }
+
+    /// Copies a program into memory at `START_ADDRESS`.
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), Chip8Error> {
+        let start = START_ADDRESS as usize;
+        let max = MEMORY_SIZE - start;
+
+        if program.len() > max {
+            return Err(Chip8Error::RomTooLarge {
+                size: program.len(),
+                max,
+            });
         }
 
+        self.memory[start..start + program.len()].copy_from_slice(program);
+        self.invalidate_code(start..start + program.len());
+        self.rom_hash = hash_rom(program);
+
         Ok(())
     }
 
     pub fn load_fontset(&mut self) {
-        for i in 0..FONTSET_SIZE as usize {
-            self.memory[FONTSET_START_ADDRESS as usize + i] = FONTSET[i];
+        let start = FONTSET_START_ADDRESS as usize;
+        self.memory[start..start + FONTSET.len()].copy_from_slice(&FONTSET);
+
+        let start = LARGE_FONTSET_START_ADDRESS as usize;
+        self.memory[start..start + LARGE_FONTSET.len()].copy_from_slice(&LARGE_FONTSET);
+
+        self.invalidate_code(FONTSET_START_ADDRESS as usize..start + LARGE_FONTSET.len());
+    }
+
+    /// Forgets the decoded instructions overlapping the bytes in `range`.
+    pub fn invalidate_code(&mut self, range: Range<usize>) {
+        // An instruction starting one byte earlier covers the first byte
+        let start = range.start.saturating_sub(1);
+        let end = range.end.min(MEMORY_SIZE);
+
+        if start < end {
+            self.code_cache.invalidate(start..end);
+            self.code_writes = Some(match self.code_writes {
+                Some((first, last)) => (first.min(start), last.max(end - 1)),
+                None => (start, end - 1),
+            });
+        }
+    }
+
+    /// The bytes written since the last call, if any. Includes the byte
+    /// before each write, like `invalidate_code`.
+    pub fn take_code_writes(&mut self) -> Option<Range<usize>> {
+        self.code_writes.take().map(|(first, last)| first..last + 1)
+    }
+
+    pub fn video_width(&self) -> usize {
+        if self.hires {
+            HIRES_VIDEO_WIDTH as usize
+        } else {
+            VIDEO_WIDTH as usize
+        }
+    }
+
+    pub fn video_height(&self) -> usize {
+        if self.hires {
+            HIRES_VIDEO_HEIGHT as usize
+        } else {
+            VIDEO_HEIGHT as usize
+        }
+    }
+
+    /// The part of `display` covered by the active resolution, one byte
+    /// per pixel, row by row.
+    pub fn active_display(&self) -> Vec<u8> {
+        self.display.pixels(self.video_width(), self.video_height())
+    }
+
+    /// Sample rate of the XO-CHIP audio pattern for the current pitch.
+    pub fn audio_playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    /// What the beeper should play, once the program has loaded a pattern.
+    pub fn sound_pattern(&self) -> Option<AudioPattern> {
+        self.audio_pattern_loaded.then(|| AudioPattern {
+            bits: self.audio_pattern,
+            rate: self.audio_playback_rate(),
+        })
+    }
+
+    // Moves the selected bitplanes by (dx, dy) pixels, filling with 0
+    fn scroll_display(&mut self, dx: isize, dy: isize) {
+        let width = self.video_width();
+        let height = self.video_height();
+
+        self.display
+            .scroll(dx, dy, width, height, self.selected_planes);
+    }
+
+    // Address of the instruction being executed, `pc` has already moved on
+    fn instruction_address(&self) -> u16 {
+        self.pc.wrapping_sub(2)
+    }
+
+    fn illegal_opcode(&self) -> Chip8Error {
+        Chip8Error::IllegalOpcode {
+            pc: self.instruction_address(),
+            opcode: self.opcode,
+        }
+    }
+
+    // Fails unless `len` bytes starting at `address` are all inside memory
+    fn check_memory_range(&self, address: usize, len: usize) -> Result<(), Chip8Error> {
+        if address + len > MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds {
+                pc: self.instruction_address(),
+                address,
+            });
         }
+
+        Ok(())
     }
 
-    pub fn cycle(&mut self) {
-        self.opcode = (self.memory[self.pc as usize] as u16) << 8
-            | (self.memory[(self.pc + 1) as usize]) as u16;
+    fn read_word(&self, address: u16) -> Result<u16, Chip8Error> {
+        if address as usize + 1 >= MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds {
+                pc: address,
+                address: address as usize + 1,
+            });
+        }
 
-        self.pc += 2;
+        Ok((self.memory[address as usize] as u16) << 8 | self.memory[address as usize + 1] as u16)
+    }
 
-        self.table[((self.opcode & 0xF000) >> 12) as usize](self);
+    // Skips the next instruction, which is four bytes long if it is F000 nnnn
+    fn skip_next_instruction(&mut self) {
+        if let Ok(0xF000) = self.read_word(self.pc) {
+            self.pc = self.pc.wrapping_add(4);
+        } else {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    /// Signals that the frontend has just presented a frame.
+    pub fn signal_vblank(&mut self) {
+        self.vblank = true;
+    }
+
+    /// Fetches and executes a single instruction.
+    pub fn step(&mut self) -> Result<(), Chip8Error> {
+        if self.halted {
+            return Ok(());
+        }
+
+        let decoded = match self.code_cache.get(self.pc) {
+            Some(decoded) => decoded,
+            None => {
+                let opcode = self.read_word(self.pc)?;
+                let decoded = DecodedOp {
+                    opcode,
+                    instruction: Instruction::decode_lenient(opcode),
+                };
+                self.code_cache.insert(self.pc, decoded);
+                decoded
+            }
+        };
 
+        self.opcode = decoded.opcode;
+
+        self.pc = self.pc.wrapping_add(2);
+
+        self.execute(decoded.instruction)
+    }
+
+    /// Runs `instruction`, with `opcode` set and `pc` already past it.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), Chip8Error> {
+        match instruction {
+            Instruction::Scd(n) => self.OP_00Cn(n),
+            Instruction::Scu(n) => self.OP_00Dn(n),
+            Instruction::Cls => self.OP_00E0(),
+            Instruction::Ret => self.OP_00EE(),
+            Instruction::Scr => self.OP_00FB(),
+            Instruction::Scl => self.OP_00FC(),
+            Instruction::Exit => self.OP_00FD(),
+            Instruction::Low => self.OP_00FE(),
+            Instruction::High => self.OP_00FF(),
+            Instruction::Jp(nnn) => self.OP_1nnn(nnn),
+            Instruction::Call(nnn) => self.OP_2nnn(nnn),
+            Instruction::SeByte { x, kk } => self.OP_3xkk(x, kk),
+            Instruction::SneByte { x, kk } => self.OP_4xkk(x, kk),
+            Instruction::SeReg { x, y } => self.OP_5xy0(x, y),
+            Instruction::Save { x, y } => self.OP_5xy2(x, y),
+            Instruction::Load { x, y } => self.OP_5xy3(x, y),
+            Instruction::LdByte { x, kk } => self.OP_6xkk(x, kk),
+            Instruction::AddByte { x, kk } => self.OP_7xkk(x, kk),
+            Instruction::LdReg { x, y } => self.OP_8xy0(x, y),
+            Instruction::Or { x, y } => self.OP_8xy1(x, y),
+            Instruction::And { x, y } => self.OP_8xy2(x, y),
+            Instruction::Xor { x, y } => self.OP_8xy3(x, y),
+            Instruction::AddReg { x, y } => self.OP_8xy4(x, y),
+            Instruction::Sub { x, y } => self.OP_8xy5(x, y),
+            Instruction::Shr { x, y } => self.OP_8xy6(x, y),
+            Instruction::Subn { x, y } => self.OP_8xy7(x, y),
+            Instruction::Shl { x, y } => self.OP_8xyE(x, y),
+            Instruction::SneReg { x, y } => self.OP_9xy0(x, y),
+            Instruction::LdIAddr(nnn) => self.OP_Annn(nnn),
+            Instruction::JpV0(nnn) => self.OP_Bnnn(nnn),
+            Instruction::Rnd { x, kk } => self.OP_Cxkk(x, kk),
+            Instruction::Drw { x, y, n } => self.OP_Dxyn(x, y, n),
+            Instruction::Skp(x) => self.OP_Ex9E(x),
+            Instruction::Sknp(x) => self.OP_ExA1(x),
+            Instruction::LdILong => self.OP_F000(),
+            Instruction::Plane(n) => self.OP_Fn01(n),
+            Instruction::Audio => self.OP_F002(),
+            Instruction::LdVxDt(x) => self.OP_Fx07(x),
+            Instruction::LdVxK(x) => self.OP_Fx0A(x),
+            Instruction::LdDtVx(x) => self.OP_Fx15(x),
+            Instruction::LdStVx(x) => self.OP_Fx18(x),
+            Instruction::AddIVx(x) => self.OP_Fx1E(x),
+            Instruction::LdFVx(x) => self.OP_Fx29(x),
+            Instruction::LdHfVx(x) => self.OP_Fx30(x),
+            Instruction::LdBVx(x) => self.OP_Fx33(x),
+            Instruction::Pitch(x) => self.OP_Fx3A(x),
+            Instruction::LdIVx(x) => self.OP_Fx55(x),
+            Instruction::LdVxI(x) => self.OP_Fx65(x),
+            Instruction::LdRVx(x) => self.OP_Fx75(x),
+            Instruction::LdVxR(x) => self.OP_Fx85(x),
+            Instruction::Sys(_) | Instruction::Unknown(_) => self.OP_null(),
+        }
+    }
+
+    /// Decrements the delay and sound timers. Must be called at 60 Hz.
+    pub fn tick_timers(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
@@ -126,122 +427,262 @@ impl Chip8 {
             self.sound_timer -= 1;
         }
     }
+
+    /// Runs one 60 Hz frame: a vertical blank, `instructions_per_frame`
+    /// instructions and a timer tick.
+    pub fn run_frame(&mut self, instructions_per_frame: u32) -> Result<(), Chip8Error> {
+        self.run_frame_traced(instructions_per_frame, |_| {})
+    }
+
+    /// Like [`Chip8::run_frame`], calling `trace` with the machine right
+    /// before each instruction is executed.
+    pub fn run_frame_traced<F: FnMut(&Chip8)>(
+        &mut self,
+        instructions_per_frame: u32,
+        mut trace: F,
+    ) -> Result<(), Chip8Error> {
+        self.signal_vblank();
+
+        for _ in 0..instructions_per_frame {
+            if self.halted {
+                break;
+            }
+
+            trace(self);
+            self.step()?;
+        }
+
+        self.tick_timers();
+
+        Ok(())
+    }
 }
 
 //Instructions
 impl Chip8 {
+    //SCD nibble
+    pub fn OP_00Cn(&mut self, n: u8) -> Result<(), Chip8Error> {
+        let rows = n as isize;
+
+        self.scroll_display(0, rows);
+
+        Ok(())
+    }
+
+    //SCU nibble
+    pub fn OP_00Dn(&mut self, n: u8) -> Result<(), Chip8Error> {
+        let rows = n as isize;
+
+        self.scroll_display(0, -rows);
+
+        Ok(())
+    }
+
     //CLS
-    pub fn OP_00E0(&mut self) {
-        //self.display = [0; 64 * 32];
-        self.display.fill(0);
+    pub fn OP_00E0(&mut self) -> Result<(), Chip8Error> {
+        self.display.clear(self.selected_planes);
+
+        Ok(())
     }
 
     //RET
-    pub fn OP_00EE(&mut self) {
+    pub fn OP_00EE(&mut self) -> Result<(), Chip8Error> {
+        if self.sp == 0 {
+            return Err(Chip8Error::StackUnderflow {
+                pc: self.instruction_address(),
+            });
+        }
+
         self.sp -= 1;
         self.pc = self.stack[self.sp as usize];
+
+        Ok(())
     }
 
-    //JP addr
-    pub fn OP_1nnn(&mut self) {
-        let address: u16 = self.opcode & 0x0FFF as u16;
+    //SCR
+    pub fn OP_00FB(&mut self) -> Result<(), Chip8Error> {
+        self.scroll_display(4, 0);
+
+        Ok(())
+    }
+
+    //SCL
+    pub fn OP_00FC(&mut self) -> Result<(), Chip8Error> {
+        self.scroll_display(-4, 0);
+
+        Ok(())
+    }
+
+    //EXIT
+    pub fn OP_00FD(&mut self) -> Result<(), Chip8Error> {
+        self.halted = true;
+
+        Ok(())
+    }
+
+    //LOW
+    pub fn OP_00FE(&mut self) -> Result<(), Chip8Error> {
+        self.hires = false;
+        self.display.clear(0x3);
+
+        Ok(())
+    }
+
+    //HIGH
+    pub fn OP_00FF(&mut self) -> Result<(), Chip8Error> {
+        self.hires = true;
+        self.display.clear(0x3);
 
+        Ok(())
+    }
+
+    //JP addr
+    pub fn OP_1nnn(&mut self, address: u16) -> Result<(), Chip8Error> {
         self.pc = address;
+
+        Ok(())
     }
 
     //CALL addr
-    pub fn OP_2nnn(&mut self) {
-        let address: u16 = self.opcode & 0x0FFF as u16;
+    pub fn OP_2nnn(&mut self, address: u16) -> Result<(), Chip8Error> {
+        if self.sp as usize >= self.stack.len() {
+            return Err(Chip8Error::StackOverflow {
+                pc: self.instruction_address(),
+            });
+        }
 
         self.stack[self.sp as usize] = self.pc;
         self.sp += 1;
 
         self.pc = address;
+
+        Ok(())
     }
 
     //SE(skip if equal) Vx, byte
-    pub fn OP_3xkk(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let byte: u8 = (self.opcode & 0x00FF) as u8;
-
+    pub fn OP_3xkk(&mut self, vx: u8, byte: u8) -> Result<(), Chip8Error> {
         if self.registers[vx as usize] == byte {
-            self.pc += 2;
+            self.skip_next_instruction();
         }
+
+        Ok(())
     }
 
     //SNE(skip if not equal) Vx, byte
-    pub fn OP_4xkk(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let byte: u8 = (self.opcode & 0x00FF) as u8;
-
+    pub fn OP_4xkk(&mut self, vx: u8, byte: u8) -> Result<(), Chip8Error> {
         if self.registers[vx as usize] != byte {
-            self.pc += 2;
+            self.skip_next_instruction();
         }
+
+        Ok(())
     }
 
     //SE Vx, Vy
-    pub fn OP_5xy0(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_5xy0(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         if self.registers[vx as usize] == self.registers[vy as usize] {
-            self.pc += 2;
+            self.skip_next_instruction();
         }
+
+        Ok(())
     }
 
-    //LD Vx, byte
-    pub fn OP_6xkk(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let byte: u8 = (self.opcode & 0x00FF) as u8;
+    //SAVE Vx - Vy
+    pub fn OP_5xy2(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
+        let (vx, vy) = (vx as usize, vy as usize);
 
+        let registers: Vec<usize> = if vx <= vy {
+            (vx..=vy).collect()
+        } else {
+            (vy..=vx).rev().collect()
+        };
+
+        self.check_memory_range(self.index as usize, registers.len())?;
+
+        let start = self.index as usize;
+        let len = registers.len();
+        for (i, register) in registers.into_iter().enumerate() {
+            self.memory[start + i] = self.registers[register];
+        }
+        self.invalidate_code(start..start + len);
+
+        Ok(())
+    }
+
+    //LOAD Vx - Vy
+    pub fn OP_5xy3(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
+        let (vx, vy) = (vx as usize, vy as usize);
+
+        let registers: Vec<usize> = if vx <= vy {
+            (vx..=vy).collect()
+        } else {
+            (vy..=vx).rev().collect()
+        };
+
+        self.check_memory_range(self.index as usize, registers.len())?;
+
+        for (i, register) in registers.into_iter().enumerate() {
+            self.registers[register] = self.memory[self.index as usize + i];
+        }
+
+        Ok(())
+    }
+
+    //LD Vx, byte
+    pub fn OP_6xkk(&mut self, vx: u8, byte: u8) -> Result<(), Chip8Error> {
         self.registers[vx as usize] = byte;
+
+        Ok(())
     }
 
     //ADD Vx, byte
-    pub fn OP_7xkk(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let byte: u8 = (self.opcode & 0x00FF) as u8;
-
+    pub fn OP_7xkk(&mut self, vx: u8, byte: u8) -> Result<(), Chip8Error> {
         self.registers[vx as usize] = self.registers[vx as usize].wrapping_add(byte);
+
+        Ok(())
     }
 
     //LD Vx, Vy
-    pub fn OP_8xy0(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_8xy0(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         self.registers[vx as usize] = self.registers[vy as usize];
+
+        Ok(())
     }
 
     //OR Vx, Vy
-    pub fn OP_8xy1(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_8xy1(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         self.registers[vx as usize] |= self.registers[vy as usize];
+
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+
+        Ok(())
     }
 
     //AND Vx, Vy
-    pub fn OP_8xy2(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_8xy2(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         self.registers[vx as usize] &= self.registers[vy as usize];
+
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+
+        Ok(())
     }
 
     //XOR Vx, Vy
-    pub fn OP_8xy3(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_8xy3(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         self.registers[vx as usize] ^= self.registers[vy as usize];
+
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+
+        Ok(())
     }
 
     //ADD Vx, Vy
-    pub fn OP_8xy4(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_8xy4(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         let sum: u16 = self.registers[vx as usize] as u16 + self.registers[vy as usize] as u16;
 
         if sum > 255 {
@@ -252,13 +693,12 @@ impl Chip8 {
 
         //In the background, apply the operation sum & 0xFF
         self.registers[vx as usize] = sum as u8;
+
+        Ok(())
     }
 
     //SUB Vx, Vy
-    pub fn OP_8xy5(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_8xy5(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         if self.registers[vx as usize] >= self.registers[vy as usize] {
             self.registers[0xF] = 1;
         } else {
@@ -268,22 +708,23 @@ impl Chip8 {
         //In the background, apply the operation sum & 0xFF
         self.registers[vx as usize] =
             self.registers[vx as usize].wrapping_sub(self.registers[vy as usize]);
+
+        Ok(())
     }
 
-    //SHR Vx
-    pub fn OP_8xy6(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+    //SHR Vx {, Vy}
+    pub fn OP_8xy6(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
+        let source = if self.quirks.shift_uses_vy { vy } else { vx };
+        let value = self.registers[source as usize];
 
-        self.registers[0xF] = self.registers[vx as usize] & 0x1;
+        self.registers[vx as usize] = value >> 1;
+        self.registers[0xF] = value & 0x1;
 
-        self.registers[vx as usize] >>= 1;
+        Ok(())
     }
 
     //SUBN Vx, Vy
-    pub fn OP_8xy7(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_8xy7(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         if self.registers[vx as usize] >= self.registers[vy as usize] {
             self.registers[0xF] = 1;
         } else {
@@ -292,120 +733,203 @@ impl Chip8 {
 
         self.registers[vx as usize] =
             self.registers[vy as usize].wrapping_sub(self.registers[vx as usize]);
+
+        Ok(())
     }
 
     //SHL Vx {, Vy}
-    pub fn OP_8xyE(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+    pub fn OP_8xyE(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
+        let source = if self.quirks.shift_uses_vy { vy } else { vx };
+        let value = self.registers[source as usize];
 
-        self.registers[0xF] = self.registers[vx as usize] & 0x80 >> 7;
+        self.registers[vx as usize] = value << 1;
+        self.registers[0xF] = (value & 0x80) >> 7;
 
-        self.registers[vx as usize] <<= 1;
+        Ok(())
     }
 
     //SNE Vx, Vy
-    pub fn OP_9xy0(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-
+    pub fn OP_9xy0(&mut self, vx: u8, vy: u8) -> Result<(), Chip8Error> {
         if self.registers[vx as usize] != self.registers[vy as usize] {
-            self.pc += 2;
+            self.skip_next_instruction();
         }
+
+        Ok(())
     }
 
     //LD I, addr
     // I = nnn
-    pub fn OP_Annn(&mut self) {
-        let address: u32 = (self.opcode & 0x0FFF) as u32;
+    pub fn OP_Annn(&mut self, address: u16) -> Result<(), Chip8Error> {
+        self.index = address as u32;
 
-        self.index = address;
+        Ok(())
     }
 
     //JP V0, addr
-    pub fn OP_Bnnn(&mut self) {
-        let address: u32 = (self.opcode & 0x0FFF) as u32;
+    //JP Vx, addr (jump quirk)
+    pub fn OP_Bnnn(&mut self, address: u16) -> Result<(), Chip8Error> {
+        let offset_register = if self.quirks.jump_uses_vx {
+            (address >> 8) as usize
+        } else {
+            0
+        };
 
-        self.pc = self.registers[0] as u16 + address as u16;
+        self.pc = address + self.registers[offset_register] as u16;
+
+        Ok(())
     }
 
     //RND Vx, byte
-    pub fn OP_Cxkk(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let byte: u8 = (self.opcode & 0x00FF) as u8;
+    pub fn OP_Cxkk(&mut self, vx: u8, byte: u8) -> Result<(), Chip8Error> {
+        self.registers[vx as usize] = self.rng.next_u8() & byte;
 
-        let mut rng = rand::rng();
-        self.registers[vx as usize] = rng.random::<u8>() & byte;
+        Ok(())
     }
 
     //DRW Vx, Vy, nibble
-    //
-    pub fn OP_Dxyn(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-        let vy: u8 = ((self.opcode & 0x00F0) >> 4) as u8;
-        let height: u8 = (self.opcode & 0x000F) as u8;
+    //Dxy0 draws a 16x16 sprite (SUPER-CHIP)
+    //With several bitplanes selected, the sprite data for each plane
+    //follows the previous one in memory (XO-CHIP)
+    pub fn OP_Dxyn(&mut self, vx: u8, vy: u8, height: u8) -> Result<(), Chip8Error> {
+        if self.quirks.display_wait {
+            // Retry this instruction until the frontend signals a vblank
+            if !self.vblank {
+                self.pc = self.pc.wrapping_sub(2);
+                return Ok(());
+            }
+            self.vblank = false;
+        }
+
+        let (sprite_width, sprite_height): (usize, usize) = if height == 0 {
+            (16, 16)
+        } else {
+            (8, height as usize)
+        };
+        let sprite_bytes = sprite_height * sprite_width / 8;
+
+        let width = self.video_width();
+        let screen_height = self.video_height();
 
-        let x_pos: usize = (self.registers[vx as usize] % VIDEO_WIDTH) as usize;
-        let y_pos: usize = (self.registers[vy as usize] % VIDEO_HEIGHT) as usize;
+        // The starting position always wraps; the quirk only decides what
+        // happens to the part of the sprite that crosses the edge.
+        let x_pos: usize = self.registers[vx as usize] as usize % width;
+        let y_pos: usize = self.registers[vy as usize] as usize % screen_height;
 
         self.registers[0xF] = 0;
 
-        for row in 0..height as usize {
-            if y_pos + row >= VIDEO_HEIGHT as usize {
-                break;
+        let mut sprite_address = self.index as usize;
+
+        for (plane_index, plane) in [0x1, 0x2].into_iter().enumerate() {
+            if self.selected_planes & plane == 0 {
+                continue;
             }
 
-            let sprite_byte = self.memory[self.index as usize + row];
+            self.check_memory_range(sprite_address, sprite_bytes)?;
 
-            for column in 0..8 as usize {
-                let sprite_pixel = sprite_byte & (0x80 >> column);
+            for row in 0..sprite_height {
+                let mut y = y_pos + row;
+                if y >= screen_height {
+                    if !self.quirks.sprite_wrap {
+                        break;
+                    }
+                    y %= screen_height;
+                }
 
-                if sprite_pixel != 0 {
-                    let screen_pixel =
-                        &mut self.display[(y_pos + row) * VIDEO_WIDTH as usize + (x_pos + column)];
+                // Left-align the row so bit 15 is always the leftmost pixel
+                let sprite_row: u16 = if sprite_width == 16 {
+                    let address = sprite_address + row * 2;
+                    (self.memory[address] as u16) << 8 | self.memory[address + 1] as u16
+                } else {
+                    (self.memory[sprite_address + row] as u16) << 8
+                };
+
+                // The screen pixels this row covers, clipped or wrapped
+                let mut screen_row: u128 = 0;
+                for column in 0..sprite_width {
+                    let mut x = x_pos + column;
+                    if x >= width {
+                        if !self.quirks.sprite_wrap {
+                            break;
+                        }
+                        x %= width;
+                    }
 
-                    if *screen_pixel == 0xFFFFFFFF {
-                        self.registers[0xF] = 1;
+                    if sprite_row & (0x8000 >> column) != 0 {
+                        screen_row |= 1 << (127 - x);
                     }
+                }
 
-                    *screen_pixel ^= 0xFFFFFFFF;
+                if self.display.draw_row(plane_index, y, screen_row) {
+                    self.registers[0xF] = 1;
                 }
             }
+
+            sprite_address += sprite_bytes;
         }
+
+        Ok(())
     }
 
     //SKP Vx
-    pub fn OP_Ex9E(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-
-        let key = self.registers[vx as usize];
+    pub fn OP_Ex9E(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        let key = self.registers[vx as usize] & 0xF;
 
         if self.keypad[key as usize] != 0 {
-            self.pc += 2;
+            self.skip_next_instruction();
         }
+
+        Ok(())
     }
 
     //SKNP Vx
-    pub fn OP_ExA1(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-
-        let key = self.registers[vx as usize];
+    pub fn OP_ExA1(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        let key = self.registers[vx as usize] & 0xF;
 
         if self.keypad[key as usize] == 0 {
-            self.pc += 2;
+            self.skip_next_instruction();
         }
+
+        Ok(())
     }
 
-    //LD Vx, DT
-    pub fn OP_Fx07(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+    //LD I, long addr
+    pub fn OP_F000(&mut self) -> Result<(), Chip8Error> {
+        let address = self.read_word(self.pc)?;
+
+        self.index = address as u32;
+        self.pc = self.pc.wrapping_add(2);
+
+        Ok(())
+    }
+
+    //PLANE n
+    pub fn OP_Fn01(&mut self, planes: u8) -> Result<(), Chip8Error> {
+        self.selected_planes = planes & 0x3;
 
+        Ok(())
+    }
+
+    //AUDIO
+    pub fn OP_F002(&mut self) -> Result<(), Chip8Error> {
+        let start = self.index as usize;
+        self.check_memory_range(start, AUDIO_PATTERN_SIZE)?;
+
+        self.audio_pattern
+            .copy_from_slice(&self.memory[start..start + AUDIO_PATTERN_SIZE]);
+        self.audio_pattern_loaded = true;
+
+        Ok(())
+    }
+
+    //LD Vx, DT
+    pub fn OP_Fx07(&mut self, vx: u8) -> Result<(), Chip8Error> {
         self.registers[vx as usize] = self.delay_timer;
+
+        Ok(())
     }
 
     //LD Vx, K
-    pub fn OP_Fx0A(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-
+    pub fn OP_Fx0A(&mut self, vx: u8) -> Result<(), Chip8Error> {
         if self.keypad[0] != 0 {
             self.registers[vx as usize] = 0;
         } else if self.keypad[1] != 0 {
@@ -439,44 +963,64 @@ impl Chip8 {
         } else if self.keypad[15] != 0 {
             self.registers[vx as usize] = 15;
         } else {
-            self.pc -= 2;
+            self.pc = self.pc.wrapping_sub(2);
         }
+
+        Ok(())
     }
 
     //LD DT, Vx
-    pub fn OP_Fx15(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-
+    pub fn OP_Fx15(&mut self, vx: u8) -> Result<(), Chip8Error> {
         self.delay_timer = self.registers[vx as usize];
+
+        Ok(())
     }
 
     //LD ST, Vx
-    pub fn OP_Fx18(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
-
+    pub fn OP_Fx18(&mut self, vx: u8) -> Result<(), Chip8Error> {
         self.sound_timer = self.registers[vx as usize];
+
+        Ok(())
     }
 
     //ADD I, Vx
-    pub fn OP_Fx1E(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+    pub fn OP_Fx1E(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        self.index = self.index.wrapping_add(self.registers[vx as usize] as u32);
 
-        self.index += self.registers[vx as usize] as u32;
+        Ok(())
     }
 
     //LD F, Vx
-    pub fn OP_Fx29(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+    pub fn OP_Fx29(&mut self, vx: u8) -> Result<(), Chip8Error> {
         let digit = self.registers[vx as usize];
 
         self.index = FONTSET_START_ADDRESS as u32 + (5 * digit as u32);
+
+        Ok(())
+    }
+
+    //LD HF, Vx
+    pub fn OP_Fx30(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        let digit = self.registers[vx as usize];
+
+        self.index = LARGE_FONTSET_START_ADDRESS as u32 + (10 * digit as u32);
+
+        Ok(())
+    }
+
+    //PITCH Vx
+    pub fn OP_Fx3A(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        self.pitch = self.registers[vx as usize];
+
+        Ok(())
     }
 
     //LD B, Vx
-    pub fn OP_Fx33(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+    pub fn OP_Fx33(&mut self, vx: u8) -> Result<(), Chip8Error> {
         let mut value = self.registers[vx as usize];
 
+        self.check_memory_range(self.index as usize, 3)?;
+
         self.memory[(self.index + 2) as usize] = value % 10;
         value /= 10;
 
@@ -484,52 +1028,607 @@ impl Chip8 {
         value /= 10;
 
         self.memory[self.index as usize] = value % 10;
+
+        let start = self.index as usize;
+        self.invalidate_code(start..start + 3);
+
+        Ok(())
+    }
+
+    // Moves I after Fx55/Fx65 as the load/store quirk says
+    fn apply_load_store_quirk(&mut self, vx: u8) {
+        let increment = match self.quirks.load_store {
+            LoadStore::Unchanged => return,
+            LoadStore::AddX => vx as u32,
+            LoadStore::AddXPlusOne => vx as u32 + 1,
+        };
+
+        self.index = self.index.wrapping_add(increment);
     }
 
     //LD [I], Vx
-    pub fn OP_Fx55(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+    pub fn OP_Fx55(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        self.check_memory_range(self.index as usize, vx as usize + 1)?;
 
         for i in 0..=vx as usize {
             self.memory[self.index as usize + i] = self.registers[i];
         }
+
+        let start = self.index as usize;
+        self.invalidate_code(start..start + vx as usize + 1);
+
+        self.apply_load_store_quirk(vx);
+
+        Ok(())
     }
 
     //LD Vx, [I]
-    pub fn OP_Fx65(&mut self) {
-        let vx: u8 = ((self.opcode & 0x0F00) >> 8) as u8;
+    pub fn OP_Fx65(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        self.check_memory_range(self.index as usize, vx as usize + 1)?;
 
         for i in 0..=vx as usize {
             self.registers[i] = self.memory[self.index as usize + i];
         }
+
+        self.apply_load_store_quirk(vx);
+
+        Ok(())
+    }
+
+    //LD R, Vx
+    pub fn OP_Fx75(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        let count = (vx as usize + 1).min(RPL_FLAGS_SIZE);
+
+        self.rpl_flags[..count].copy_from_slice(&self.registers[..count]);
+
+        Ok(())
+    }
+
+    //LD Vx, R
+    pub fn OP_Fx85(&mut self, vx: u8) -> Result<(), Chip8Error> {
+        let count = (vx as usize + 1).min(RPL_FLAGS_SIZE);
+
+        self.registers[..count].copy_from_slice(&self.rpl_flags[..count]);
+
+        Ok(())
     }
 
-    pub fn OP_null(&mut self) {}
+    //Every opcode without a handler ends up here
+    pub fn OP_null(&mut self) -> Result<(), Chip8Error> {
+        self.unknown_opcodes
+            .record(self.instruction_address(), self.opcode);
+
+        let machine_code_call = self.opcode & 0xF000 == 0 && self.opcode != 0x0000;
+
+        match self.unknown_opcode_policy {
+            UnknownOpcodePolicy::Continue => Ok(()),
+            UnknownOpcodePolicy::IgnoreMachineCode if machine_code_call => Ok(()),
+            _ => Err(self.illegal_opcode()),
+        }
+    }
 }
 
-//Table functions
-impl Chip8 {
-    pub fn table_0_fn(&mut self) {
-        let index = (self.opcode & 0x000F) as usize;
-        let op_function = self.table_0[index];
-        op_function(self);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(program: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(program).unwrap();
+        chip8
     }
 
-    pub fn table_8_fn(&mut self) {
-        let index = (self.opcode & 0x000F) as usize;
-        let op_function = self.table_8[index];
-        op_function(self);
+    #[test]
+    fn add_to_index_wraps() {
+        // ADD I, V0
+        let mut chip8 = machine(&[0xF0, 0x1E]);
+        chip8.index = u32::MAX;
+        chip8.registers[0] = 2;
+
+        chip8.step().unwrap();
+
+        assert_eq!(chip8.index, 1);
+    }
+
+    #[test]
+    fn out_of_bounds_store_reports_its_start() {
+        // LD [I], V3
+        let mut chip8 = machine(&[0xF3, 0x55]);
+        chip8.index = MEMORY_SIZE as u32 - 2;
+
+        assert!(matches!(
+            chip8.step(),
+            Err(Chip8Error::MemoryOutOfBounds { address, .. }) if address == MEMORY_SIZE - 2
+        ));
     }
 
-    pub fn table_e_fn(&mut self) {
-        let index = (self.opcode & 0x000F) as usize;
-        let op_function = self.table_e[index];
-        op_function(self);
+    fn with_quirks(quirks: Quirks) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.quirks = quirks;
+        chip8
     }
 
-    pub fn table_f_fn(&mut self) {
-        let index = (self.opcode & 0x00FF) as usize;
-        let op_function = self.table_f[index];
-        op_function(self);
+    #[test]
+    fn shift_quirk_picks_the_source_register() {
+        for (shift_uses_vy, right, left) in [(false, 0x40, 0x02), (true, 0x02, 0x08)] {
+            let mut chip8 = with_quirks(Quirks {
+                shift_uses_vy,
+                ..Quirks::LEGACY
+            });
+
+            chip8.registers[1] = 0x81;
+            chip8.registers[2] = 0x04;
+            chip8.OP_8xy6(1, 2).unwrap();
+            assert_eq!(chip8.registers[1], right);
+
+            chip8.registers[1] = 0x81;
+            chip8.OP_8xyE(1, 2).unwrap();
+            assert_eq!(chip8.registers[1], left);
+            // The bit shifted out
+            assert_eq!(chip8.registers[0xF], !shift_uses_vy as u8);
+        }
+    }
+
+    #[test]
+    fn jump_quirk_picks_the_offset_register() {
+        for (jump_uses_vx, target) in [(false, 0x235), (true, 0x237)] {
+            let mut chip8 = with_quirks(Quirks {
+                jump_uses_vx,
+                ..Quirks::LEGACY
+            });
+            chip8.registers[0] = 1;
+            chip8.registers[2] = 3;
+
+            chip8.OP_Bnnn(0x234).unwrap();
+
+            assert_eq!(chip8.pc, target);
+        }
+    }
+
+    #[test]
+    fn load_store_quirk_moves_the_index() {
+        for (preset, index) in [
+            ("legacy", 0x300),
+            ("vip", 0x304),
+            ("chip48", 0x303),
+            ("schip", 0x300),
+            ("octo", 0x304),
+        ] {
+            let mut chip8 = with_quirks(Quirks::from_name(preset).unwrap());
+            chip8.registers[..4].copy_from_slice(&[1, 2, 3, 4]);
+
+            chip8.index = 0x300;
+            chip8.OP_Fx55(3).unwrap();
+            assert_eq!(chip8.memory[0x300..0x304], [1, 2, 3, 4], "{}", preset);
+            assert_eq!(chip8.index, index, "{}", preset);
+
+            chip8.registers = [0; 16];
+            chip8.index = 0x300;
+            chip8.OP_Fx65(3).unwrap();
+            assert_eq!(chip8.registers[..4], [1, 2, 3, 4], "{}", preset);
+            assert_eq!(chip8.index, index, "{}", preset);
+        }
+    }
+
+    #[test]
+    fn vf_reset_quirk_clears_vf_after_logic_ops() {
+        type Handler = fn(&mut Chip8, u8, u8) -> Result<(), Chip8Error>;
+        let handlers: [Handler; 3] = [Chip8::OP_8xy1, Chip8::OP_8xy2, Chip8::OP_8xy3];
+
+        for vf_reset in [false, true] {
+            for handler in handlers {
+                let mut chip8 = with_quirks(Quirks {
+                    vf_reset,
+                    ..Quirks::LEGACY
+                });
+                chip8.registers[0xF] = 5;
+
+                handler(&mut chip8, 1, 2).unwrap();
+
+                assert_eq!(chip8.registers[0xF], if vf_reset { 0 } else { 5 });
+            }
+        }
+    }
+
+    #[test]
+    fn sprite_wrap_quirk_clips_or_wraps_at_the_edges() {
+        for sprite_wrap in [false, true] {
+            let mut chip8 = with_quirks(Quirks {
+                sprite_wrap,
+                ..Quirks::LEGACY
+            });
+            // The top two rows of the "0" glyph, 0xF0 and 0x90, in the
+            // bottom right corner
+            chip8.index = FONTSET_START_ADDRESS as u32;
+            chip8.registers[0] = 62;
+            chip8.registers[1] = 31;
+
+            chip8.OP_Dxyn(0, 1, 2).unwrap();
+
+            let pixel = |x, y| chip8.display.pixel(x, y);
+            assert_eq!((pixel(62, 31), pixel(63, 31)), (1, 1));
+            assert_eq!(
+                (pixel(0, 31), pixel(1, 31)),
+                (sprite_wrap as u8, sprite_wrap as u8)
+            );
+            assert_eq!(
+                (pixel(62, 0), pixel(1, 0)),
+                (sprite_wrap as u8, sprite_wrap as u8)
+            );
+            // Pixels past the low resolution edge are never drawn
+            assert_eq!(pixel(64, 31), 0);
+        }
+    }
+
+    #[test]
+    fn display_wait_quirk_waits_for_the_vblank() {
+        let mut chip8 = with_quirks(Quirks::COSMAC_VIP);
+        chip8.index = FONTSET_START_ADDRESS as u32;
+        chip8.vblank = false;
+        chip8.pc = 0x202;
+
+        // Retried until the vblank
+        chip8.OP_Dxyn(0, 0, 1).unwrap();
+        assert_eq!(chip8.pc, 0x200);
+        assert_eq!(chip8.display.pixel(0, 0), 0);
+
+        chip8.signal_vblank();
+        chip8.pc = 0x202;
+        chip8.OP_Dxyn(0, 0, 1).unwrap();
+        assert_eq!(chip8.pc, 0x202);
+        assert_eq!(chip8.display.pixel(0, 0), 1);
+    }
+
+    // A hires machine with the single pixel (10, 10) lit
+    fn hires_with_pixel() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.OP_00FF().unwrap();
+        chip8.display.draw_row(0, 10, 1 << (127 - 10));
+        chip8
+    }
+
+    fn lit_pixels(chip8: &Chip8) -> Vec<(usize, usize)> {
+        let width = chip8.video_width();
+
+        chip8
+            .active_display()
+            .iter()
+            .enumerate()
+            .filter(|(_, pixel)| **pixel != 0)
+            .map(|(i, _)| (i % width, i / width))
+            .collect()
+    }
+
+    #[test]
+    fn switches_resolution_and_clears() {
+        let mut chip8 = hires_with_pixel();
+        assert!(chip8.hires);
+        assert_eq!((chip8.video_width(), chip8.video_height()), (128, 64));
+
+        chip8.display.take_dirty();
+        chip8.OP_00FE().unwrap();
+        assert!(!chip8.hires);
+        assert_eq!((chip8.video_width(), chip8.video_height()), (64, 32));
+        assert_eq!(lit_pixels(&chip8), []);
+        // The frontend has to redraw the whole display
+        assert_eq!(chip8.display.take_dirty(), Some(0..64));
+
+        chip8.display.draw_row(0, 10, 1 << (127 - 10));
+        chip8.display.take_dirty();
+        chip8.OP_00FF().unwrap();
+        assert_eq!(lit_pixels(&chip8), []);
+        assert_eq!(chip8.display.take_dirty(), Some(0..64));
+    }
+
+    #[test]
+    fn scrolls_in_each_direction() {
+        type Scroll = fn(&mut Chip8) -> Result<(), Chip8Error>;
+        let scrolls: [(Scroll, (usize, usize)); 4] = [
+            (|chip8| chip8.OP_00Cn(3), (10, 13)),
+            (|chip8| chip8.OP_00Dn(3), (10, 7)),
+            (Chip8::OP_00FB, (14, 10)),
+            (Chip8::OP_00FC, (6, 10)),
+        ];
+
+        for (scroll, pixel) in scrolls {
+            let mut chip8 = hires_with_pixel();
+
+            scroll(&mut chip8).unwrap();
+
+            assert_eq!(lit_pixels(&chip8), [pixel]);
+        }
+    }
+
+    #[test]
+    fn scrolls_pixels_off_the_edge() {
+        let mut chip8 = hires_with_pixel();
+
+        chip8.OP_00Dn(11).unwrap();
+
+        assert_eq!(lit_pixels(&chip8), []);
+    }
+
+    #[test]
+    fn large_sprites_draw_16_by_16_and_detect_collisions() {
+        let mut chip8 = Chip8::new();
+        chip8.OP_00FF().unwrap();
+        // A 16x16 sprite with only its bottom right pixel set
+        chip8.index = 0x300;
+        chip8.memory[0x300 + 31] = 0x01;
+        chip8.registers[0] = 20;
+
+        chip8.OP_Dxyn(0, 0, 0).unwrap();
+        assert_eq!(lit_pixels(&chip8), [(35, 35)]);
+        assert_eq!(chip8.registers[0xF], 0);
+
+        chip8.OP_Dxyn(0, 0, 0).unwrap();
+        assert_eq!(lit_pixels(&chip8), []);
+        assert_eq!(chip8.registers[0xF], 1);
+
+        // A full sprite over a single lit pixel still sets VF to 1
+        chip8.memory[0x300..0x320].fill(0xFF);
+        chip8.display.draw_row(0, 27, 1 << (127 - 27));
+        chip8.OP_Dxyn(0, 0, 0).unwrap();
+        assert_eq!(chip8.registers[0xF], 1);
+        assert_eq!(lit_pixels(&chip8).len(), 16 * 16 - 1);
+    }
+
+    #[test]
+    fn points_at_the_large_font() {
+        let mut chip8 = Chip8::new();
+        chip8.registers[3] = 7;
+
+        chip8.OP_Fx30(3).unwrap();
+
+        assert_eq!(chip8.index, LARGE_FONTSET_START_ADDRESS as u32 + 70);
+    }
+
+    #[test]
+    fn saves_and_restores_rpl_flags() {
+        let mut chip8 = Chip8::new();
+        for (i, register) in chip8.registers.iter_mut().enumerate() {
+            *register = i as u8 + 1;
+        }
+
+        chip8.OP_Fx75(3).unwrap();
+        assert_eq!(chip8.rpl_flags[..5], [1, 2, 3, 4, 0]);
+
+        chip8.registers = [0; 16];
+        chip8.OP_Fx85(2).unwrap();
+        assert_eq!(chip8.registers[..4], [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn exit_halts() {
+        // EXIT, then ADD V0, 1
+        let mut chip8 = machine(&[0x00, 0xFD, 0x70, 0x01]);
+
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+
+        assert!(chip8.halted);
+        assert_eq!(chip8.registers[0], 0);
+    }
+
+    #[test]
+    fn saves_and_loads_register_ranges_in_both_directions() {
+        let mut chip8 = Chip8::new();
+        chip8.registers[2..6].copy_from_slice(&[1, 2, 3, 4]);
+        chip8.index = 0x300;
+
+        chip8.OP_5xy2(2, 5).unwrap();
+        assert_eq!(chip8.memory[0x300..0x304], [1, 2, 3, 4]);
+        chip8.OP_5xy2(5, 2).unwrap();
+        assert_eq!(chip8.memory[0x300..0x304], [4, 3, 2, 1]);
+        // I doesn't move
+        assert_eq!(chip8.index, 0x300);
+
+        chip8.memory[0x300..0x304].copy_from_slice(&[5, 6, 7, 8]);
+        chip8.OP_5xy3(8, 11).unwrap();
+        assert_eq!(chip8.registers[8..12], [5, 6, 7, 8]);
+        chip8.OP_5xy3(11, 8).unwrap();
+        assert_eq!(chip8.registers[8..12], [8, 7, 6, 5]);
+    }
+
+    #[test]
+    fn loads_a_long_index() {
+        // i := long 0x1234, then ADD V0, 1
+        let mut chip8 = machine(&[0xF0, 0x00, 0x12, 0x34, 0x70, 0x01]);
+
+        chip8.step().unwrap();
+
+        assert_eq!(chip8.index, 0x1234);
+        assert_eq!(chip8.pc, 0x204);
+    }
+
+    #[test]
+    fn skips_over_a_long_index_load() {
+        // SE V0, 0, then i := long 0x1234, then ADD V0, 1
+        let mut chip8 = machine(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x70, 0x01]);
+
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc, 0x206);
+
+        chip8.step().unwrap();
+        assert_eq!(chip8.registers[0], 1);
+        assert_eq!(chip8.index, 0);
+    }
+
+    #[test]
+    fn draws_on_the_selected_planes() {
+        let mut chip8 = Chip8::new();
+        // One row for plane 1, then one for plane 2
+        chip8.index = 0x300;
+        chip8.memory[0x300..0x302].copy_from_slice(&[0xC0, 0x60]);
+
+        chip8.OP_Fn01(2).unwrap();
+        chip8.OP_Dxyn(0, 0, 1).unwrap();
+        assert_eq!(chip8.active_display()[..4], [2, 2, 0, 0]);
+
+        chip8.OP_00E0().unwrap();
+        chip8.OP_Fn01(3).unwrap();
+        chip8.OP_Dxyn(0, 0, 1).unwrap();
+        assert_eq!(chip8.active_display()[..4], [1, 3, 2, 0]);
+
+        // Clearing only touches the selected planes
+        chip8.OP_Fn01(1).unwrap();
+        chip8.OP_00E0().unwrap();
+        assert_eq!(chip8.active_display()[..4], [0, 2, 2, 0]);
+    }
+
+    #[test]
+    fn scrolls_only_the_selected_planes() {
+        let mut chip8 = Chip8::new();
+        chip8.display.draw_row(0, 0, 1 << 127);
+        chip8.display.draw_row(1, 0, 1 << 127);
+
+        chip8.OP_Fn01(2).unwrap();
+        chip8.OP_00Cn(1).unwrap();
+
+        assert_eq!(chip8.display.pixel(0, 0), 1);
+        assert_eq!(chip8.display.pixel(0, 1), 2);
+    }
+
+    #[test]
+    fn loads_the_audio_pattern_and_pitch() {
+        let mut chip8 = Chip8::new();
+        chip8.index = 0x300;
+        for (i, byte) in chip8.memory[0x300..0x310].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        chip8.registers[4] = 112;
+
+        chip8.OP_F002().unwrap();
+        chip8.OP_Fx3A(4).unwrap();
+
+        assert_eq!(chip8.audio_pattern, chip8.memory[0x300..0x310]);
+        assert_eq!(chip8.pitch, 112);
+    }
+
+    #[test]
+    fn plays_the_audio_pattern_once_loaded() {
+        let mut chip8 = Chip8::new();
+        assert!(chip8.sound_pattern().is_none());
+
+        chip8.memory[0x300] = 0xAA;
+        chip8.index = 0x300;
+        chip8.OP_F002().unwrap();
+        // Pitch 64 plays at 4000 Hz, each 48 above doubles the rate
+        let pattern = chip8.sound_pattern().unwrap();
+        assert_eq!(pattern.bits[0], 0xAA);
+        assert_eq!(pattern.rate, 4000.0);
+
+        chip8.pitch = 112;
+        assert_eq!(chip8.sound_pattern().unwrap().rate, 8000.0);
+    }
+
+    // Runs an unknown `5xy1`, a `0nnn` machine code call and `0000`, each
+    // followed by ADD V0, 1, and returns what each step gave
+    fn run_unknown_opcodes(policy: UnknownOpcodePolicy) -> (Chip8, Vec<bool>) {
+        let mut chip8 = machine(&[
+            0x51, 0x21, 0x70, 0x01, 0x01, 0x23, 0x70, 0x01, 0x00, 0x00, 0x70, 0x01,
+        ]);
+        chip8.unknown_opcode_policy = policy;
+
+        let results = (0..3)
+            .map(|i| {
+                chip8.pc = 0x200 + i * 4;
+                let result = chip8.step().is_ok();
+                chip8.step().unwrap();
+                result
+            })
+            .collect();
+
+        (chip8, results)
+    }
+
+    #[test]
+    fn unknown_opcodes_continue_by_default() {
+        assert_eq!(
+            Chip8::new().unknown_opcode_policy,
+            UnknownOpcodePolicy::Continue
+        );
+    }
+
+    #[test]
+    fn unknown_opcode_policies() {
+        for (policy, expected) in [
+            (UnknownOpcodePolicy::Continue, [true, true, true]),
+            (UnknownOpcodePolicy::Halt, [false, false, false]),
+            (UnknownOpcodePolicy::IgnoreMachineCode, [false, true, false]),
+        ] {
+            let (chip8, results) = run_unknown_opcodes(policy);
+
+            assert_eq!(results, expected, "{:?}", policy);
+            // Reported whatever the policy
+            assert_eq!(
+                chip8.unknown_opcodes.iter().collect::<Vec<_>>(),
+                [(0x200, 0x5121, 1), (0x204, 0x0123, 1), (0x208, 0x0000, 1)],
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn halting_reports_the_address_and_opcode() {
+        let mut chip8 = machine(&[0x70, 0x01, 0xE1, 0x23]);
+        chip8.unknown_opcode_policy = UnknownOpcodePolicy::Halt;
+
+        chip8.step().unwrap();
+
+        assert!(matches!(
+            chip8.step(),
+            Err(Chip8Error::IllegalOpcode {
+                pc: 0x202,
+                opcode: 0xE123
+            })
+        ));
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame_whatever_the_instruction_rate() {
+        for instructions_per_frame in [0, 1, 10, 1000] {
+            // JP 0x200
+            let mut chip8 = machine(&[0x12, 0x00]);
+            chip8.delay_timer = 10;
+            chip8.sound_timer = 5;
+
+            for _ in 0..3 {
+                chip8.run_frame(instructions_per_frame).unwrap();
+            }
+
+            assert_eq!(
+                (chip8.delay_timer, chip8.sound_timer),
+                (7, 2),
+                "{} instructions per frame",
+                instructions_per_frame
+            );
+        }
+    }
+
+    #[test]
+    fn timers_stop_at_zero() {
+        let mut chip8 = Chip8::new();
+        chip8.delay_timer = 1;
+
+        chip8.tick_timers();
+        chip8.tick_timers();
+
+        assert_eq!((chip8.delay_timer, chip8.sound_timer), (0, 0));
+    }
+
+    #[test]
+    fn stepping_leaves_the_timers_alone() {
+        // JP 0x200
+        let mut chip8 = machine(&[0x12, 0x00]);
+        chip8.delay_timer = 10;
+        chip8.sound_timer = 5;
+
+        for _ in 0..1000 {
+            chip8.step().unwrap();
+        }
+
+        assert_eq!((chip8.delay_timer, chip8.sound_timer), (10, 5));
     }
 }
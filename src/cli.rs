@@ -0,0 +1,595 @@
+use chip_8::audio::{BeeperSettings, Waveform};
+use chip_8::constants::{DEFAULT_INSTRUCTIONS_PER_FRAME, MEMORY_SIZE, TIMER_FREQUENCY};
+use chip_8::headless::Check;
+use chip_8::recording::MAX_GIF_SCALE;
+use chip_8::screenshot::ImageFormat;
+use chip_8::trace::TraceFilter;
+use chip_8::{Backend, Palette, Quirks, UnknownOpcodePolicy};
+
+use crate::config;
+
+pub const DEFAULT_VIDEO_SCALE: u32 = 10;
+const DEFAULT_REWIND_SECONDS: u32 = 10;
+// Every rewound frame costs memory, an hour is already far more than useful
+const MAX_REWIND_SECONDS: u32 = 3600;
+const DEFAULT_TEST_FRAMES: u32 = 300;
+const DEFAULT_GIF_SCALE: u32 = 4;
+const DEFAULT_BENCH_FRAMES: u32 = 3600;
+pub const DEFAULT_BENCH_INSTRUCTIONS_PER_FRAME: u32 = 1000;
+
+/// Options shared by every mode that runs a machine. Those left `None`
+/// come from the config file or the mode's defaults.
+#[derive(Debug, Clone, Default)]
+pub struct MachineOptions {
+    pub instructions_per_frame: Option<u32>,
+    pub quirks: Option<Quirks>,
+    pub unknown_opcode_policy: UnknownOpcodePolicy,
+    pub seed: Option<u64>,
+    pub backend: Backend,
+    pub config: Option<String>,
+}
+
+/// Options of the emulator. Those left `None` come from the config file or
+/// the built-in defaults.
+pub struct Options {
+    pub video_scale: Option<u32>,
+    pub rom_filename: String,
+    pub machine: MachineOptions,
+    pub rewind_seconds: u32,
+    pub record_movie: Option<String>,
+    pub play_movie: Option<String>,
+    pub debug: bool,
+    pub trace: Option<String>,
+    pub trace_filter: TraceFilter,
+    pub screenshot_format: ImageFormat,
+    pub screenshot_scale: u32,
+    pub record_gif: Option<String>,
+    pub gif_scale: u32,
+    pub beeper: BeeperSettings,
+    pub mute: bool,
+    pub palette: Option<Palette>,
+}
+
+/// Options of the headless `test` mode.
+pub struct TestOptions {
+    pub rom_filename: String,
+    pub frames: u32,
+    pub machine: MachineOptions,
+    pub expect_display: Option<String>,
+    pub save_display: Option<String>,
+    pub screenshot: Option<String>,
+    pub screenshot_scale: u32,
+    pub beeper: BeeperSettings,
+    pub audio_wav: Option<String>,
+    pub palette: Option<Palette>,
+    // Memory and pc checks, the display check needs the golden file read
+    pub checks: Vec<Check>,
+}
+
+/// Options of the `bench` mode.
+pub struct BenchOptions {
+    pub rom_filename: String,
+    pub frames: u32,
+    pub machine: MachineOptions,
+}
+
+/// What the binary was asked to do.
+pub enum Mode {
+    Emulate(Options),
+    Disassemble { rom_filename: String },
+    Test(TestOptions),
+    Bench(BenchOptions),
+}
+
+pub fn usage(program: &str) -> String {
+    format!(
+        "Usage: {program} [options] [<Scale>] <ROM>
+       {program} disasm <ROM>
+       {program} test [options] <ROM>
+       {program} bench [options] <ROM>
+
+Options:
+  --ipf <n>                  Instructions per 60 Hz frame (default {})
+  --quirks <{}>
+                             Quirks preset for ambiguous instructions (default legacy)
+  --unknown-opcodes <{}>
+                             What to do when an unknown opcode is executed
+                             (default continue)
+  --seed <n>                 Seed for the random number generator
+  --backend <{}>
+                             How instructions are executed (default interpreter,
+                             lockstep checks blocks against the interpreter)
+  --rewind <seconds>         How far back Backspace can rewind (default {}, at most {},
+                             0 disables)
+  --record <file>            Record the keypad input to a movie file
+  --play <file>              Replay a movie recorded with --record
+  --debug                    Run in the terminal debugger instead of the window
+  --trace <file>             Write every executed instruction to a file
+  --trace-addresses <a-b>    Only trace instructions between these addresses
+  --trace-cycles <a-b>       Only trace instructions in this cycle window
+  --screenshot-format <{}>
+                             Format of F12 screenshots (default png)
+  --screenshot-scale <n>     Pixel size in screenshots (default 1)
+  --record-gif <file>        Record the session to an animated GIF
+  --gif-scale <n>            Size of a high resolution pixel in the GIF (default {},
+                             at most {})
+  --beep-frequency <hz>      Pitch of the beeper (default {})
+  --volume <0-100>           Volume of the beeper (default {})
+  --waveform <{}>
+                             Shape of the beep (default square)
+  --mute                     Start muted, F8 toggles the sound
+  --palette <{}|bg,fg[,plane2,both]>
+                             Display colours, a preset or RRGGBB hex colours
+                             (default classic, F7 cycles through the presets)
+  --config <file>            Settings file (default {})
+
+The settings file sets scale, ipf, palette, quirks and [keys] (CHIP-8 key = SDL
+key name) for every ROM, or in [rom.\"<file name or SHA-1>\"] sections. Flags
+on the command line take precedence. test and bench use its ipf, quirks and
+palette too, but only read the file given with --config.
+
+Test options (no window, exits with status 1 if a check fails):
+  --frames <n>               Frames to run (default {})
+  --expect-display <file>    The display must match this golden image
+  --expect-memory <addr>:<hex bytes>
+                             Memory must hold these bytes, e.g. 0x300:0A0B
+  --expect-pc <addr>         pc must reach this address during the run
+  --save-display <file>      Write the final display as a golden image
+  --screenshot <file>        Write the final display as a .png, .pbm or .pgm image
+  --audio-wav <file>         Write the beeper output to a WAV file
+  --ipf, --quirks, --unknown-opcodes, --seed, --backend, --config,
+  --screenshot-scale, --palette and the beeper options work as above (seed
+  defaults to 0)
+
+Bench options (no window, runs as fast as possible and reports the speed):
+  --frames <n>               Frames to run (default {})
+  --ipf <n>                  Instructions per frame (default {})
+  --quirks, --unknown-opcodes, --seed, --backend and --config work as above (seed
+  defaults to 0)
+
+<Scale> is the size of a pixel in the window (default {})
+<ROM> is a binary .ch8 file or an Octo .8o source",
+        DEFAULT_INSTRUCTIONS_PER_FRAME,
+        Quirks::PRESET_NAMES.join("|"),
+        UnknownOpcodePolicy::NAMES.join("|"),
+        Backend::NAMES.join("|"),
+        DEFAULT_REWIND_SECONDS,
+        MAX_REWIND_SECONDS,
+        ImageFormat::NAMES.join("|"),
+        DEFAULT_GIF_SCALE,
+        MAX_GIF_SCALE,
+        BeeperSettings::default().frequency,
+        BeeperSettings::default().volume * 100.0,
+        Waveform::NAMES.join("|"),
+        Palette::PRESET_NAMES.join("|"),
+        config::default_path().map_or("none".to_string(), |path| path.display().to_string()),
+        DEFAULT_TEST_FRAMES,
+        DEFAULT_BENCH_FRAMES,
+        DEFAULT_BENCH_INSTRUCTIONS_PER_FRAME,
+        DEFAULT_VIDEO_SCALE
+    )
+}
+
+pub fn parse_args(args: &[String]) -> Result<Mode, String> {
+    if args.get(1).map(String::as_str) == Some("disasm") {
+        return match &args[2..] {
+            [rom] => Ok(Mode::Disassemble {
+                rom_filename: rom.clone(),
+            }),
+            _ => Err("Expected disasm <ROM>".to_string()),
+        };
+    }
+
+    if args.get(1).map(String::as_str) == Some("test") {
+        return parse_test_args(&args[2..]).map(Mode::Test);
+    }
+
+    if args.get(1).map(String::as_str) == Some("bench") {
+        return parse_bench_args(&args[2..]).map(Mode::Bench);
+    }
+
+    parse_emulator_args(args).map(Mode::Emulate)
+}
+
+fn parse_test_args(args: &[String]) -> Result<TestOptions, String> {
+    let mut options = TestOptions {
+        rom_filename: String::new(),
+        frames: DEFAULT_TEST_FRAMES,
+        machine: MachineOptions::default(),
+        expect_display: None,
+        save_display: None,
+        screenshot: None,
+        screenshot_scale: 1,
+        beeper: BeeperSettings::default(),
+        audio_wav: None,
+        palette: None,
+        checks: Vec::new(),
+    };
+    let mut positional: Vec<&String> = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let value = iter.next().ok_or("--frames needs a number")?;
+                options.frames = value
+                    .parse()
+                    .map_err(|_| "Frame count must be a number".to_string())?;
+            }
+            _ if parse_machine_arg(arg, &mut iter, &mut options.machine)? => {}
+            "--expect-display" => {
+                let path = iter.next().ok_or("--expect-display needs a file name")?;
+                options.expect_display = Some(path.clone());
+            }
+            "--save-display" => {
+                let path = iter.next().ok_or("--save-display needs a file name")?;
+                options.save_display = Some(path.clone());
+            }
+            "--screenshot" => {
+                let path = iter.next().ok_or("--screenshot needs a file name")?;
+                if ImageFormat::from_path(path).is_none() {
+                    return Err(format!("Screenshots must be .png, .pbm or .pgm: {}", path));
+                }
+                options.screenshot = Some(path.clone());
+            }
+            "--screenshot-scale" => {
+                let value = iter.next().ok_or("--screenshot-scale needs a number")?;
+                options.screenshot_scale = parse_scale(value)?;
+            }
+            "--audio-wav" => {
+                let path = iter.next().ok_or("--audio-wav needs a file name")?;
+                options.audio_wav = Some(path.clone());
+            }
+            "--palette" => {
+                let value = iter.next().ok_or("--palette needs a preset or colours")?;
+                options.palette = Some(parse_palette(value)?);
+            }
+            _ if parse_beeper_arg(arg, &mut iter, &mut options.beeper)? => {}
+            "--expect-memory" => {
+                let value = iter.next().ok_or("--expect-memory needs <addr>:<bytes>")?;
+                options.checks.push(parse_memory_check(value)?);
+            }
+            "--expect-pc" => {
+                let value = iter.next().ok_or("--expect-pc needs an address")?;
+                let address = parse_number(value)?;
+                if address > u16::MAX as u64 {
+                    return Err(format!("Address out of range: {}", value));
+                }
+                options.checks.push(Check::PcReached(address as u16));
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    match positional.as_slice() {
+        [rom] => options.rom_filename = rom.to_string(),
+        _ => return Err("Expected test [options] <ROM>".to_string()),
+    }
+
+    Ok(options)
+}
+
+fn parse_bench_args(args: &[String]) -> Result<BenchOptions, String> {
+    let mut options = BenchOptions {
+        rom_filename: String::new(),
+        frames: DEFAULT_BENCH_FRAMES,
+        machine: MachineOptions::default(),
+    };
+    let mut positional: Vec<&String> = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let value = iter.next().ok_or("--frames needs a number")?;
+                options.frames = value
+                    .parse()
+                    .map_err(|_| "Frame count must be a number".to_string())?;
+            }
+            _ if parse_machine_arg(arg, &mut iter, &mut options.machine)? => {}
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    match positional.as_slice() {
+        [rom] => options.rom_filename = rom.to_string(),
+        _ => return Err("Expected bench [options] <ROM>".to_string()),
+    }
+
+    Ok(options)
+}
+
+// `<addr>:<hex bytes>`, e.g. `0x300:0A0B0C`
+fn parse_memory_check(value: &str) -> Result<Check, String> {
+    let (address, hex) = value
+        .split_once(':')
+        .ok_or_else(|| format!("Expected <addr>:<hex bytes>: {}", value))?;
+
+    if hex.is_empty() || hex.len() % 2 != 0 {
+        return Err(format!("Expected an even number of hex digits: {}", hex));
+    }
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("Not hex bytes: {}", hex))?;
+
+    let address = parse_number(address)? as usize;
+    if address + bytes.len() > MEMORY_SIZE {
+        return Err(format!(
+            "Memory check goes past the end of memory: {}",
+            value
+        ));
+    }
+
+    Ok(Check::Memory { address, bytes })
+}
+
+fn parse_emulator_args(args: &[String]) -> Result<Options, String> {
+    let mut machine = MachineOptions::default();
+    let mut rewind_seconds = DEFAULT_REWIND_SECONDS;
+    let mut record_movie: Option<String> = None;
+    let mut play_movie: Option<String> = None;
+    let mut debug = false;
+    let mut trace: Option<String> = None;
+    let mut trace_filter = TraceFilter::default();
+    let mut screenshot_format = ImageFormat::Png;
+    let mut screenshot_scale = 1;
+    let mut record_gif: Option<String> = None;
+    let mut gif_scale = DEFAULT_GIF_SCALE;
+    let mut beeper = BeeperSettings::default();
+    let mut mute = false;
+    let mut palette: Option<Palette> = None;
+    let mut positional: Vec<&String> = Vec::new();
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            _ if parse_machine_arg(arg, &mut iter, &mut machine)? => {}
+            "--rewind" => {
+                let value = iter.next().ok_or("--rewind needs a number of seconds")?;
+                rewind_seconds = value
+                    .parse()
+                    .ok()
+                    .filter(|seconds| *seconds <= MAX_REWIND_SECONDS)
+                    .ok_or_else(|| {
+                        format!(
+                            "Rewind length must be a number of seconds up to {}",
+                            MAX_REWIND_SECONDS
+                        )
+                    })?;
+            }
+            "--record" => {
+                record_movie = Some(iter.next().ok_or("--record needs a file name")?.clone());
+            }
+            "--play" => {
+                play_movie = Some(iter.next().ok_or("--play needs a file name")?.clone());
+            }
+            "--debug" => debug = true,
+            "--trace" => {
+                trace = Some(iter.next().ok_or("--trace needs a file name")?.clone());
+            }
+            "--trace-addresses" => {
+                let value = iter.next().ok_or("--trace-addresses needs a range")?;
+                let (start, end) = parse_range(value)?;
+                if end > u16::MAX as u64 {
+                    return Err(format!("Address out of range: {}", value));
+                }
+                trace_filter.addresses = Some(start as u16..=end as u16);
+            }
+            "--trace-cycles" => {
+                let value = iter.next().ok_or("--trace-cycles needs a range")?;
+                let (start, end) = parse_range(value)?;
+                trace_filter.cycles = Some(start..=end);
+            }
+            "--screenshot-format" => {
+                let name = iter.next().ok_or("--screenshot-format needs a format")?;
+                screenshot_format = ImageFormat::from_name(name)
+                    .ok_or_else(|| format!("Unknown image format: {}", name))?;
+            }
+            "--screenshot-scale" => {
+                let value = iter.next().ok_or("--screenshot-scale needs a number")?;
+                screenshot_scale = parse_scale(value)?;
+            }
+            "--record-gif" => {
+                record_gif = Some(iter.next().ok_or("--record-gif needs a file name")?.clone());
+            }
+            "--gif-scale" => {
+                let value = iter.next().ok_or("--gif-scale needs a number")?;
+                gif_scale = parse_scale(value)?;
+                if gif_scale > MAX_GIF_SCALE {
+                    return Err(format!("GIF scale must be at most {}", MAX_GIF_SCALE));
+                }
+            }
+            "--mute" => mute = true,
+            "--palette" => {
+                let value = iter.next().ok_or("--palette needs a preset or colours")?;
+                palette = Some(parse_palette(value)?);
+            }
+            _ if parse_beeper_arg(arg, &mut iter, &mut beeper)? => {}
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    // The player restarts the machine from the movie's seed, which the
+    // recording wouldn't know about
+    if record_movie.is_some() && play_movie.is_some() {
+        return Err("--record can't be combined with --play".to_string());
+    }
+
+    if debug && (record_movie.is_some() || play_movie.is_some()) {
+        return Err("--debug can't be combined with --record or --play".to_string());
+    }
+
+    if debug && machine.backend != Backend::Interpreter {
+        return Err("--debug only works with the interpreter backend".to_string());
+    }
+
+    // `<Scale> <Delay> <ROM>` is still accepted: the delay between
+    // instructions (in ms) is turned into an instruction rate
+    let (scale, delay, rom) = match positional.as_slice() {
+        [rom] => (None, None, rom),
+        [scale, rom] => (Some(scale), None, rom),
+        [scale, delay, rom] => (Some(scale), Some(delay), rom),
+        _ => return Err("Expected [<Scale>] <ROM>".to_string()),
+    };
+
+    let video_scale: Option<u32> = scale
+        .map(|scale| scale.parse())
+        .transpose()
+        .map_err(|_| "Scale must be a number".to_string())?;
+
+    if let (None, Some(delay)) = (machine.instructions_per_frame, delay) {
+        let cycle_delay: u32 = delay
+            .parse()
+            .map_err(|_| "Delay must be a number".to_string())?;
+        let frame_ms = 1000 / TIMER_FREQUENCY;
+        machine.instructions_per_frame = Some((frame_ms / cycle_delay.max(1)).max(1));
+    }
+
+    Ok(Options {
+        video_scale,
+        rom_filename: rom.to_string(),
+        machine,
+        rewind_seconds,
+        record_movie,
+        play_movie,
+        debug,
+        trace,
+        trace_filter,
+        screenshot_format,
+        screenshot_scale,
+        record_gif,
+        gif_scale,
+        beeper,
+        mute,
+        palette,
+    })
+}
+
+// A preset name, or custom colours
+pub fn parse_palette(value: &str) -> Result<Palette, String> {
+    match Palette::from_name(value) {
+        Some(palette) => Ok(palette),
+        None if value.contains(',') => Palette::parse(value),
+        None => Err(format!("Unknown palette: {}", value)),
+    }
+}
+
+// The flags every mode that runs a machine takes. Returns false if `arg`
+// isn't one of them.
+fn parse_machine_arg<'a>(
+    arg: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+    machine: &mut MachineOptions,
+) -> Result<bool, String> {
+    match arg {
+        "--ipf" => {
+            let value = iter.next().ok_or("--ipf needs a number")?;
+            machine.instructions_per_frame = Some(
+                value
+                    .parse()
+                    .map_err(|_| "Instructions per frame must be a number".to_string())?,
+            );
+        }
+        "--quirks" => {
+            let name = iter.next().ok_or("--quirks needs a preset name")?;
+            machine.quirks = Some(
+                Quirks::from_name(name)
+                    .ok_or_else(|| format!("Unknown quirks preset: {}", name))?,
+            );
+        }
+        "--unknown-opcodes" => {
+            let name = iter.next().ok_or("--unknown-opcodes needs a policy name")?;
+            machine.unknown_opcode_policy = UnknownOpcodePolicy::from_name(name)
+                .ok_or_else(|| format!("Unknown opcode policy: {}", name))?;
+        }
+        "--seed" => {
+            let value = iter.next().ok_or("--seed needs a number")?;
+            machine.seed = Some(
+                value
+                    .parse()
+                    .map_err(|_| "Seed must be a number".to_string())?,
+            );
+        }
+        "--backend" => {
+            let name = iter.next().ok_or("--backend needs a backend name")?;
+            machine.backend =
+                Backend::from_name(name).ok_or_else(|| format!("Unknown backend: {}", name))?;
+        }
+        "--config" => {
+            machine.config = Some(iter.next().ok_or("--config needs a file name")?.clone());
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+// Beeper flags shared by both modes, returns false for any other argument
+fn parse_beeper_arg<'a>(
+    arg: &str,
+    iter: &mut impl Iterator<Item = &'a String>,
+    beeper: &mut BeeperSettings,
+) -> Result<bool, String> {
+    match arg {
+        "--beep-frequency" => {
+            let value = iter.next().ok_or("--beep-frequency needs a number")?;
+            beeper.frequency = match value.parse() {
+                Ok(frequency) if frequency > 0.0 => frequency,
+                _ => return Err(format!("Frequency must be a positive number: {}", value)),
+            };
+        }
+        "--volume" => {
+            let value = iter.next().ok_or("--volume needs a number")?;
+            let volume: f32 = match value.parse() {
+                Ok(volume) if (0.0..=100.0).contains(&volume) => volume,
+                _ => return Err(format!("Volume must be between 0 and 100: {}", value)),
+            };
+            beeper.volume = volume / 100.0;
+        }
+        "--waveform" => {
+            let name = iter.next().ok_or("--waveform needs a waveform name")?;
+            beeper.waveform =
+                Waveform::from_name(name).ok_or_else(|| format!("Unknown waveform: {}", name))?;
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+fn parse_scale(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(scale) if scale > 0 => Ok(scale),
+        _ => Err(format!("Scale must be a positive number: {}", value)),
+    }
+}
+
+// Decimal, or hexadecimal with a 0x prefix
+fn parse_number(value: &str) -> Result<u64, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    parsed.map_err(|_| format!("Not a number: {}", value))
+}
+
+// `<start>-<end>`, both ends included
+fn parse_range(value: &str) -> Result<(u64, u64), String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("Expected a range like 0x200-0x2FF: {}", value))?;
+    let (start, end) = (parse_number(start)?, parse_number(end)?);
+
+    if start > end {
+        return Err(format!("Empty range: {}", value));
+    }
+
+    Ok((start, end))
+}
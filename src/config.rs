@@ -0,0 +1,343 @@
+//! The config file, `chip-8/config.toml` in the user's config directory.
+//!
+//! Settings at the top apply to every ROM. A `[rom."<name>"]` table, keyed
+//! by a ROM's file name or its SHA-1 in hex, overrides them for that ROM,
+//! and the hash wins over the name. Flags on the command line override both.
+//! The headless modes only read a file given with `--config`.
+//!
+//! ```toml
+//! scale = 12
+//! ipf = 15
+//! palette = "amber"
+//!
+//! # CHIP-8 key = SDL key name
+//! [keys]
+//! 5 = "Up"
+//! 8 = "Down"
+//!
+//! [rom."Pong.ch8"]
+//! quirks = "vip"
+//! palette = "000000,33FF66"
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chip_8::{Palette, Quirks};
+use toml::{Table, Value};
+
+use crate::cli::{MachineOptions, parse_palette};
+
+/// What the config file sets for one ROM. `None` leaves the choice to the
+/// command line or the built-in default.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub video_scale: Option<u32>,
+    pub instructions_per_frame: Option<u32>,
+    pub palette: Option<Palette>,
+    pub quirks: Option<Quirks>,
+    /// SDL key names, indexed by CHIP-8 key
+    pub keys: [Option<String>; 16],
+}
+
+impl Settings {
+    /// The flags given on the command line, to [`merge`](Settings::merge)
+    /// over the settings from the file.
+    pub fn from_flags(
+        machine: &MachineOptions,
+        video_scale: Option<u32>,
+        palette: Option<Palette>,
+    ) -> Settings {
+        Settings {
+            video_scale,
+            instructions_per_frame: machine.instructions_per_frame,
+            palette,
+            quirks: machine.quirks,
+            keys: Default::default(),
+        }
+    }
+
+    /// Takes everything `other` sets.
+    pub fn merge(&mut self, other: &Settings) {
+        self.video_scale = other.video_scale.or(self.video_scale);
+        self.instructions_per_frame = other.instructions_per_frame.or(self.instructions_per_frame);
+        self.palette = other.palette.or(self.palette);
+        self.quirks = other.quirks.or(self.quirks);
+
+        for (key, name) in self.keys.iter_mut().zip(&other.keys) {
+            if name.is_some() {
+                key.clone_from(name);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    global: Settings,
+    // Keyed by file name or SHA-1, as written in the file
+    roms: Vec<(String, Settings)>,
+}
+
+/// Where the config file is read from when `--config` isn't given.
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("chip-8").join("config.toml"))
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+
+        Config::parse(&text).map_err(|e| format!("Config {}: {}", path.display(), e))
+    }
+
+    /// Reads the config at [`default_path`], if there is one.
+    pub fn load_default() -> Result<Config, String> {
+        match default_path() {
+            Some(path) if path.exists() => Config::load(&path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut table: Table = text.parse().map_err(|e| format!("{}", e))?;
+        let mut config = Config::default();
+
+        if let Some(roms) = table.remove("rom") {
+            let Value::Table(roms) = roms else {
+                return Err("rom must be a table of ROM sections".to_string());
+            };
+
+            for (name, section) in roms {
+                let Value::Table(section) = section else {
+                    return Err(format!("rom.\"{}\" must be a table", name));
+                };
+                let settings =
+                    parse_settings(section).map_err(|e| format!("rom.\"{}\": {}", name, e))?;
+                config.roms.push((name, settings));
+            }
+        }
+
+        config.global = parse_settings(table)?;
+
+        Ok(config)
+    }
+
+    /// The settings for the ROM at `rom_filename` with SHA-1 `rom_hash`.
+    pub fn settings_for(&self, rom_filename: &str, rom_hash: &[u8; 20]) -> Settings {
+        let file_name = Path::new(rom_filename)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        let hash: String = rom_hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let mut settings = self.global.clone();
+
+        for (name, section) in &self.roms {
+            if Some(name) == file_name.as_ref() {
+                settings.merge(section);
+            }
+        }
+        for (name, section) in &self.roms {
+            if name.eq_ignore_ascii_case(&hash) {
+                settings.merge(section);
+            }
+        }
+
+        settings
+    }
+}
+
+fn parse_settings(table: Table) -> Result<Settings, String> {
+    let mut settings = Settings::default();
+
+    for (name, value) in table {
+        match name.as_str() {
+            "scale" => {
+                let scale = integer(&name, &value)?;
+                settings.video_scale = Some(
+                    u32::try_from(scale)
+                        .ok()
+                        .filter(|scale| *scale > 0)
+                        .ok_or_else(|| format!("Scale must be a positive number: {}", scale))?,
+                );
+            }
+            "ipf" => {
+                let ipf = integer(&name, &value)?;
+                settings.instructions_per_frame = Some(
+                    u32::try_from(ipf)
+                        .map_err(|_| format!("Instructions per frame out of range: {}", ipf))?,
+                );
+            }
+            "palette" => settings.palette = Some(parse_palette(string(&name, &value)?)?),
+            "quirks" => {
+                let preset = string(&name, &value)?;
+                settings.quirks = Some(
+                    Quirks::from_name(preset)
+                        .ok_or_else(|| format!("Unknown quirks preset: {}", preset))?,
+                );
+            }
+            "keys" => {
+                let Value::Table(keys) = value else {
+                    return Err("keys must be a table of CHIP-8 keys".to_string());
+                };
+
+                for (key, value) in keys {
+                    let index = match u8::from_str_radix(&key, 16) {
+                        Ok(index) if key.len() == 1 => index as usize,
+                        _ => return Err(format!("Not a CHIP-8 key (0-F): {}", key)),
+                    };
+                    settings.keys[index] = Some(string(&key, &value)?.to_string());
+                }
+            }
+            _ => return Err(format!("Unknown setting: {}", name)),
+        }
+    }
+
+    Ok(settings)
+}
+
+fn integer(name: &str, value: &Value) -> Result<i64, String> {
+    value
+        .as_integer()
+        .ok_or_else(|| format!("{} must be a number", name))
+}
+
+fn string<'a>(name: &str, value: &'a Value) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("{} must be a string", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        scale = 12
+        ipf = 15
+        palette = "amber"
+
+        [keys]
+        5 = "Up"
+        a = "Return"
+
+        [rom."Pong.ch8"]
+        quirks = "vip"
+        ipf = 20
+
+        [rom."0123456789abcdef0123456789abcdef01234567"]
+        quirks = "octo"
+    "#;
+
+    const HASH: [u8; 20] = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef, 0x01, 0x23, 0x45, 0x67,
+    ];
+
+    fn parse_error(text: &str) -> String {
+        Config::parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn parses_the_global_settings() {
+        let settings = Config::parse(CONFIG)
+            .unwrap()
+            .settings_for("Tetris.ch8", &[0; 20]);
+
+        assert_eq!(settings.video_scale, Some(12));
+        assert_eq!(settings.instructions_per_frame, Some(15));
+        assert_eq!(settings.palette, Palette::from_name("amber"));
+        assert_eq!(settings.quirks, None);
+        assert_eq!(settings.keys[0x5].as_deref(), Some("Up"));
+        assert_eq!(settings.keys[0xA].as_deref(), Some("Return"));
+        assert_eq!(settings.keys[0x0], None);
+    }
+
+    #[test]
+    fn overrides_by_file_name() {
+        let settings = Config::parse(CONFIG)
+            .unwrap()
+            .settings_for("roms/Pong.ch8", &[0; 20]);
+
+        assert_eq!(settings.quirks, Some(Quirks::COSMAC_VIP));
+        assert_eq!(settings.instructions_per_frame, Some(20));
+        // Kept from the global settings
+        assert_eq!(settings.video_scale, Some(12));
+    }
+
+    #[test]
+    fn overrides_by_hash_over_the_name() {
+        let config = Config::parse(CONFIG).unwrap();
+
+        let settings = config.settings_for("Tetris.ch8", &HASH);
+        assert_eq!(settings.quirks, Some(Quirks::OCTO));
+
+        let settings = config.settings_for("Pong.ch8", &HASH);
+        assert_eq!(settings.quirks, Some(Quirks::OCTO));
+        assert_eq!(settings.instructions_per_frame, Some(20));
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert_eq!(parse_error("speed = 3"), "Unknown setting: speed");
+        assert_eq!(
+            parse_error("[rom.\"Pong.ch8\"]\nspeed = 3"),
+            "rom.\"Pong.ch8\": Unknown setting: speed"
+        );
+        assert_eq!(
+            parse_error("[keys]\n10 = \"Up\""),
+            "Not a CHIP-8 key (0-F): 10"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert_eq!(
+            parse_error("scale = 0"),
+            "Scale must be a positive number: 0"
+        );
+        assert_eq!(parse_error("ipf = \"fast\""), "ipf must be a number");
+        assert_eq!(
+            parse_error("ipf = -1"),
+            "Instructions per frame out of range: -1"
+        );
+        assert_eq!(
+            parse_error("quirks = \"nes\""),
+            "Unknown quirks preset: nes"
+        );
+        assert_eq!(parse_error("palette = \"plaid\""), "Unknown palette: plaid");
+        assert_eq!(
+            parse_error("rom = 1"),
+            "rom must be a table of ROM sections"
+        );
+        assert!(Config::parse("scale = ").is_err());
+    }
+
+    #[test]
+    fn flags_take_precedence() {
+        let machine = MachineOptions {
+            instructions_per_frame: Some(50),
+            ..MachineOptions::default()
+        };
+
+        let mut settings = Config::parse(CONFIG)
+            .unwrap()
+            .settings_for("Pong.ch8", &HASH);
+        settings.merge(&Settings::from_flags(
+            &machine,
+            None,
+            Palette::from_name("lcd"),
+        ));
+
+        assert_eq!(settings.instructions_per_frame, Some(50));
+        assert_eq!(settings.palette, Palette::from_name("lcd"));
+        // Not given on the command line
+        assert_eq!(settings.video_scale, Some(12));
+        assert_eq!(settings.quirks, Some(Quirks::OCTO));
+        assert_eq!(settings.keys[0x5].as_deref(), Some("Up"));
+    }
+}
@@ -1,8 +1,20 @@
 pub const START_ADDRESS: u16 = 0x200;
+pub const TIMER_FREQUENCY: u32 = 60;
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+// XO-CHIP extends the address space to the full 16 bits
+pub const MEMORY_SIZE: usize = 0x10000;
+pub const STACK_SIZE: usize = 16;
 pub const FONTSET_START_ADDRESS: u16 = 0x50;
 pub const FONTSET_SIZE: u8 = 80;
+pub const LARGE_FONTSET_START_ADDRESS: u16 = 0xA0;
+pub const LARGE_FONTSET_SIZE: u8 = 160;
 pub const VIDEO_WIDTH: u8 = 64;
 pub const VIDEO_HEIGHT: u8 = 32;
+pub const HIRES_VIDEO_WIDTH: u8 = 128;
+pub const HIRES_VIDEO_HEIGHT: u8 = 64;
+pub const RPL_FLAGS_SIZE: usize = 16;
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+pub const DEFAULT_PITCH: u8 = 64;
 
 pub const FONTSET: [u8; FONTSET_SIZE as usize] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -23,3 +35,22 @@ pub const FONTSET: [u8; FONTSET_SIZE as usize] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// SUPER-CHIP 10-byte digits, plus the A-F glyphs used by Octo
+pub const LARGE_FONTSET: [u8; LARGE_FONTSET_SIZE as usize] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
@@ -0,0 +1,534 @@
+//! Line based debugger, driven from a terminal so it works over SSH
+//! without a window.
+//!
+//! Numbers are decimal unless prefixed with `0x`.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::chip8::Chip8;
+use crate::constants::MEMORY_SIZE;
+use crate::error::Chip8Error;
+use crate::instruction::Instruction;
+
+const DEFAULT_DUMP_LENGTH: usize = 0x40;
+
+const HELP: &str = "Commands:
+  s, step [n]             Execute n instructions (default 1)
+  n, next                 Step over a 2nnn call
+  o, out                  Run until the current subroutine returns
+  c, continue             Run until a breakpoint, a halt or an error
+  b, break <addr>         Set a breakpoint
+  d, delete <addr>        Remove a breakpoint
+  bl, breakpoints         List breakpoints
+  r, regs                 Show registers, stack and timers
+  x, mem <addr> [len]     Dump memory
+  set <reg> <value>       Set v0-vf, i, pc, sp, dt or st
+  w, write <addr> <b>...  Write bytes to memory
+  key <k> <0|1>           Release or press a keypad key
+  h, help                 Show this help
+  q, quit                 Exit the debugger
+An empty line repeats the last command. Ctrl-C stops a running command.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    DelayTimer,
+    SoundTimer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugCommand {
+    Step(u32),
+    Next,
+    Out,
+    Continue,
+    Break(u16),
+    Delete(u16),
+    Breakpoints,
+    Registers,
+    Memory { address: usize, len: usize },
+    Set { register: Register, value: u32 },
+    Write { address: usize, bytes: Vec<u8> },
+    Key { key: usize, pressed: bool },
+    Help,
+    Quit,
+}
+
+fn parse_number(word: &str) -> Result<u32, String> {
+    let parsed = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+
+    parsed.map_err(|_| format!("Not a number: {}", word))
+}
+
+fn parse_address(word: &str) -> Result<usize, String> {
+    let address = parse_number(word)? as usize;
+
+    if address >= MEMORY_SIZE {
+        return Err(format!("Address out of memory: {}", word));
+    }
+
+    Ok(address)
+}
+
+fn parse_register(word: &str) -> Result<Register, String> {
+    let name = word.to_ascii_lowercase();
+
+    let register = match name.as_str() {
+        "i" => Register::I,
+        "pc" => Register::Pc,
+        "sp" => Register::Sp,
+        "dt" => Register::DelayTimer,
+        "st" => Register::SoundTimer,
+        _ => name
+            .strip_prefix('v')
+            .filter(|x| x.len() == 1)
+            .and_then(|x| usize::from_str_radix(x, 16).ok())
+            .map(Register::V)
+            .ok_or_else(|| format!("Unknown register: {}", word))?,
+    };
+
+    Ok(register)
+}
+
+impl DebugCommand {
+    pub fn parse(line: &str) -> Result<DebugCommand, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((name, args)) = words.split_first() else {
+            return Err("Empty command".to_string());
+        };
+
+        let arg = |n: usize, what: &str| {
+            args.get(n)
+                .copied()
+                .ok_or_else(|| format!("{} needs {}", name, what))
+        };
+
+        let command = match *name {
+            "s" | "step" => match args.first() {
+                Some(count) => match parse_number(count)? {
+                    0 => return Err(format!("{} needs a count of at least 1", name)),
+                    count => DebugCommand::Step(count),
+                },
+                None => DebugCommand::Step(1),
+            },
+            "n" | "next" => DebugCommand::Next,
+            "o" | "out" => DebugCommand::Out,
+            "c" | "continue" => DebugCommand::Continue,
+            "b" | "break" => DebugCommand::Break(parse_address(arg(0, "an address")?)? as u16),
+            "d" | "delete" => DebugCommand::Delete(parse_address(arg(0, "an address")?)? as u16),
+            "bl" | "breakpoints" => DebugCommand::Breakpoints,
+            "r" | "regs" => DebugCommand::Registers,
+            "x" | "mem" => DebugCommand::Memory {
+                address: parse_address(arg(0, "an address")?)?,
+                len: match args.get(1) {
+                    Some(len) => parse_number(len)? as usize,
+                    None => DEFAULT_DUMP_LENGTH,
+                },
+            },
+            "set" => DebugCommand::Set {
+                register: parse_register(arg(0, "a register")?)?,
+                value: parse_number(arg(1, "a value")?)?,
+            },
+            "w" | "write" => {
+                let address = parse_address(arg(0, "an address")?)?;
+                let bytes = args[1..]
+                    .iter()
+                    .map(|word| {
+                        u8::try_from(parse_number(word)?)
+                            .map_err(|_| format!("Not a byte: {}", word))
+                    })
+                    .collect::<Result<Vec<u8>, String>>()?;
+
+                if bytes.is_empty() {
+                    return Err(format!("{} needs at least one byte", name));
+                }
+                if address + bytes.len() > MEMORY_SIZE {
+                    return Err("Write goes past the end of memory".to_string());
+                }
+
+                DebugCommand::Write { address, bytes }
+            }
+            "key" => {
+                let key = parse_number(arg(0, "a key")?)? as usize;
+                if key > 0xF {
+                    return Err(format!("No such key: {}", key));
+                }
+
+                DebugCommand::Key {
+                    key,
+                    pressed: parse_number(arg(1, "a state")?)? != 0,
+                }
+            }
+            "h" | "help" => DebugCommand::Help,
+            "q" | "quit" => DebugCommand::Quit,
+            _ => return Err(format!("Unknown command: {} (try help)", name)),
+        };
+
+        Ok(command)
+    }
+}
+
+// Why a run of instructions came to an end
+enum Stop {
+    Done,
+    Breakpoint,
+    Halted,
+    Interrupted,
+    Error(Chip8Error),
+}
+
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    pub instructions_per_frame: u32,
+    /// Set from another thread or a signal handler to stop the command
+    /// that is running, e.g. a `continue` stuck in a loop.
+    pub interrupt: Arc<AtomicBool>,
+    // Instructions executed since the timers last ticked
+    frame_cycles: u32,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(instructions_per_frame: u32) -> Self {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            instructions_per_frame: instructions_per_frame.max(1),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            frame_cycles: 0,
+            last_command: String::new(),
+        }
+    }
+
+    /// Reads commands from `input` until `quit` or end of input.
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        chip8: &mut Chip8,
+        mut input: R,
+        output: &mut W,
+    ) -> io::Result<()> {
+        self.print_location(chip8, output)?;
+
+        loop {
+            write!(output, "(chip8) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+            self.last_command = line.clone();
+
+            match DebugCommand::parse(&line) {
+                Ok(DebugCommand::Quit) => return Ok(()),
+                Ok(command) => self.execute(chip8, command, output)?,
+                Err(e) => writeln!(output, "{}", e)?,
+            }
+        }
+    }
+
+    pub fn execute<W: Write>(
+        &mut self,
+        chip8: &mut Chip8,
+        command: DebugCommand,
+        output: &mut W,
+    ) -> io::Result<()> {
+        match command {
+            DebugCommand::Step(count) => {
+                let mut remaining = count;
+                let stop = self.run_until(chip8, |_| {
+                    remaining = remaining.saturating_sub(1);
+                    remaining == 0
+                });
+                self.report(chip8, stop, output)?;
+            }
+            DebugCommand::Next => {
+                let opcode = read_opcode(chip8);
+
+                let stop = if let Instruction::Call(_) = Instruction::decode(opcode) {
+                    let return_address = chip8.pc.wrapping_add(2);
+                    let sp = chip8.sp;
+                    self.run_until(chip8, |chip8| chip8.pc == return_address && chip8.sp == sp)
+                } else {
+                    self.run_until(chip8, |_| true)
+                };
+                self.report(chip8, stop, output)?;
+            }
+            DebugCommand::Out => {
+                if chip8.sp == 0 {
+                    writeln!(output, "Not inside a subroutine")?;
+                } else {
+                    let sp = chip8.sp;
+                    let stop = self.run_until(chip8, |chip8| chip8.sp < sp);
+                    self.report(chip8, stop, output)?;
+                }
+            }
+            DebugCommand::Continue => {
+                let stop = self.run_until(chip8, |_| false);
+                self.report(chip8, stop, output)?;
+            }
+            DebugCommand::Break(address) => {
+                self.breakpoints.insert(address);
+                writeln!(output, "Breakpoint at 0x{:04X}", address)?;
+            }
+            DebugCommand::Delete(address) => {
+                if !self.breakpoints.remove(&address) {
+                    writeln!(output, "No breakpoint at 0x{:04X}", address)?;
+                }
+            }
+            DebugCommand::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    writeln!(output, "No breakpoints")?;
+                }
+                for address in &self.breakpoints {
+                    writeln!(output, "0x{:04X}", address)?;
+                }
+            }
+            DebugCommand::Registers => print_registers(chip8, output)?,
+            DebugCommand::Memory { address, len } => {
+                let end = (address + len).min(MEMORY_SIZE);
+                print_memory(chip8, address, end, output)?;
+            }
+            DebugCommand::Set { register, value } => {
+                if let Err(e) = set_register(chip8, register, value) {
+                    writeln!(output, "{}", e)?;
+                }
+            }
+            DebugCommand::Write { address, bytes } => {
+                chip8.memory[address..address + bytes.len()].copy_from_slice(&bytes);
+                chip8.invalidate_code(address..address + bytes.len());
+            }
+            DebugCommand::Key { key, pressed } => {
+                chip8.keypad[key] = pressed as u8;
+            }
+            DebugCommand::Help => writeln!(output, "{}", HELP)?,
+            DebugCommand::Quit => {}
+        }
+
+        Ok(())
+    }
+
+    // Executes one instruction with the same vblank and timer cadence as
+    // `Chip8::run_frame`
+    fn step(&mut self, chip8: &mut Chip8) -> Result<(), Chip8Error> {
+        if self.frame_cycles == 0 {
+            chip8.signal_vblank();
+        }
+
+        chip8.step()?;
+
+        self.frame_cycles += 1;
+        if self.frame_cycles >= self.instructions_per_frame {
+            chip8.tick_timers();
+            self.frame_cycles = 0;
+        }
+
+        Ok(())
+    }
+
+    // Steps until `done` returns true after an instruction, a breakpoint is
+    // reached, the program exits, an instruction fails or `interrupt` is
+    // set. The instruction at `pc` is always executed, so a breakpoint
+    // there doesn't stop it.
+    fn run_until<F: FnMut(&Chip8) -> bool>(&mut self, chip8: &mut Chip8, mut done: F) -> Stop {
+        // Forget a Ctrl-C pressed at the prompt
+        self.interrupt.store(false, Ordering::Relaxed);
+
+        loop {
+            if chip8.halted {
+                return Stop::Halted;
+            }
+
+            if self.interrupt.swap(false, Ordering::Relaxed) {
+                return Stop::Interrupted;
+            }
+
+            if let Err(e) = self.step(chip8) {
+                return Stop::Error(e);
+            }
+
+            if done(chip8) {
+                return Stop::Done;
+            }
+
+            if self.breakpoints.contains(&chip8.pc) {
+                return Stop::Breakpoint;
+            }
+        }
+    }
+
+    fn report<W: Write>(&self, chip8: &Chip8, stop: Stop, output: &mut W) -> io::Result<()> {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint => writeln!(output, "Breakpoint reached")?,
+            Stop::Halted => writeln!(output, "Program exited")?,
+            Stop::Interrupted => writeln!(output, "Interrupted")?,
+            Stop::Error(e) => writeln!(output, "Stopped: {}", e)?,
+        }
+
+        self.print_location(chip8, output)
+    }
+
+    fn print_location<W: Write>(&self, chip8: &Chip8, output: &mut W) -> io::Result<()> {
+        let opcode = read_opcode(chip8);
+
+        writeln!(
+            output,
+            "0x{:04X}: {:04X}  {}",
+            chip8.pc,
+            opcode,
+            Instruction::decode(opcode)
+        )
+    }
+}
+
+// The opcode at `pc`, or 0 if it runs past the end of memory
+fn read_opcode(chip8: &Chip8) -> u16 {
+    let pc = chip8.pc as usize;
+
+    match chip8.memory.get(pc..pc + 2) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    }
+}
+
+fn print_registers<W: Write>(chip8: &Chip8, output: &mut W) -> io::Result<()> {
+    for (row, values) in chip8.registers.chunks(8).enumerate() {
+        let line: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(column, value)| format!("V{:X} {:02X}", row * 8 + column, value))
+            .collect();
+        writeln!(output, "{}", line.join("  "))?;
+    }
+
+    writeln!(
+        output,
+        "I  0x{:04X}  PC 0x{:04X}  SP {}  DT {}  ST {}",
+        chip8.index, chip8.pc, chip8.sp, chip8.delay_timer, chip8.sound_timer
+    )?;
+
+    write!(output, "Stack:")?;
+    for address in &chip8.stack[..(chip8.sp as usize).min(chip8.stack.len())] {
+        write!(output, " 0x{:04X}", address)?;
+    }
+    writeln!(output)
+}
+
+fn print_memory<W: Write>(
+    chip8: &Chip8,
+    start: usize,
+    end: usize,
+    output: &mut W,
+) -> io::Result<()> {
+    for line_start in (start..end).step_by(16) {
+        let line_end = (line_start + 16).min(end);
+
+        write!(output, "0x{:04X}:", line_start)?;
+        for byte in &chip8.memory[line_start..line_end] {
+            write!(output, " {:02X}", byte)?;
+        }
+        writeln!(output)?;
+    }
+
+    Ok(())
+}
+
+fn set_register(chip8: &mut Chip8, register: Register, value: u32) -> Result<(), String> {
+    let too_large = || format!("Value too large: {}", value);
+
+    match register {
+        Register::V(x) => chip8.registers[x] = u8::try_from(value).map_err(|_| too_large())?,
+        Register::I => chip8.index = value,
+        Register::Pc => {
+            if value as usize >= MEMORY_SIZE {
+                return Err(too_large());
+            }
+            chip8.pc = value as u16;
+        }
+        Register::Sp => {
+            if value as usize > chip8.stack.len() {
+                return Err(too_large());
+            }
+            chip8.sp = value as u16;
+        }
+        Register::DelayTimer => chip8.delay_timer = u8::try_from(value).map_err(|_| too_large())?,
+        Register::SoundTimer => chip8.sound_timer = u8::try_from(value).map_err(|_| too_large())?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    // A program that jumps to itself forever
+    fn looping_machine() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&[0x12, 0x00]).unwrap();
+        chip8
+    }
+
+    fn run_command(debugger: &mut Debugger, chip8: &mut Chip8, line: &str) -> String {
+        let mut output = Vec::new();
+        let command = DebugCommand::parse(line).unwrap();
+        debugger.execute(chip8, command, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn rejects_step_zero() {
+        assert!(DebugCommand::parse("step 0").is_err());
+        assert_eq!(DebugCommand::parse("step 3"), Ok(DebugCommand::Step(3)));
+    }
+
+    #[test]
+    fn interrupt_stops_continue() {
+        let mut chip8 = looping_machine();
+        let mut debugger = Debugger::new(10);
+
+        let interrupt = debugger.interrupt.clone();
+        let interrupter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+
+        let output = run_command(&mut debugger, &mut chip8, "continue");
+        interrupter.join().unwrap();
+
+        assert!(output.starts_with("Interrupted"));
+        assert_eq!(chip8.pc, 0x200);
+    }
+
+    #[test]
+    fn interrupt_at_the_prompt_is_forgotten() {
+        let mut chip8 = looping_machine();
+        let mut debugger = Debugger::new(10);
+        debugger.interrupt.store(true, Ordering::Relaxed);
+
+        let output = run_command(&mut debugger, &mut chip8, "step");
+
+        assert!(!output.contains("Interrupted"));
+    }
+}
@@ -0,0 +1,338 @@
+//! Recursive descent disassembler.
+//!
+//! Starting from `START_ADDRESS`, control flow is followed through jumps,
+//! calls, skips and the jump tables `Bnnn` points at. Every byte reached
+//! that way is code, everything else is listed as data with a sprite
+//! preview.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::constants::START_ADDRESS;
+use crate::instruction::Instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LabelKind {
+    /// Target of a `2nnn`
+    Subroutine,
+    /// Target of a `1nnn` or `Bnnn`
+    Jump,
+    /// Target of an `Annn` or `F000 nnnn`
+    Data,
+}
+
+pub struct Disassembly {
+    pub program: Vec<u8>,
+    /// Addresses where a reachable instruction starts
+    pub instructions: BTreeSet<u16>,
+    pub labels: BTreeMap<u16, LabelKind>,
+}
+
+impl Disassembly {
+    /// Disassembles a program loaded at `START_ADDRESS`.
+    pub fn new(program: &[u8]) -> Self {
+        let mut disassembly = Disassembly {
+            program: program.to_vec(),
+            instructions: BTreeSet::new(),
+            labels: BTreeMap::new(),
+        };
+
+        disassembly.trace(START_ADDRESS);
+        disassembly
+    }
+
+    fn end(&self) -> usize {
+        START_ADDRESS as usize + self.program.len()
+    }
+
+    fn word(&self, address: usize) -> Option<u16> {
+        let offset = address.checked_sub(START_ADDRESS as usize)?;
+        let bytes = self.program.get(offset..offset + 2)?;
+
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // Reads the instruction at `address`, along with the address operand
+    // of `F000 nnnn`
+    fn instruction(&self, address: usize) -> Option<(Instruction, Option<u16>)> {
+        let instruction = Instruction::decode(self.word(address)?);
+
+        match instruction {
+            Instruction::LdILong => Some((instruction, Some(self.word(address + 2)?))),
+            _ => Some((instruction, None)),
+        }
+    }
+
+    fn add_label(&mut self, address: u16, kind: LabelKind) {
+        let label = self.labels.entry(address).or_insert(kind);
+        // A subroutine that is also jumped to is still a subroutine
+        *label = (*label).min(kind);
+    }
+
+    fn trace(&mut self, start: u16) {
+        let mut pending = vec![start as usize];
+
+        while let Some(mut address) = pending.pop() {
+            while let Some((instruction, long_address)) = self.instruction(address) {
+                // Flow running into an unknown opcode has most likely left the
+                // code, e.g. after a skip over data
+                if let Instruction::Unknown(_) = instruction {
+                    break;
+                }
+
+                if !self.instructions.insert(address as u16) {
+                    break;
+                }
+
+                let next = address + instruction.size() as usize;
+
+                match instruction {
+                    Instruction::Jp(target) => {
+                        self.add_label(target, LabelKind::Jump);
+                        pending.push(target as usize);
+                        break;
+                    }
+                    Instruction::JpV0(target) => {
+                        // Usually a jump table: follow the run of jumps
+                        // starting at the target
+                        self.add_label(target, LabelKind::Jump);
+                        pending.push(target as usize);
+
+                        let mut entry = target as usize + 2;
+                        while let Some((Instruction::Jp(_), _)) = self.instruction(entry) {
+                            pending.push(entry);
+                            entry += 2;
+                        }
+                        break;
+                    }
+                    Instruction::Call(target) => {
+                        self.add_label(target, LabelKind::Subroutine);
+                        pending.push(target as usize);
+                    }
+                    Instruction::Ret | Instruction::Exit => break,
+                    Instruction::SeByte { .. }
+                    | Instruction::SneByte { .. }
+                    | Instruction::SeReg { .. }
+                    | Instruction::SneReg { .. }
+                    | Instruction::Skp(_)
+                    | Instruction::Sknp(_) => {
+                        // The skipped instruction is four bytes long if it is F000
+                        let skipped = match self.instruction(next) {
+                            Some((skipped, _)) => skipped.size() as usize,
+                            None => 2,
+                        };
+                        pending.push(next + skipped);
+                    }
+                    Instruction::LdIAddr(target) => self.add_label(target, LabelKind::Data),
+                    Instruction::LdILong => {
+                        if let Some(target) = long_address {
+                            self.add_label(target, LabelKind::Data);
+                        }
+                    }
+                    _ => {}
+                }
+
+                address = next;
+            }
+        }
+    }
+
+    // Labels outside the program (e.g. the fontset) are never printed
+    fn label_name(&self, address: u16) -> Option<String> {
+        if !(START_ADDRESS as usize..self.end()).contains(&(address as usize)) {
+            return None;
+        }
+
+        let prefix = match self.labels.get(&address)? {
+            LabelKind::Subroutine => "sub",
+            LabelKind::Jump => "loc",
+            LabelKind::Data => "data",
+        };
+
+        Some(format!("{}_{:04X}", prefix, address))
+    }
+
+    // The label for an address operand, or the address itself
+    fn operand(&self, address: u16) -> String {
+        self.label_name(address)
+            .unwrap_or_else(|| format!("0x{:03X}", address))
+    }
+
+    fn format_instruction(&self, instruction: Instruction, long_address: Option<u16>) -> String {
+        match instruction {
+            Instruction::Jp(target) => format!("JP {}", self.operand(target)),
+            Instruction::JpV0(target) => format!("JP V0, {}", self.operand(target)),
+            Instruction::Call(target) => format!("CALL {}", self.operand(target)),
+            Instruction::LdIAddr(target) => format!("LD I, {}", self.operand(target)),
+            Instruction::LdILong => match long_address {
+                Some(target) => format!("LD I, LONG {}", self.operand(target)),
+                None => instruction.to_string(),
+            },
+            _ => instruction.to_string(),
+        }
+    }
+}
+
+fn sprite_row(byte: u8) -> String {
+    (0..8)
+        .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+        .collect()
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut address = START_ADDRESS as usize;
+
+        while address < self.end() {
+            if let Some(label) = self.label_name(address as u16) {
+                writeln!(f, "{}:", label)?;
+            }
+
+            match self.instruction(address) {
+                Some((instruction, long_address))
+                    if self.instructions.contains(&(address as u16)) =>
+                {
+                    let size = instruction.size() as usize;
+                    let bytes = &self.program[address - START_ADDRESS as usize..][..size];
+                    let hex: Vec<String> =
+                        bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+
+                    writeln!(
+                        f,
+                        "0x{:04X}  {:<11} {}",
+                        address,
+                        hex.join(""),
+                        self.format_instruction(instruction, long_address)
+                    )?;
+                    address += size;
+                }
+                _ => {
+                    let byte = self.program[address - START_ADDRESS as usize];
+                    writeln!(
+                        f,
+                        "0x{:04X}  {:02X}          {}",
+                        address,
+                        byte,
+                        sprite_row(byte)
+                    )?;
+                    address += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instructions(program: &[u8]) -> Vec<u16> {
+        Disassembly::new(program).instructions.into_iter().collect()
+    }
+
+    #[test]
+    fn follows_every_entry_of_a_jump_table() {
+        let program = [
+            0x60, 0x02, // LD V0, 2
+            0xB2, 0x06, // JP V0, 0x206
+            0xAB, 0xCD, // data
+            0x12, 0x0C, // JP 0x20C
+            0x12, 0x0E, // JP 0x20E
+            0x12, 0x10, // JP 0x210
+            0x00, 0xFD, // EXIT
+            0x00, 0xFD, // EXIT
+            0x00, 0xFD, // EXIT
+        ];
+
+        let disassembly = Disassembly::new(&program);
+
+        assert_eq!(
+            disassembly.instructions.iter().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x206, 0x208, 0x20A, 0x20C, 0x20E, 0x210]
+        );
+        for target in [0x206, 0x20C, 0x20E, 0x210] {
+            assert_eq!(disassembly.labels.get(&target), Some(&LabelKind::Jump));
+        }
+    }
+
+    #[test]
+    fn lists_bytes_after_a_jump_as_data() {
+        let program = [
+            0x12, 0x04, // JP 0x204
+            0xF0, 0x90, // data
+            0x00, 0xFD, // EXIT
+        ];
+
+        assert_eq!(instructions(&program), [0x200, 0x204]);
+        assert_eq!(
+            Disassembly::new(&program).to_string(),
+            "0x0200  1204        JP loc_0204\n\
+             0x0202  F0          ####....\n\
+             0x0203  90          #..#....\n\
+             loc_0204:\n\
+             0x0204  00FD        EXIT\n"
+        );
+    }
+
+    #[test]
+    fn follows_both_paths_of_a_skip() {
+        let program = [
+            0x30, 0x01, // SE V0, 1
+            0x12, 0x06, // JP 0x206
+            0x00, 0xFD, // EXIT
+            0x00, 0xEE, // RET
+        ];
+
+        assert_eq!(instructions(&program), [0x200, 0x202, 0x204, 0x206]);
+    }
+
+    #[test]
+    fn skips_over_a_long_index_load() {
+        let program = [
+            0x30, 0x01, // SE V0, 1
+            0xF0, 0x00, 0x02, 0x08, // LD I, LONG 0x208
+            0x00, 0xFD, // EXIT
+            0x12, 0x34, // data
+        ];
+
+        let disassembly = Disassembly::new(&program);
+
+        assert_eq!(
+            disassembly.instructions.iter().copied().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x206]
+        );
+        assert_eq!(disassembly.labels.get(&0x208), Some(&LabelKind::Data));
+    }
+
+    #[test]
+    fn names_labels_by_kind() {
+        let program = [
+            0x22, 0x0A, // CALL 0x20A
+            0xA2, 0x0E, // LD I, 0x20E
+            0xA0, 0x50, // LD I, 0x050 (the font, outside the program)
+            0x12, 0x0A, // JP 0x20A
+            0x00, 0xFD, // unreachable
+            0x00, 0xEE, // RET
+            0x00, 0x00, // padding
+            0xFF, // data
+        ];
+
+        assert_eq!(
+            Disassembly::new(&program).to_string(),
+            "0x0200  220A        CALL sub_020A\n\
+             0x0202  A20E        LD I, data_020E\n\
+             0x0204  A050        LD I, 0x050\n\
+             0x0206  120A        JP sub_020A\n\
+             0x0208  00          ........\n\
+             0x0209  FD          ######.#\n\
+             sub_020A:\n\
+             0x020A  00EE        RET\n\
+             0x020C  00          ........\n\
+             0x020D  00          ........\n\
+             data_020E:\n\
+             0x020E  FF          ########\n"
+        );
+    }
+}
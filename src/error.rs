@@ -0,0 +1,89 @@
+use std::fmt;
+use std::io;
+
+use crate::assembler::AssembleError;
+
+/// Everything that can stop the machine. Addresses are the location of the
+/// instruction that failed, not the already advanced `pc`.
+#[derive(Debug)]
+pub enum Chip8Error {
+    /// `2nnn` was executed with all 16 stack slots in use.
+    StackOverflow { pc: u16 },
+    /// `00EE` was executed with an empty stack.
+    StackUnderflow { pc: u16 },
+    /// An instruction (or the fetch itself) touched memory past the end of
+    /// the address space.
+    MemoryOutOfBounds { pc: u16, address: usize },
+    /// The ROM doesn't fit between `START_ADDRESS` and the end of memory.
+    RomTooLarge { size: usize, max: usize },
+    /// The opcode doesn't map to any known instruction.
+    IllegalOpcode { pc: u16, opcode: u16 },
+    /// In lockstep mode, the block backend and the interpreter ended up in
+    /// a different state after running the block starting at `pc`.
+    BackendMismatch { pc: u16, what: &'static str },
+    /// The ROM file couldn't be read.
+    Io(io::Error),
+    /// The `.8o` source couldn't be assembled.
+    Assemble(AssembleError),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip8Error::StackOverflow { pc } => {
+                write!(f, "stack overflow at {:#05X}", pc)
+            }
+            Chip8Error::StackUnderflow { pc } => {
+                write!(f, "stack underflow (return without call) at {:#05X}", pc)
+            }
+            Chip8Error::MemoryOutOfBounds { pc, address } => {
+                write!(
+                    f,
+                    "memory access out of bounds ({:#X}) at {:#05X}",
+                    address, pc
+                )
+            }
+            Chip8Error::RomTooLarge { size, max } => {
+                write!(
+                    f,
+                    "ROM is {} bytes, but at most {} bytes fit in memory",
+                    size, max
+                )
+            }
+            Chip8Error::IllegalOpcode { pc, opcode } => {
+                write!(f, "illegal opcode {:04X} at {:#05X}", opcode, pc)
+            }
+            Chip8Error::BackendMismatch { pc, what } => {
+                write!(
+                    f,
+                    "block backend and interpreter disagree on {} after the block at {:#05X}",
+                    what, pc
+                )
+            }
+            Chip8Error::Io(e) => write!(f, "{}", e),
+            Chip8Error::Assemble(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Chip8Error::Io(e) => Some(e),
+            Chip8Error::Assemble(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Chip8Error {
+    fn from(e: io::Error) -> Self {
+        Chip8Error::Io(e)
+    }
+}
+
+impl From<AssembleError> for Chip8Error {
+    fn from(e: AssembleError) -> Self {
+        Chip8Error::Assemble(e)
+    }
+}
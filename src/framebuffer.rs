@@ -0,0 +1,220 @@
+//! Bit-packed display memory.
+//!
+//! Each bitplane stores a row as one `u128`, with bit 127 the leftmost
+//! pixel, so drawing a sprite row is a shift and an XOR. Low resolution
+//! uses the top-left 64x32 pixels.
+//!
+//! Every change records the rows it touched, so frontends only upload the
+//! display when it actually changed.
+
+use std::ops::Range;
+
+use crate::constants::{HIRES_VIDEO_HEIGHT, HIRES_VIDEO_WIDTH};
+
+const ROWS: usize = HIRES_VIDEO_HEIGHT as usize;
+
+/// Size of the one byte per pixel image used by save states.
+pub const DISPLAY_BYTES: usize = HIRES_VIDEO_WIDTH as usize * HIRES_VIDEO_HEIGHT as usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameBuffer {
+    planes: [[u128; ROWS]; 2],
+    // First and last row changed since the last `take_dirty`
+    dirty: Option<(usize, usize)>,
+}
+
+// Bits of a row that are on screen at `width` pixels
+fn row_mask(width: usize) -> u128 {
+    !0u128 << (HIRES_VIDEO_WIDTH as usize - width)
+}
+
+impl FrameBuffer {
+    /// A blank display, dirty so the first frame gets drawn.
+    pub fn new() -> Self {
+        FrameBuffer {
+            planes: [[0; ROWS]; 2],
+            dirty: Some((0, ROWS - 1)),
+        }
+    }
+
+    /// The bitplanes set at (x, y), 0 to 3.
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let bit = 127 - x;
+        ((self.planes[0][y] >> bit) & 1) as u8 | (((self.planes[1][y] >> bit) & 1) as u8) << 1
+    }
+
+    /// XORs `bits` into row `y` of `plane` (0 or 1). Returns whether a lit
+    /// pixel was turned off.
+    pub fn draw_row(&mut self, plane: usize, y: usize, bits: u128) -> bool {
+        if bits == 0 {
+            return false;
+        }
+
+        let row = &mut self.planes[plane][y];
+        let collision = *row & bits != 0;
+        *row ^= bits;
+        self.mark_dirty(y, y);
+
+        collision
+    }
+
+    /// Clears the bitplanes in `planes` (bit 0 for plane 0, bit 1 for plane 1).
+    pub fn clear(&mut self, planes: u8) {
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) != 0 {
+                rows.fill(0);
+            }
+        }
+        self.mark_dirty(0, ROWS - 1);
+    }
+
+    /// Moves the bitplanes in `planes` by (dx, dy) pixels within a
+    /// `width` x `height` display, filling with 0.
+    pub fn scroll(&mut self, dx: isize, dy: isize, width: usize, height: usize, planes: u8) {
+        let mask = row_mask(width);
+
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) == 0 {
+                continue;
+            }
+
+            let source = *rows;
+            for (y, row) in rows.iter_mut().enumerate().take(height) {
+                let src_y = y as isize - dy;
+                let moved = if (0..height as isize).contains(&src_y) {
+                    source[src_y as usize]
+                } else {
+                    0
+                };
+
+                *row = match dx {
+                    0 => moved,
+                    dx if dx > 0 => moved >> dx,
+                    dx => moved << -dx,
+                } & mask;
+            }
+        }
+        self.mark_dirty(0, height - 1);
+    }
+
+    /// The `width` x `height` top-left corner, one byte per pixel, row by row.
+    pub fn pixels(&self, width: usize, height: usize) -> Vec<u8> {
+        self.row_pixels(width, 0..height)
+    }
+
+    /// The first `width` pixels of `rows`, one byte per pixel.
+    pub fn row_pixels(&self, width: usize, rows: Range<usize>) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(width * rows.len());
+
+        for y in rows {
+            pixels.extend((0..width).map(|x| self.pixel(x, y)));
+        }
+
+        pixels
+    }
+
+    /// The save state image: [`FrameBuffer::pixels`] padded with zeros.
+    pub fn to_bytes(&self, width: usize, height: usize) -> [u8; DISPLAY_BYTES] {
+        let mut bytes = [0; DISPLAY_BYTES];
+        let pixels = self.pixels(width, height);
+        bytes[..pixels.len()].copy_from_slice(&pixels);
+
+        bytes
+    }
+
+    /// Reverse of [`FrameBuffer::to_bytes`], the whole display becomes dirty.
+    pub fn from_bytes(bytes: &[u8; DISPLAY_BYTES], width: usize, height: usize) -> Self {
+        let mut framebuffer = FrameBuffer::new();
+
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = bytes[y * width + x];
+                for plane in 0..2 {
+                    if pixel & (1 << plane) != 0 {
+                        framebuffer.planes[plane][y] |= 1 << (127 - x);
+                    }
+                }
+            }
+        }
+
+        framebuffer
+    }
+
+    fn mark_dirty(&mut self, first: usize, last: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((dirty_first, dirty_last)) => (dirty_first.min(first), dirty_last.max(last)),
+            None => (first, last),
+        });
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.is_some()
+    }
+
+    /// The rows changed since the last call, if any.
+    pub fn take_dirty(&mut self) -> Option<Range<usize>> {
+        self.dirty.take().map(|(first, last)| first..last + 1)
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        FrameBuffer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A display with nothing left to upload
+    fn clean() -> FrameBuffer {
+        let mut framebuffer = FrameBuffer::new();
+        framebuffer.take_dirty();
+        framebuffer
+    }
+
+    #[test]
+    fn starts_dirty_and_taking_resets() {
+        let mut framebuffer = FrameBuffer::new();
+
+        assert_eq!(framebuffer.take_dirty(), Some(0..ROWS));
+        assert!(!framebuffer.is_dirty());
+        assert_eq!(framebuffer.take_dirty(), None);
+    }
+
+    #[test]
+    fn drawing_marks_the_rows_drawn_to() {
+        let mut framebuffer = clean();
+
+        framebuffer.draw_row(0, 5, 1 << 127);
+        framebuffer.draw_row(1, 9, 1 << 100);
+        assert_eq!(framebuffer.take_dirty(), Some(5..10));
+
+        // Blank sprite rows change nothing
+        framebuffer.draw_row(0, 20, 0);
+        assert_eq!(framebuffer.take_dirty(), None);
+
+        framebuffer.draw_row(0, 63, 1);
+        assert_eq!(framebuffer.take_dirty(), Some(63..64));
+    }
+
+    #[test]
+    fn clearing_marks_every_row() {
+        let mut framebuffer = clean();
+
+        framebuffer.clear(0x2);
+        assert_eq!(framebuffer.take_dirty(), Some(0..ROWS));
+    }
+
+    #[test]
+    fn scrolling_marks_the_rows_on_screen() {
+        let mut framebuffer = clean();
+
+        framebuffer.scroll(0, 4, 64, 32, 0x1);
+        assert_eq!(framebuffer.take_dirty(), Some(0..32));
+
+        framebuffer.scroll(-4, 0, 128, 64, 0x3);
+        assert_eq!(framebuffer.take_dirty(), Some(0..64));
+    }
+}
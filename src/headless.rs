@@ -0,0 +1,349 @@
+//! Runs a ROM without a frontend and checks the machine afterwards, for
+//! regression tests.
+//!
+//! Golden display images are text, one line per row and one character per
+//! pixel: `.` for an unlit pixel, `#` for plane 1, `+` for plane 2 and `@`
+//! for both.
+
+use std::time::{Duration, Instant};
+
+use crate::audio::{Beeper, BeeperSettings, DEFAULT_SAMPLE_RATE};
+use crate::backend::{Backend, Cpu};
+use crate::chip8::Chip8;
+use crate::constants::TIMER_FREQUENCY;
+use crate::error::Chip8Error;
+
+const PIXEL_CHARS: [char; 4] = ['.', '#', '+', '@'];
+
+/// Something that must hold once the run is over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    /// The display matches a golden image, see [`render_display`].
+    Display(String),
+    /// Memory starting at `address` holds `bytes`.
+    Memory { address: usize, bytes: Vec<u8> },
+    /// `pc` was at this address at some point during the run.
+    PcReached(u16),
+}
+
+/// The active display as a golden image.
+pub fn render_display(chip8: &Chip8) -> String {
+    let mut image = String::new();
+
+    for row in chip8.active_display().chunks(chip8.video_width()) {
+        image.extend(row.iter().map(|pixel| PIXEL_CHARS[(*pixel & 0x3) as usize]));
+        image.push('\n');
+    }
+
+    image
+}
+
+// Compares the display with a golden image, describing the first difference
+fn compare_display(chip8: &Chip8, golden: &str) -> Option<String> {
+    let expected: Vec<&str> = golden.lines().map(str::trim_end).collect();
+    let actual = render_display(chip8);
+    let actual: Vec<&str> = actual.lines().collect();
+
+    let expected_width = expected.first().map_or(0, |row| row.chars().count());
+    if expected.len() != actual.len()
+        || expected
+            .iter()
+            .any(|row| row.chars().count() != chip8.video_width())
+    {
+        return Some(format!(
+            "golden image is {}x{}, the display is {}x{}",
+            expected_width,
+            expected.len(),
+            chip8.video_width(),
+            chip8.video_height()
+        ));
+    }
+
+    let mut differences = 0;
+    let mut first = None;
+    for (y, (expected_row, actual_row)) in expected.iter().zip(&actual).enumerate() {
+        for (x, (expected, actual)) in expected_row.chars().zip(actual_row.chars()).enumerate() {
+            if expected != actual {
+                differences += 1;
+                first.get_or_insert((x, y));
+            }
+        }
+    }
+
+    let (x, y) = first?;
+    Some(format!(
+        "display differs from the golden image in {} pixels, first at ({}, {})\n{}",
+        differences,
+        x,
+        y,
+        render_display(chip8)
+    ))
+}
+
+/// What went wrong in a run, empty if every check passed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeadlessReport {
+    pub failures: Vec<String>,
+    /// Beeper output at `DEFAULT_SAMPLE_RATE`, if the runner had `audio` set
+    pub audio: Vec<f32>,
+}
+
+impl HeadlessReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Result of [`benchmark`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Benchmark {
+    pub frames: u32,
+    pub instructions: u64,
+    pub elapsed: Duration,
+}
+
+impl Benchmark {
+    pub fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn frames_per_second(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Runs `frames` frames on `backend` as fast as the host allows. Stops early
+/// if the program exits.
+pub fn benchmark(
+    chip8: &mut Chip8,
+    frames: u32,
+    instructions_per_frame: u32,
+    backend: Backend,
+) -> Result<Benchmark, Chip8Error> {
+    let mut cpu = Cpu::new(backend);
+    let mut instructions = 0;
+    let mut frames_run = 0;
+    let start = Instant::now();
+
+    while frames_run < frames && !chip8.halted {
+        cpu.run_frame_traced(chip8, instructions_per_frame, |_| instructions += 1)?;
+        frames_run += 1;
+    }
+
+    Ok(Benchmark {
+        frames: frames_run,
+        instructions,
+        elapsed: start.elapsed(),
+    })
+}
+
+pub struct HeadlessRunner {
+    pub frames: u32,
+    pub instructions_per_frame: u32,
+    pub checks: Vec<Check>,
+    /// Renders the beeper into the report when set
+    pub audio: Option<BeeperSettings>,
+    pub backend: Backend,
+}
+
+impl HeadlessRunner {
+    /// Runs `frames` frames on a machine with the ROM already loaded, then
+    /// applies the checks. An error from the machine aborts the run.
+    pub fn run(&self, chip8: &mut Chip8) -> Result<HeadlessReport, Chip8Error> {
+        let mut visited = Vec::new();
+        let mut visit = |chip8: &Chip8| {
+            for check in &self.checks {
+                if let Check::PcReached(address) = check
+                    && chip8.pc == *address
+                    && !visited.contains(address)
+                {
+                    visited.push(*address);
+                }
+            }
+        };
+
+        let mut beeper = self
+            .audio
+            .map(|settings| Beeper::new(settings, DEFAULT_SAMPLE_RATE));
+        let mut audio = Vec::new();
+        let mut cpu = Cpu::new(self.backend);
+
+        for frame in 1..=self.frames as u64 {
+            cpu.run_frame_traced(chip8, self.instructions_per_frame, &mut visit)?;
+
+            if let Some(beeper) = beeper.as_mut() {
+                // Whole samples up to the end of this frame
+                let end = frame * DEFAULT_SAMPLE_RATE as u64 / TIMER_FREQUENCY as u64;
+                let start = audio.len();
+                audio.resize(end as usize, 0.0);
+
+                beeper.active = chip8.sound_timer > 0;
+                beeper.pattern = chip8.sound_pattern();
+                beeper.fill(&mut audio[start..]);
+            }
+
+            if chip8.halted {
+                break;
+            }
+        }
+        // Where the run stopped counts as well
+        visit(chip8);
+
+        let mut report = HeadlessReport {
+            failures: Vec::new(),
+            audio,
+        };
+
+        for check in &self.checks {
+            let failure = match check {
+                Check::Display(golden) => compare_display(chip8, golden),
+                Check::Memory { address, bytes } => {
+                    let actual = chip8.memory.get(*address..*address + bytes.len());
+
+                    (actual != Some(bytes.as_slice())).then(|| {
+                        format!(
+                            "memory at {:#06X} is {:02X?}, expected {:02X?}",
+                            address,
+                            actual.unwrap_or_default(),
+                            bytes
+                        )
+                    })
+                }
+                Check::PcReached(address) => (!visited.contains(address))
+                    .then(|| format!("pc never reached {:#06X}", address)),
+            };
+
+            report.failures.extend(failure);
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    const TEST_OPCODE: &[u8] = include_bytes!("../test_opcode.ch8");
+    const GOLDEN: &str = include_str!("../test_opcode.display.txt");
+
+    fn run_test_opcode(checks: Vec<Check>) -> HeadlessReport {
+        let mut chip8 = Chip8::new();
+        chip8.rng = Rng::new(0);
+        chip8.load_program(TEST_OPCODE).unwrap();
+
+        let runner = HeadlessRunner {
+            frames: 300,
+            instructions_per_frame: 10,
+            checks,
+            audio: None,
+            backend: Backend::Interpreter,
+        };
+
+        runner.run(&mut chip8).unwrap()
+    }
+
+    #[test]
+    fn test_opcode_matches_its_golden_image() {
+        let report = run_test_opcode(vec![Check::Display(GOLDEN.to_string())]);
+
+        assert_eq!(report.failures, Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_a_wrong_golden_image() {
+        // Light the top left pixel, which is unlit in the real image
+        let golden = format!("#{}", &GOLDEN[1..]);
+
+        let report = run_test_opcode(vec![Check::Display(golden)]);
+
+        assert!(!report.passed());
+        assert!(
+            report.failures[0]
+                .starts_with("display differs from the golden image in 1 pixels, first at (0, 0)"),
+            "{}",
+            report.failures[0]
+        );
+    }
+
+    #[test]
+    fn reports_a_golden_image_of_the_wrong_size() {
+        let golden: String = GOLDEN
+            .lines()
+            .take(16)
+            .map(|row| format!("{}\n", row))
+            .collect();
+
+        let report = run_test_opcode(vec![Check::Display(golden)]);
+
+        assert_eq!(
+            report.failures,
+            ["golden image is 64x16, the display is 64x32"]
+        );
+    }
+
+    #[test]
+    fn reports_memory_and_pc_checks() {
+        let report = run_test_opcode(vec![
+            Check::Memory {
+                address: 0x200,
+                bytes: TEST_OPCODE[..2].to_vec(),
+            },
+            Check::Memory {
+                address: 0x200,
+                bytes: vec![0xFF],
+            },
+            Check::PcReached(0x200),
+            Check::PcReached(0xFFE),
+        ]);
+
+        assert_eq!(
+            report.failures,
+            [
+                format!(
+                    "memory at 0x0200 is [{:02X}], expected [FF]",
+                    TEST_OPCODE[0]
+                ),
+                "pc never reached 0x0FFE".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_the_sound_timer_into_a_wav() {
+        // LD V0, 30; LD ST, V0; JP 0x204
+        let mut chip8 = Chip8::new();
+        chip8
+            .load_program(&[0x60, 0x1E, 0xF0, 0x18, 0x12, 0x04])
+            .unwrap();
+        let runner = HeadlessRunner {
+            frames: 60,
+            instructions_per_frame: 10,
+            checks: Vec::new(),
+            audio: Some(BeeperSettings::default()),
+            backend: Backend::Interpreter,
+        };
+
+        let audio = runner.run(&mut chip8).unwrap().audio;
+
+        // A second of audio, beeping for the first half
+        let samples_per_frame = DEFAULT_SAMPLE_RATE as usize / 60;
+        assert_eq!(audio.len(), DEFAULT_SAMPLE_RATE as usize);
+        assert!(
+            audio[..samples_per_frame * 29]
+                .iter()
+                .any(|sample| *sample != 0.0)
+        );
+        assert!(
+            audio[samples_per_frame * 31..]
+                .iter()
+                .all(|sample| *sample == 0.0)
+        );
+
+        let mut wav = Vec::new();
+        crate::audio::write_wav(&mut wav, DEFAULT_SAMPLE_RATE, &audio).unwrap();
+        assert_eq!(wav.len(), 44 + audio.len() * 2);
+        assert_eq!(wav[40..44], (audio.len() as u32 * 2).to_le_bytes());
+    }
+}
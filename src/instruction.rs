@@ -0,0 +1,555 @@
+//! Typed view of the opcode format, shared by tools that read or write
+//! programs (debugger, disassembler, assembler).
+//!
+//! [`Instruction::decode`] follows the documented encodings. The
+//! interpreter uses [`Instruction::decode_lenient`], which ignores some
+//! don't-care bits, e.g. it runs `9xy1` as `9xy0`.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// `00Cn` (SCHIP)
+    Scd(u8),
+    /// `00Dn` (XO-CHIP)
+    Scu(u8),
+    /// `00E0`
+    Cls,
+    /// `00EE`
+    Ret,
+    /// `00FB` (SCHIP)
+    Scr,
+    /// `00FC` (SCHIP)
+    Scl,
+    /// `00FD` (SCHIP)
+    Exit,
+    /// `00FE` (SCHIP)
+    Low,
+    /// `00FF` (SCHIP)
+    High,
+    /// `0nnn`, a machine code routine
+    Sys(u16),
+    /// `1nnn`
+    Jp(u16),
+    /// `2nnn`
+    Call(u16),
+    /// `3xkk`
+    SeByte { x: u8, kk: u8 },
+    /// `4xkk`
+    SneByte { x: u8, kk: u8 },
+    /// `5xy0`
+    SeReg { x: u8, y: u8 },
+    /// `5xy2` (XO-CHIP)
+    Save { x: u8, y: u8 },
+    /// `5xy3` (XO-CHIP)
+    Load { x: u8, y: u8 },
+    /// `6xkk`
+    LdByte { x: u8, kk: u8 },
+    /// `7xkk`
+    AddByte { x: u8, kk: u8 },
+    /// `8xy0`
+    LdReg { x: u8, y: u8 },
+    /// `8xy1`
+    Or { x: u8, y: u8 },
+    /// `8xy2`
+    And { x: u8, y: u8 },
+    /// `8xy3`
+    Xor { x: u8, y: u8 },
+    /// `8xy4`
+    AddReg { x: u8, y: u8 },
+    /// `8xy5`
+    Sub { x: u8, y: u8 },
+    /// `8xy6`
+    Shr { x: u8, y: u8 },
+    /// `8xy7`
+    Subn { x: u8, y: u8 },
+    /// `8xyE`
+    Shl { x: u8, y: u8 },
+    /// `9xy0`
+    SneReg { x: u8, y: u8 },
+    /// `Annn`
+    LdIAddr(u16),
+    /// `Bnnn`
+    JpV0(u16),
+    /// `Cxkk`
+    Rnd { x: u8, kk: u8 },
+    /// `Dxyn`
+    Drw { x: u8, y: u8, n: u8 },
+    /// `Ex9E`
+    Skp(u8),
+    /// `ExA1`
+    Sknp(u8),
+    /// `F000` (XO-CHIP). The 16 bit address is in the word that follows,
+    /// so the instruction is four bytes long.
+    LdILong,
+    /// `Fn01` (XO-CHIP), n is the plane mask
+    Plane(u8),
+    /// `F002` (XO-CHIP)
+    Audio,
+    /// `Fx07`
+    LdVxDt(u8),
+    /// `Fx0A`
+    LdVxK(u8),
+    /// `Fx15`
+    LdDtVx(u8),
+    /// `Fx18`
+    LdStVx(u8),
+    /// `Fx1E`
+    AddIVx(u8),
+    /// `Fx29`
+    LdFVx(u8),
+    /// `Fx30` (SCHIP)
+    LdHfVx(u8),
+    /// `Fx33`
+    LdBVx(u8),
+    /// `Fx3A` (XO-CHIP)
+    Pitch(u8),
+    /// `Fx55`
+    LdIVx(u8),
+    /// `Fx65`
+    LdVxI(u8),
+    /// `Fx75` (SCHIP)
+    LdRVx(u8),
+    /// `Fx85` (SCHIP)
+    LdVxR(u8),
+    /// Any opcode not listed above
+    Unknown(u16),
+}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Instruction {
+        let nnn = opcode & 0x0FFF;
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+        let n = (opcode & 0x000F) as u8;
+        let kk = (opcode & 0x00FF) as u8;
+
+        match opcode >> 12 {
+            0x0 => match nnn {
+                0x0C0..=0x0CF => Instruction::Scd(n),
+                0x0D0..=0x0DF => Instruction::Scu(n),
+                0x0E0 => Instruction::Cls,
+                0x0EE => Instruction::Ret,
+                0x0FB => Instruction::Scr,
+                0x0FC => Instruction::Scl,
+                0x0FD => Instruction::Exit,
+                0x0FE => Instruction::Low,
+                0x0FF => Instruction::High,
+                _ => Instruction::Sys(nnn),
+            },
+            0x1 => Instruction::Jp(nnn),
+            0x2 => Instruction::Call(nnn),
+            0x3 => Instruction::SeByte { x, kk },
+            0x4 => Instruction::SneByte { x, kk },
+            0x5 => match n {
+                0x0 => Instruction::SeReg { x, y },
+                0x2 => Instruction::Save { x, y },
+                0x3 => Instruction::Load { x, y },
+                _ => Instruction::Unknown(opcode),
+            },
+            0x6 => Instruction::LdByte { x, kk },
+            0x7 => Instruction::AddByte { x, kk },
+            0x8 => match n {
+                0x0 => Instruction::LdReg { x, y },
+                0x1 => Instruction::Or { x, y },
+                0x2 => Instruction::And { x, y },
+                0x3 => Instruction::Xor { x, y },
+                0x4 => Instruction::AddReg { x, y },
+                0x5 => Instruction::Sub { x, y },
+                0x6 => Instruction::Shr { x, y },
+                0x7 => Instruction::Subn { x, y },
+                0xE => Instruction::Shl { x, y },
+                _ => Instruction::Unknown(opcode),
+            },
+            0x9 if n == 0 => Instruction::SneReg { x, y },
+            0xA => Instruction::LdIAddr(nnn),
+            0xB => Instruction::JpV0(nnn),
+            0xC => Instruction::Rnd { x, kk },
+            0xD => Instruction::Drw { x, y, n },
+            0xE => match kk {
+                0x9E => Instruction::Skp(x),
+                0xA1 => Instruction::Sknp(x),
+                _ => Instruction::Unknown(opcode),
+            },
+            0xF => match kk {
+                0x00 if x == 0 => Instruction::LdILong,
+                0x01 => Instruction::Plane(x),
+                0x02 if x == 0 => Instruction::Audio,
+                0x07 => Instruction::LdVxDt(x),
+                0x0A => Instruction::LdVxK(x),
+                0x15 => Instruction::LdDtVx(x),
+                0x18 => Instruction::LdStVx(x),
+                0x1E => Instruction::AddIVx(x),
+                0x29 => Instruction::LdFVx(x),
+                0x30 => Instruction::LdHfVx(x),
+                0x33 => Instruction::LdBVx(x),
+                0x3A => Instruction::Pitch(x),
+                0x55 => Instruction::LdIVx(x),
+                0x65 => Instruction::LdVxI(x),
+                0x75 => Instruction::LdRVx(x),
+                0x85 => Instruction::LdVxR(x),
+                _ => Instruction::Unknown(opcode),
+            },
+            _ => Instruction::Unknown(opcode),
+        }
+    }
+
+    /// Like [`Instruction::decode`], but ignoring the bits the interpreter
+    /// doesn't look at: the low nibble of `9xy0`, the high nibble of the
+    /// second byte of `Ex9E`/`ExA1`, and x in `F000` and `F002`.
+    pub fn decode_lenient(opcode: u16) -> Instruction {
+        let opcode = match opcode >> 12 {
+            0x9 => opcode & 0xFFF0,
+            0xE => match opcode & 0x000F {
+                0x1 => opcode & 0xFF00 | 0x00A1,
+                0xE => opcode & 0xFF00 | 0x009E,
+                _ => opcode,
+            },
+            0xF if matches!(opcode & 0x00FF, 0x00 | 0x02) => opcode & 0xF0FF,
+            _ => opcode,
+        };
+
+        Instruction::decode(opcode)
+    }
+
+    /// The opcode for this instruction. Operands are masked to their field
+    /// width, so `Jp(0xFFFF)` encodes as `1FFF`.
+    pub fn encode(&self) -> u16 {
+        let addr = |prefix: u16, nnn: u16| prefix << 12 | (nnn & 0x0FFF);
+        let xkk = |prefix: u16, x: u8, kk: u8| prefix << 12 | ((x as u16) & 0xF) << 8 | kk as u16;
+        let xyn = |prefix: u16, x: u8, y: u8, n: u8| {
+            prefix << 12 | ((x as u16) & 0xF) << 8 | ((y as u16) & 0xF) << 4 | ((n as u16) & 0xF)
+        };
+
+        match *self {
+            Instruction::Scd(n) => 0x00C0 | ((n as u16) & 0xF),
+            Instruction::Scu(n) => 0x00D0 | ((n as u16) & 0xF),
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Scr => 0x00FB,
+            Instruction::Scl => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Low => 0x00FE,
+            Instruction::High => 0x00FF,
+            Instruction::Sys(nnn) => addr(0x0, nnn),
+            Instruction::Jp(nnn) => addr(0x1, nnn),
+            Instruction::Call(nnn) => addr(0x2, nnn),
+            Instruction::SeByte { x, kk } => xkk(0x3, x, kk),
+            Instruction::SneByte { x, kk } => xkk(0x4, x, kk),
+            Instruction::SeReg { x, y } => xyn(0x5, x, y, 0x0),
+            Instruction::Save { x, y } => xyn(0x5, x, y, 0x2),
+            Instruction::Load { x, y } => xyn(0x5, x, y, 0x3),
+            Instruction::LdByte { x, kk } => xkk(0x6, x, kk),
+            Instruction::AddByte { x, kk } => xkk(0x7, x, kk),
+            Instruction::LdReg { x, y } => xyn(0x8, x, y, 0x0),
+            Instruction::Or { x, y } => xyn(0x8, x, y, 0x1),
+            Instruction::And { x, y } => xyn(0x8, x, y, 0x2),
+            Instruction::Xor { x, y } => xyn(0x8, x, y, 0x3),
+            Instruction::AddReg { x, y } => xyn(0x8, x, y, 0x4),
+            Instruction::Sub { x, y } => xyn(0x8, x, y, 0x5),
+            Instruction::Shr { x, y } => xyn(0x8, x, y, 0x6),
+            Instruction::Subn { x, y } => xyn(0x8, x, y, 0x7),
+            Instruction::Shl { x, y } => xyn(0x8, x, y, 0xE),
+            Instruction::SneReg { x, y } => xyn(0x9, x, y, 0x0),
+            Instruction::LdIAddr(nnn) => addr(0xA, nnn),
+            Instruction::JpV0(nnn) => addr(0xB, nnn),
+            Instruction::Rnd { x, kk } => xkk(0xC, x, kk),
+            Instruction::Drw { x, y, n } => xyn(0xD, x, y, n),
+            Instruction::Skp(x) => xkk(0xE, x, 0x9E),
+            Instruction::Sknp(x) => xkk(0xE, x, 0xA1),
+            Instruction::LdILong => 0xF000,
+            Instruction::Plane(n) => xkk(0xF, n, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::LdVxDt(x) => xkk(0xF, x, 0x07),
+            Instruction::LdVxK(x) => xkk(0xF, x, 0x0A),
+            Instruction::LdDtVx(x) => xkk(0xF, x, 0x15),
+            Instruction::LdStVx(x) => xkk(0xF, x, 0x18),
+            Instruction::AddIVx(x) => xkk(0xF, x, 0x1E),
+            Instruction::LdFVx(x) => xkk(0xF, x, 0x29),
+            Instruction::LdHfVx(x) => xkk(0xF, x, 0x30),
+            Instruction::LdBVx(x) => xkk(0xF, x, 0x33),
+            Instruction::Pitch(x) => xkk(0xF, x, 0x3A),
+            Instruction::LdIVx(x) => xkk(0xF, x, 0x55),
+            Instruction::LdVxI(x) => xkk(0xF, x, 0x65),
+            Instruction::LdRVx(x) => xkk(0xF, x, 0x75),
+            Instruction::LdVxR(x) => xkk(0xF, x, 0x85),
+            Instruction::Unknown(opcode) => opcode,
+        }
+    }
+
+    /// Size in bytes, including the address word after `F000`.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdILong => 4,
+            _ => 2,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Scd(n) => write!(f, "SCD {}", n),
+            Instruction::Scu(n) => write!(f, "SCU {}", n),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Scr => write!(f, "SCR"),
+            Instruction::Scl => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Sys(nnn) => write!(f, "SYS 0x{:03X}", nnn),
+            Instruction::Jp(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            Instruction::SeByte { x, kk } => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SneByte { x, kk } => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SeReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::Save { x, y } => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::Load { x, y } => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LdByte { x, kk } => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            Instruction::AddByte { x, kk } => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            Instruction::LdReg { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdIAddr(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            Instruction::JpV0(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Instruction::Rnd { x, kk } => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Instruction::Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdILong => write!(f, "LD I, LONG"),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIVx(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFVx(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHfVx(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdBVx(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::Unknown(opcode) => write!(f, "DW 0x{:04X}", opcode),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseInstructionError(pub String);
+
+impl fmt::Display for ParseInstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseInstructionError {}
+
+// One comma separated operand of a mnemonic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    V(u8),
+    Number(u32),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long,
+}
+
+fn parse_operand(text: &str) -> Result<Operand, ParseInstructionError> {
+    let upper = text.to_ascii_uppercase();
+
+    let operand = match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::Hf,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        "LONG" => Operand::Long,
+        _ => {
+            if let Some(register) = upper.strip_prefix('V').filter(|r| r.len() == 1) {
+                u8::from_str_radix(register, 16)
+                    .map(Operand::V)
+                    .map_err(|_| ParseInstructionError(format!("Bad register: {}", text)))?
+            } else {
+                let number = match upper.strip_prefix("0X") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => upper.parse(),
+                };
+                number
+                    .map(Operand::Number)
+                    .map_err(|_| ParseInstructionError(format!("Bad operand: {}", text)))?
+            }
+        }
+    };
+
+    Ok(operand)
+}
+
+// Checks that a number fits in a `bits` wide field
+fn field(value: u32, bits: u32) -> Result<u16, ParseInstructionError> {
+    if value >= 1 << bits {
+        return Err(ParseInstructionError(format!(
+            "0x{:X} doesn't fit in {} bits",
+            value, bits
+        )));
+    }
+
+    Ok(value as u16)
+}
+
+impl FromStr for Instruction {
+    type Err = ParseInstructionError;
+
+    /// Parses the mnemonics written by `Display`, case insensitively.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use Instruction::*;
+        use Operand::{B, Dt, F, Hf, I, IndirectI, K, Long, Number, R, St, V};
+
+        let s = s.trim();
+        let (mnemonic, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let operands = if rest.trim().is_empty() {
+            Vec::new()
+        } else {
+            rest.split(',')
+                .map(|operand| parse_operand(operand.trim()))
+                .collect::<Result<Vec<Operand>, ParseInstructionError>>()?
+        };
+
+        let addr = |value: u32| field(value, 12);
+        let byte = |value: u32| field(value, 8).map(|value| value as u8);
+        let nibble = |value: u32| field(value, 4).map(|value| value as u8);
+
+        let instruction = match (mnemonic.to_ascii_uppercase().as_str(), operands.as_slice()) {
+            ("SCD", [Number(n)]) => Scd(nibble(*n)?),
+            ("SCU", [Number(n)]) => Scu(nibble(*n)?),
+            ("CLS", []) => Cls,
+            ("RET", []) => Ret,
+            ("SCR", []) => Scr,
+            ("SCL", []) => Scl,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => Low,
+            ("HIGH", []) => High,
+            ("SYS", [Number(nnn)]) => Sys(addr(*nnn)?),
+            ("JP", [Number(nnn)]) => Jp(addr(*nnn)?),
+            ("JP", [V(0), Number(nnn)]) => JpV0(addr(*nnn)?),
+            ("CALL", [Number(nnn)]) => Call(addr(*nnn)?),
+            ("SE", [V(x), Number(kk)]) => SeByte {
+                x: *x,
+                kk: byte(*kk)?,
+            },
+            ("SE", [V(x), V(y)]) => SeReg { x: *x, y: *y },
+            ("SNE", [V(x), Number(kk)]) => SneByte {
+                x: *x,
+                kk: byte(*kk)?,
+            },
+            ("SNE", [V(x), V(y)]) => SneReg { x: *x, y: *y },
+            ("SAVE", [V(x), V(y)]) => Save { x: *x, y: *y },
+            ("LOAD", [V(x), V(y)]) => Load { x: *x, y: *y },
+            ("LD", [V(x), Number(kk)]) => LdByte {
+                x: *x,
+                kk: byte(*kk)?,
+            },
+            ("LD", [V(x), V(y)]) => LdReg { x: *x, y: *y },
+            ("LD", [I, Number(nnn)]) => LdIAddr(addr(*nnn)?),
+            ("LD", [I, Long]) => LdILong,
+            ("LD", [V(x), Dt]) => LdVxDt(*x),
+            ("LD", [V(x), K]) => LdVxK(*x),
+            ("LD", [Dt, V(x)]) => LdDtVx(*x),
+            ("LD", [St, V(x)]) => LdStVx(*x),
+            ("LD", [F, V(x)]) => LdFVx(*x),
+            ("LD", [Hf, V(x)]) => LdHfVx(*x),
+            ("LD", [B, V(x)]) => LdBVx(*x),
+            ("LD", [IndirectI, V(x)]) => LdIVx(*x),
+            ("LD", [V(x), IndirectI]) => LdVxI(*x),
+            ("LD", [R, V(x)]) => LdRVx(*x),
+            ("LD", [V(x), R]) => LdVxR(*x),
+            ("ADD", [V(x), Number(kk)]) => AddByte {
+                x: *x,
+                kk: byte(*kk)?,
+            },
+            ("ADD", [V(x), V(y)]) => AddReg { x: *x, y: *y },
+            ("ADD", [I, V(x)]) => AddIVx(*x),
+            ("OR", [V(x), V(y)]) => Or { x: *x, y: *y },
+            ("AND", [V(x), V(y)]) => And { x: *x, y: *y },
+            ("XOR", [V(x), V(y)]) => Xor { x: *x, y: *y },
+            ("SUB", [V(x), V(y)]) => Sub { x: *x, y: *y },
+            ("SHR", [V(x), V(y)]) => Shr { x: *x, y: *y },
+            ("SUBN", [V(x), V(y)]) => Subn { x: *x, y: *y },
+            ("SHL", [V(x), V(y)]) => Shl { x: *x, y: *y },
+            ("RND", [V(x), Number(kk)]) => Rnd {
+                x: *x,
+                kk: byte(*kk)?,
+            },
+            ("DRW", [V(x), V(y), Number(n)]) => Drw {
+                x: *x,
+                y: *y,
+                n: nibble(*n)?,
+            },
+            ("SKP", [V(x)]) => Skp(*x),
+            ("SKNP", [V(x)]) => Sknp(*x),
+            ("PLANE", [Number(n)]) => Plane(nibble(*n)?),
+            ("AUDIO", []) => Audio,
+            ("PITCH", [V(x)]) => Pitch(*x),
+            ("DW", [Number(opcode)]) => Instruction::decode(field(*opcode, 16)?),
+            _ => {
+                return Err(ParseInstructionError(format!("Unknown instruction: {}", s)));
+            }
+        };
+
+        Ok(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_and_encode_round_trip() {
+        for opcode in 0..=0xFFFF {
+            assert_eq!(
+                Instruction::decode(opcode).encode(),
+                opcode,
+                "{:04X}",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn display_and_parse_round_trip() {
+        for opcode in 0..=0xFFFF {
+            let instruction = Instruction::decode(opcode);
+
+            assert_eq!(
+                instruction.to_string().parse(),
+                Ok(instruction),
+                "{:04X}",
+                opcode
+            );
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! CHIP-8 emulator core.
+//!
+//! This crate has no frontend dependencies: it only knows about the machine
+//! itself. The SDL frontend lives in the `chip-8` binary (behind the `sdl`
+//! feature) and drives a [`Chip8`] through its public API.
+
+pub mod assembler;
+pub mod audio;
+pub mod backend;
+pub mod blocks;
+pub mod chip8;
+pub mod constants;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod framebuffer;
+pub mod headless;
+pub mod instruction;
+pub mod movie;
+pub mod palette;
+pub mod quirks;
+pub mod recording;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
+pub mod scheduler;
+pub mod screenshot;
+pub mod trace;
+pub mod unknown;
+
+pub use backend::{Backend, Cpu};
+pub use chip8::Chip8;
+pub use debugger::Debugger;
+pub use error::Chip8Error;
+pub use instruction::Instruction;
+pub use palette::Palette;
+pub use quirks::Quirks;
+pub use rewind::RewindBuffer;
+pub use rng::Rng;
+pub use savestate::SaveStateError;
+pub use scheduler::FrameScheduler;
+pub use unknown::{UnknownOpcodePolicy, UnknownOpcodeReport};
@@ -1,120 +1,514 @@
-extern crate sdl2;
+use sdl2::pixels::PixelFormatEnum;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use std::{env, io, process};
 
-use sdl2::EventPump;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::{Color, PixelFormatEnum};
-use sdl2::render::{Texture, WindowCanvas};
-use std::time::{Duration, Instant};
-use std::{env, process};
-
-
-use std::io::{self, Read, Seek, SeekFrom};
-
-mod chip8;
+mod cli;
+mod config;
 mod platform;
-mod constants;
 
-use chip8::Chip8;
-use platform::Platform;
-use constants::{VIDEO_WIDTH, VIDEO_HEIGHT, START_ADDRESS};
-
-use crate::chip8::config_chip8_tables;
+use chip_8::audio::{DEFAULT_SAMPLE_RATE, write_wav};
+use chip_8::chip8::read_program;
+use chip_8::constants::{
+    DEFAULT_INSTRUCTIONS_PER_FRAME, TIMER_FREQUENCY, VIDEO_HEIGHT, VIDEO_WIDTH,
+};
+use chip_8::disasm::Disassembly;
+use chip_8::headless::{Check, HeadlessRunner, benchmark, render_display};
+use chip_8::movie::{Movie, MoviePlayer, MovieRecorder};
+use chip_8::recording::GifRecorder;
+use chip_8::screenshot::{ImageFormat, Screenshot, screenshot_path};
+use chip_8::trace::Tracer;
+use chip_8::{Chip8, Cpu, Debugger, FrameScheduler, RewindBuffer, Rng};
+use cli::{
+    BenchOptions, DEFAULT_BENCH_INSTRUCTIONS_PER_FRAME, DEFAULT_VIDEO_SCALE, MachineOptions, Mode,
+    TestOptions,
+};
+use config::{Config, Settings};
+use platform::{Command, Platform};
 
 fn main() -> Result<(), String> {
     unsafe { env::set_var("RUST_BACKTRACE", "1") };
 
     let args: Vec<String> = env::args().collect();
-    if args.len() != 4 {
-        eprintln!("Usage: {} <Scale> <Delay> <ROM>", args[0]);
+    let options = match cli::parse_args(&args) {
+        Ok(Mode::Emulate(options)) => options,
+        Ok(Mode::Disassemble { rom_filename }) => return disassemble(&rom_filename),
+        Ok(Mode::Test(options)) => return run_test(options),
+        Ok(Mode::Bench(options)) => return run_benchmark(&options),
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", cli::usage(&args[0]));
+            process::exit(1);
+        }
+    };
+
+    let mut chip8 = Chip8::new();
+    chip8.unknown_opcode_policy = options.machine.unknown_opcode_policy;
+
+    // Always run from a known seed, so any session can be reproduced
+    let seed = options.machine.seed.unwrap_or_else(rand::random);
+    chip8.rng = Rng::new(seed);
+    eprintln!("RNG seed: {}", seed);
+
+    if let Err(e) = chip8.load_rom(&options.rom_filename) {
+        eprintln!("Failed to load ROM {}: {}", options.rom_filename, e);
         process::exit(1);
     }
 
-    let video_scale: u32 = args[1].parse().expect("Scale must be a number");
-    let cycle_delay: u64 = args[2].parse().expect("Delay must be a number");
-    let rom_filename = &args[3];
+    // Flags first, then the config file, then the defaults
+    let mut settings =
+        load_config(&options.machine, true)?.settings_for(&options.rom_filename, &chip8.rom_hash);
+    settings.merge(&Settings::from_flags(
+        &options.machine,
+        options.video_scale,
+        options.palette,
+    ));
+
+    let video_scale = settings.video_scale.unwrap_or(DEFAULT_VIDEO_SCALE);
+    let mut instructions_per_frame = settings
+        .instructions_per_frame
+        .unwrap_or(DEFAULT_INSTRUCTIONS_PER_FRAME);
+    let palette = settings.palette.unwrap_or_default();
+    chip8.quirks = settings.quirks.unwrap_or_default();
+
+    if options.debug {
+        let mut debugger = Debugger::new(instructions_per_frame);
+
+        let interrupt = debugger.interrupt.clone();
+        ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed))
+            .map_err(|e| format!("Failed to install the Ctrl-C handler: {}", e))?;
+
+        debugger
+            .run(&mut chip8, io::stdin().lock(), &mut io::stdout())
+            .map_err(|e| e.to_string())?;
+        report_unknown_opcodes(&chip8);
+
+        return Ok(());
+    }
 
     let mut platform = Platform::new(
         "CHIP-8 Emulator",
         VIDEO_WIDTH as u32 * video_scale,
         VIDEO_HEIGHT as u32 * video_scale,
+        options.beeper,
+        options.mute,
+        platform::key_map(&settings.keys)?,
     )?;
 
-    let mut chip8 = Chip8 {
-        registers: [0; 16],
-        memory: [0; 4096],
-        index: 0,
-        pc: START_ADDRESS,
-        stack: [0; 16],
-        sp: 0,
-        delay_timer: 0,
-        sound_timer: 0,
-        keypad: [0; 16],
-        display: [0; 64 * 32],
-        opcode: 0,
-
-        table: [Chip8::OP_null; 16],
-        table_0: [Chip8::OP_null; 0xE + 1],
-        table_8: [Chip8::OP_null; 0xE + 1],
-        table_e: [Chip8::OP_null; 0xE + 1],
-        table_f: [Chip8::OP_null; 0x65 + 1],
-    };
-
-    config_chip8_tables(&mut chip8);
+    let mut recorder = options
+        .record_movie
+        .as_ref()
+        .map(|_| MovieRecorder::new(&chip8, seed, instructions_per_frame));
 
-    chip8.load_fontset();
+    let mut player = None;
 
-    if let Err(e) = chip8.load_rom(rom_filename) {
-        eprintln!("Failed to load ROM: {}", e);
-        process::exit(1);
+    if let Some(path) = &options.play_movie {
+        match Movie::load(path).and_then(|movie| MoviePlayer::new(movie, &mut chip8)) {
+            Ok(movie) => {
+                instructions_per_frame = movie.instructions_per_frame();
+                player = Some(movie);
+            }
+            Err(e) => {
+                eprintln!("Failed to load movie {}: {}", path, e);
+                process::exit(1);
+            }
+        }
     }
 
     let texture_creator = platform.canvas.texture_creator();
+    let mut texture_width = chip8.video_width();
+    // Set when the whole texture has to be uploaded again
+    let mut redraw = true;
     let mut texture = texture_creator
         .create_texture_streaming(
             PixelFormatEnum::RGBA8888,
-            VIDEO_WIDTH as u32,
-            VIDEO_HEIGHT as u32,
+            chip8.video_width() as u32,
+            chip8.video_height() as u32,
         )
         .map_err(|e| e.to_string())?;
 
-    let video_pitch = (VIDEO_WIDTH as u32 * 4) as usize;
+    let mut tracer = match &options.trace {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("Failed to create trace file {}: {}", path, e))?;
+            Some(Tracer::new(
+                BufWriter::new(file),
+                options.trace_filter.clone(),
+            ))
+        }
+        None => None,
+    };
+
+    let palettes = palette.cycle();
+    let mut palette_index = 0;
 
-    let mut last_cycle_time = Instant::now();
-    let delay_duration = Duration::from_millis(cycle_delay);
+    let mut gif = match &options.record_gif {
+        Some(path) => {
+            let file =
+                File::create(path).map_err(|e| format!("Failed to create GIF {}: {}", path, e))?;
+            let recorder = GifRecorder::new(BufWriter::new(file), options.gif_scale, palette)
+                .map_err(|e| format!("Failed to write GIF {}: {}", path, e))?;
+            Some(recorder)
+        }
+        None => None,
+    };
+
+    let mut cpu = Cpu::new(options.machine.backend);
+    let mut scheduler = FrameScheduler::new(TIMER_FREQUENCY);
+    let rewind_frames = options
+        .rewind_seconds
+        .checked_mul(TIMER_FREQUENCY)
+        .ok_or("Rewind length out of range")?;
+    let mut rewind = RewindBuffer::new(rewind_frames as usize);
+    let mut rewinding = false;
+    let mut keypad = [0; 16];
+    let mut exit_code = 0;
 
     'gameloop: loop {
+        // Going back in time would break a movie being recorded or played
+        let movie_active = recorder.is_some() || player.is_some();
+
+        for command in platform.process_input(&mut keypad) {
+            match command {
+                Command::Quit => break 'gameloop,
+                Command::StartRewind if movie_active => {
+                    eprintln!("Rewind is disabled while a movie is active")
+                }
+                Command::LoadState(_) if movie_active => {
+                    eprintln!("Loading states is disabled while a movie is active")
+                }
+                Command::StartRewind => rewinding = true,
+                Command::StopRewind => rewinding = false,
+                Command::SaveState(slot) => {
+                    let path = state_slot_path(&options.rom_filename, slot);
+                    match chip8.save_state_to_file(&path) {
+                        Ok(()) => println!("Saved state to {}", path),
+                        Err(e) => eprintln!("Failed to save state to {}: {}", path, e),
+                    }
+                }
+                Command::LoadState(slot) => {
+                    let path = state_slot_path(&options.rom_filename, slot);
+                    match chip8.load_state_from_file(&path) {
+                        Ok(()) => {
+                            println!("Loaded state from {}", path);
+                            rewind.clear();
+                        }
+                        Err(e) => eprintln!("Failed to load state from {}: {}", path, e),
+                    }
+                }
+                Command::NextPalette => {
+                    palette_index = (palette_index + 1) % palettes.len();
+                    redraw = true;
+                    println!("Palette: {}", palettes[palette_index].name);
+
+                    if let Some(gif) = gif.as_mut() {
+                        gif.palette = palettes[palette_index];
+                    }
+                }
+                Command::Redraw => redraw = true,
+                Command::ToggleMute => {
+                    if platform.toggle_mute() {
+                        println!("Sound muted");
+                    } else {
+                        println!("Sound on");
+                    }
+                }
+                Command::Screenshot => {
+                    let screenshot = Screenshot {
+                        format: options.screenshot_format,
+                        scale: options.screenshot_scale,
+                        palette: palettes[palette_index],
+                    };
+                    let path = screenshot_path(&options.rom_filename, screenshot.format);
+                    match screenshot.save(&chip8, &path) {
+                        Ok(()) => println!("Saved screenshot to {}", path),
+                        Err(e) => eprintln!("Failed to save screenshot to {}: {}", path, e),
+                    }
+                }
+            }
+        }
 
-        if platform.process_input(&mut chip8.keypad) {
+        if chip8.halted {
             break 'gameloop;
         }
 
-        let current_time = Instant::now();
+        let frames = scheduler.frames_due(Instant::now());
+
+        for _ in 0..frames {
+            if rewinding {
+                rewind.rewind(&mut chip8);
+                capture_gif_frame(&mut gif, &chip8);
+                continue;
+            }
+
+            let playing = player
+                .as_mut()
+                .is_some_and(|movie| movie.play_input(&mut chip8));
+
+            if !playing {
+                if player.take().is_some() {
+                    println!("Movie finished, switching to live input");
+                }
+                chip8.keypad = keypad;
+            }
+
+            if let Some(recorder) = recorder.as_mut() {
+                recorder.record_input(&chip8);
+            }
+
+            let result = match tracer.as_mut() {
+                Some(tracer) => cpu.run_frame_traced(&mut chip8, instructions_per_frame, |chip8| {
+                    tracer.trace(chip8)
+                }),
+                None => cpu.run_frame(&mut chip8, instructions_per_frame),
+            };
 
-        if current_time.duration_since(last_cycle_time) >= delay_duration {
-            last_cycle_time = current_time;
+            if let Err(e) = result {
+                eprintln!("Emulation stopped: {}", e);
+                exit_code = 1;
+                break 'gameloop;
+            }
 
-            chip8.cycle();
+            if let Some(recorder) = recorder.as_mut() {
+                recorder.record_state(&chip8);
+            }
 
-            if chip8.delay_timer > 0 {
-                chip8.delay_timer -= 1;
+            if let Some(Err(e)) = player.as_mut().map(|movie| movie.check_state(&chip8)) {
+                eprintln!("{}, switching to live input", e);
+                player = None;
             }
-            if chip8.sound_timer > 0 {
-                chip8.sound_timer -= 1;
+
+            rewind.push(&chip8);
+            capture_gif_frame(&mut gif, &chip8);
+        }
+
+        if frames > 0 {
+            platform.set_beeping(chip8.sound_timer > 0, chip8.sound_pattern());
+
+            // 00FE/00FF switched resolution, the texture has to follow
+            if chip8.video_width() != texture_width {
+                texture_width = chip8.video_width();
+                texture = texture_creator
+                    .create_texture_streaming(
+                        PixelFormatEnum::RGBA8888,
+                        chip8.video_width() as u32,
+                        chip8.video_height() as u32,
+                    )
+                    .map_err(|e| e.to_string())?;
+                redraw = true;
             }
 
-            let buffer_as_u8 = unsafe {
-                std::slice::from_raw_parts(
-                    chip8.display.as_ptr() as *const u8,
-                    chip8.display.len() * 4,
-                )
+            // Only the rows drawn to since the last upload, if any
+            let height = chip8.video_height();
+            let dirty = chip8.display.take_dirty();
+            let rows = if redraw {
+                Some(0..height)
+            } else {
+                dirty.map(|rows| rows.start.min(height)..rows.end.min(height))
             };
+            redraw = false;
+
+            if let Some(rows) = rows
+                && !rows.is_empty()
+            {
+                let video_pitch = chip8.video_width() * 4;
+                let pixels = chip8.display.row_pixels(chip8.video_width(), rows.clone());
+                let buffer = platform::display_to_rgba(&pixels, &palettes[palette_index]);
+
+                platform.update(&mut texture, &buffer, video_pitch, rows)?;
+            }
+        }
+
+        std::thread::sleep(scheduler.time_until_next_frame(Instant::now()));
+    }
+
+    report_unknown_opcodes(&chip8);
+
+    if let (Some(recorder), Some(path)) = (recorder, &options.record_movie) {
+        match recorder.finish().save(path) {
+            Ok(()) => println!("Saved movie to {}", path),
+            Err(e) => eprintln!("Failed to save movie to {}: {}", path, e),
+        }
+    }
+
+    if let (Some(gif), Some(path)) = (gif, &options.record_gif) {
+        match gif.finish().and_then(|mut writer| writer.flush()) {
+            Ok(()) => println!("Saved GIF to {}", path),
+            Err(e) => eprintln!("Failed to write GIF {}: {}", path, e),
+        }
+    }
+
+    if let (Some(tracer), Some(path)) = (tracer, &options.trace)
+        && let Err(e) = tracer.finish()
+    {
+        eprintln!("Failed to write trace file {}: {}", path, e);
+    }
+
+    if exit_code != 0 {
+        process::exit(exit_code);
+    }
+
+    Ok(())
+}
+
+fn disassemble(rom_filename: &str) -> Result<(), String> {
+    let program = read_program(rom_filename)
+        .map_err(|e| format!("Failed to read ROM {}: {}", rom_filename, e))?;
+    print!("{}", Disassembly::new(&program));
+
+    Ok(())
+}
+
+// Headless regression run, exits with status 1 if a check fails
+fn run_test(options: TestOptions) -> Result<(), String> {
+    let mut chip8 = Chip8::new();
+    chip8.unknown_opcode_policy = options.machine.unknown_opcode_policy;
+    chip8.rng = Rng::new(options.machine.seed.unwrap_or(0));
+
+    chip8
+        .load_rom(&options.rom_filename)
+        .map_err(|e| format!("Failed to load ROM {}: {}", options.rom_filename, e))?;
+
+    let mut settings =
+        load_config(&options.machine, false)?.settings_for(&options.rom_filename, &chip8.rom_hash);
+    settings.merge(&Settings::from_flags(
+        &options.machine,
+        None,
+        options.palette,
+    ));
+    chip8.quirks = settings.quirks.unwrap_or_default();
+
+    let mut runner = HeadlessRunner {
+        frames: options.frames,
+        instructions_per_frame: settings
+            .instructions_per_frame
+            .unwrap_or(DEFAULT_INSTRUCTIONS_PER_FRAME),
+        checks: options.checks,
+        audio: options.audio_wav.as_ref().map(|_| options.beeper),
+        backend: options.machine.backend,
+    };
+
+    if let Some(path) = &options.expect_display {
+        let golden = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read golden image {}: {}", path, e))?;
+        runner.checks.push(Check::Display(golden));
+    }
+
+    let report = runner.run(&mut chip8);
+
+    if let Some(path) = &options.save_display {
+        fs::write(path, render_display(&chip8))
+            .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    }
 
-            platform.update(&mut texture, buffer_as_u8, video_pitch)?;
+    if let Some(path) = &options.screenshot {
+        let screenshot = Screenshot {
+            // Checked when parsing the arguments
+            format: ImageFormat::from_path(path).unwrap_or(ImageFormat::Png),
+            scale: options.screenshot_scale,
+            palette: settings.palette.unwrap_or_default(),
+        };
+        screenshot
+            .save(&chip8, path)
+            .map_err(|e| format!("Failed to save screenshot to {}: {}", path, e))?;
+    }
+
+    if let (Ok(report), Some(path)) = (&report, &options.audio_wav) {
+        let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        write_wav(BufWriter::new(file), DEFAULT_SAMPLE_RATE, &report.audio)
+            .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    }
+
+    match report {
+        Ok(report) if report.passed() => {
+            println!("{}: all checks passed", options.rom_filename);
+            Ok(())
+        }
+        Ok(report) => {
+            for failure in &report.failures {
+                eprintln!("{}: {}", options.rom_filename, failure);
+            }
+            process::exit(1);
         }
+        Err(e) => {
+            eprintln!("{}: emulation stopped: {}", options.rom_filename, e);
+            process::exit(1);
+        }
+    }
+}
 
-        std::thread::sleep(Duration::from_micros(100));
+// A failed write stops the recording, the session goes on
+fn capture_gif_frame<W: Write>(gif: &mut Option<GifRecorder<W>>, chip8: &Chip8) {
+    if let Some(recorder) = gif
+        && let Err(e) = recorder.capture(chip8)
+    {
+        eprintln!("Failed to write GIF frame, recording stopped: {}", e);
+        *gif = None;
     }
+}
+
+fn run_benchmark(options: &BenchOptions) -> Result<(), String> {
+    let mut chip8 = Chip8::new();
+    chip8.unknown_opcode_policy = options.machine.unknown_opcode_policy;
+    chip8.rng = Rng::new(options.machine.seed.unwrap_or(0));
+
+    chip8
+        .load_rom(&options.rom_filename)
+        .map_err(|e| format!("Failed to load ROM {}: {}", options.rom_filename, e))?;
+
+    let mut settings =
+        load_config(&options.machine, false)?.settings_for(&options.rom_filename, &chip8.rom_hash);
+    settings.merge(&Settings::from_flags(&options.machine, None, None));
+    chip8.quirks = settings.quirks.unwrap_or_default();
+
+    let result = benchmark(
+        &mut chip8,
+        options.frames,
+        settings
+            .instructions_per_frame
+            .unwrap_or(DEFAULT_BENCH_INSTRUCTIONS_PER_FRAME),
+        options.machine.backend,
+    )
+    .map_err(|e| format!("{}: emulation stopped: {}", options.rom_filename, e))?;
+
+    println!(
+        "{}: {} instructions in {} frames, {:.3} s",
+        options.rom_filename,
+        result.instructions,
+        result.frames,
+        result.elapsed.as_secs_f64()
+    );
+    println!(
+        "{:.2} million instructions/s, {:.0} frames/s ({:.1}x real time)",
+        result.instructions_per_second() / 1e6,
+        result.frames_per_second(),
+        result.frames_per_second() / TIMER_FREQUENCY as f64
+    );
 
     Ok(())
+}
+
+// The file given with `--config`, or the default one if `use_default` is
+// set. Headless runs don't read the default, they must give the same
+// result on every machine.
+fn load_config(machine: &MachineOptions, use_default: bool) -> Result<Config, String> {
+    match &machine.config {
+        Some(path) => Config::load(Path::new(path)),
+        None if use_default => Config::load_default(),
+        None => Ok(Config::default()),
+    }
+}
+
+fn state_slot_path(rom_filename: &str, slot: u8) -> String {
+    format!("{}.state{}", rom_filename, slot)
+}
+
+fn report_unknown_opcodes(chip8: &Chip8) {
+    if !chip8.unknown_opcodes.is_empty() {
+        eprintln!("Unknown opcodes executed:");
+        eprint!("{}", chip8.unknown_opcodes);
+    }
 }
\ No newline at end of file
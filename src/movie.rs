@@ -0,0 +1,448 @@
+//! Input movies: the keypad state of every frame, plus what is needed to
+//! start the machine the same way again, for deterministic replays.
+//!
+//! Layout (all integers little endian):
+//!
+//! ```text
+//! magic                  4 bytes  "C8MV"
+//! version                u16
+//! rom hash               20 bytes SHA-1 of the ROM
+//! seed                   u64
+//! quirks                 u8
+//! unknown opcode policy  u8       0 halt, 1 continue, 2 ignore machine code
+//! instructions/frame     u32
+//! frame count            u32
+//! frames                 u16 keypad bitmask per frame (bit n = key n)
+//! checksum count         u32
+//! checksums              (frame u32, state checksum u64) pairs
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::chip8::Chip8;
+use crate::quirks::Quirks;
+use crate::rng::Rng;
+use crate::savestate::{
+    SaveStateError, StateReader, StateWriter, quirks_from_bits, quirks_to_bits, write_machine,
+};
+use crate::unknown::UnknownOpcodePolicy;
+
+pub const MOVIE_MAGIC: [u8; 4] = *b"C8MV";
+pub const MOVIE_VERSION: u16 = 2;
+
+// A checksum of the whole machine is stored every this many frames
+const CHECKSUM_INTERVAL: u32 = 60;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    /// The data doesn't start with [`MOVIE_MAGIC`].
+    NotAMovie,
+    /// The movie was written by a different version of the format.
+    UnsupportedVersion(u16),
+    /// The movie was recorded with another ROM.
+    RomMismatch,
+    /// The data ends before the movie is complete.
+    Truncated,
+    /// A value in the movie header is out of range.
+    Corrupt(&'static str),
+    /// The machine state during playback differs from the recording.
+    Desync {
+        frame: u32,
+    },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "{}", e),
+            MovieError::NotAMovie => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion(version) => write!(
+                f,
+                "movie version {} is not supported (expected {})",
+                version, MOVIE_VERSION
+            ),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::Corrupt(what) => write!(f, "movie has an invalid {}", what),
+            MovieError::Desync { frame } => write!(f, "playback desynced at frame {}", frame),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MovieError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+
+impl From<SaveStateError> for MovieError {
+    fn from(e: SaveStateError) -> Self {
+        match e {
+            SaveStateError::Io(e) => MovieError::Io(e),
+            SaveStateError::Corrupt(what) => MovieError::Corrupt(what),
+            _ => MovieError::Truncated,
+        }
+    }
+}
+
+/// FNV-1a over the serialized machine.
+pub fn state_checksum(chip8: &Chip8) -> u64 {
+    let mut writer = StateWriter::new();
+    write_machine(chip8, &mut writer);
+
+    writer
+        .buffer
+        .iter()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3)
+        })
+}
+
+fn policy_to_u8(policy: UnknownOpcodePolicy) -> u8 {
+    match policy {
+        UnknownOpcodePolicy::Halt => 0,
+        UnknownOpcodePolicy::Continue => 1,
+        UnknownOpcodePolicy::IgnoreMachineCode => 2,
+    }
+}
+
+fn policy_from_u8(value: u8) -> Result<UnknownOpcodePolicy, MovieError> {
+    match value {
+        0 => Ok(UnknownOpcodePolicy::Halt),
+        1 => Ok(UnknownOpcodePolicy::Continue),
+        2 => Ok(UnknownOpcodePolicy::IgnoreMachineCode),
+        _ => Err(MovieError::Corrupt("unknown opcode policy")),
+    }
+}
+
+fn keypad_to_bits(keypad: &[u8; 16]) -> u16 {
+    keypad
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (key, state)| bits | ((*state != 0) as u16) << key)
+}
+
+fn keypad_from_bits(bits: u16) -> [u8; 16] {
+    let mut keypad = [0; 16];
+    for (key, state) in keypad.iter_mut().enumerate() {
+        *state = ((bits >> key) & 1) as u8;
+    }
+
+    keypad
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: [u8; 20],
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub unknown_opcode_policy: UnknownOpcodePolicy,
+    pub instructions_per_frame: u32,
+    pub frames: Vec<u16>,
+    pub checksums: Vec<(u32, u64)>,
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        writer.put_bytes(&MOVIE_MAGIC);
+        writer.put_u16(MOVIE_VERSION);
+        writer.put_bytes(&self.rom_hash);
+        writer.put_u64(self.seed);
+        writer.put_u8(quirks_to_bits(&self.quirks));
+        writer.put_u8(policy_to_u8(self.unknown_opcode_policy));
+        writer.put_u32(self.instructions_per_frame);
+
+        writer.put_u32(self.frames.len() as u32);
+        for keys in &self.frames {
+            writer.put_u16(*keys);
+        }
+
+        writer.put_u32(self.checksums.len() as u32);
+        for (frame, checksum) in &self.checksums {
+            writer.put_u32(*frame);
+            writer.put_u64(*checksum);
+        }
+
+        writer.buffer
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut reader = StateReader::new(data);
+
+        if reader.get_array::<4>().ok() != Some(MOVIE_MAGIC) {
+            return Err(MovieError::NotAMovie);
+        }
+
+        let version = reader.get_u16()?;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let rom_hash = reader.get_array()?;
+        let seed = reader.get_u64()?;
+        let quirks = quirks_from_bits(reader.get_u8()?);
+        let unknown_opcode_policy = policy_from_u8(reader.get_u8()?)?;
+        let instructions_per_frame = reader.get_u32()?;
+
+        let frame_count = reader.get_u32()?;
+        let mut frames = Vec::new();
+        for _ in 0..frame_count {
+            frames.push(reader.get_u16()?);
+        }
+
+        let checksum_count = reader.get_u32()?;
+        let mut checksums = Vec::new();
+        for _ in 0..checksum_count {
+            checksums.push((reader.get_u32()?, reader.get_u64()?));
+        }
+
+        Ok(Movie {
+            rom_hash,
+            seed,
+            quirks,
+            unknown_opcode_policy,
+            instructions_per_frame,
+            frames,
+            checksums,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        fs::write(path, self.to_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        Movie::from_bytes(&fs::read(path)?)
+    }
+}
+
+/// Records a movie while playing. The machine must have just been reset
+/// with `Rng::new(seed)` and the ROM loaded.
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    pub fn new(chip8: &Chip8, seed: u64, instructions_per_frame: u32) -> Self {
+        MovieRecorder {
+            movie: Movie {
+                rom_hash: chip8.rom_hash,
+                seed,
+                quirks: chip8.quirks,
+                unknown_opcode_policy: chip8.unknown_opcode_policy,
+                instructions_per_frame,
+                frames: Vec::new(),
+                checksums: Vec::new(),
+            },
+        }
+    }
+
+    /// Call right before `run_frame`, with the keypad it will see.
+    pub fn record_input(&mut self, chip8: &Chip8) {
+        self.movie.frames.push(keypad_to_bits(&chip8.keypad));
+    }
+
+    /// Call right after `run_frame`.
+    pub fn record_state(&mut self, chip8: &Chip8) {
+        let frame = self.movie.frames.len() as u32;
+
+        if frame.is_multiple_of(CHECKSUM_INTERVAL) {
+            self.movie.checksums.push((frame, state_checksum(chip8)));
+        }
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Drives the keypad from a movie and checks the machine stays in sync.
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    next_checksum: usize,
+}
+
+impl MoviePlayer {
+    /// Sets up `chip8` (seed, quirks and unknown opcode policy) to match
+    /// the recording. The ROM must already be loaded.
+    pub fn new(movie: Movie, chip8: &mut Chip8) -> Result<Self, MovieError> {
+        if movie.rom_hash != chip8.rom_hash {
+            return Err(MovieError::RomMismatch);
+        }
+
+        chip8.rng = Rng::new(movie.seed);
+        chip8.quirks = movie.quirks;
+        chip8.unknown_opcode_policy = movie.unknown_opcode_policy;
+
+        Ok(MoviePlayer {
+            movie,
+            frame: 0,
+            next_checksum: 0,
+        })
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.movie.instructions_per_frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    /// Call right before `run_frame`. Returns false once the movie is over.
+    pub fn play_input(&mut self, chip8: &mut Chip8) -> bool {
+        let Some(keys) = self.movie.frames.get(self.frame) else {
+            return false;
+        };
+
+        chip8.keypad = keypad_from_bits(*keys);
+        self.frame += 1;
+
+        true
+    }
+
+    /// Call right after `run_frame`.
+    pub fn check_state(&mut self, chip8: &Chip8) -> Result<(), MovieError> {
+        let frame = self.frame as u32;
+
+        while let Some(&(checksum_frame, checksum)) = self.movie.checksums.get(self.next_checksum) {
+            if checksum_frame > frame {
+                break;
+            }
+            self.next_checksum += 1;
+
+            if checksum_frame == frame && checksum != state_checksum(chip8) {
+                return Err(MovieError::Desync { frame });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PONG: &[u8] = include_bytes!("../Pong.ch8");
+    const FRAMES: usize = 200;
+    const INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+    fn pong(seed: u64) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.rng = Rng::new(seed);
+        chip8.load_program(PONG).unwrap();
+        chip8
+    }
+
+    // Plays Pong holding down key 1 every few frames
+    fn record() -> Movie {
+        let mut chip8 = pong(3);
+        chip8.quirks = Quirks::COSMAC_VIP;
+        chip8.unknown_opcode_policy = UnknownOpcodePolicy::Halt;
+        let mut recorder = MovieRecorder::new(&chip8, 3, INSTRUCTIONS_PER_FRAME);
+
+        for frame in 0..FRAMES {
+            chip8.keypad[1] = (frame % 20 < 10) as u8;
+            recorder.record_input(&chip8);
+            chip8.run_frame(INSTRUCTIONS_PER_FRAME).unwrap();
+            recorder.record_state(&chip8);
+        }
+
+        recorder.finish()
+    }
+
+    // Plays `movie` back, failing at the first desync
+    fn play(movie: Movie, chip8: &mut Chip8) -> Result<(), MovieError> {
+        let mut player = MoviePlayer::new(movie, chip8)?;
+
+        while player.play_input(chip8) {
+            chip8.run_frame(player.instructions_per_frame()).unwrap();
+            player.check_state(chip8)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips() {
+        let movie = record();
+
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+    }
+
+    #[test]
+    fn plays_back_in_sync() {
+        let movie = record();
+        assert_eq!(movie.frames.len(), FRAMES);
+        assert!(movie.checksums.len() > 1);
+
+        // Seed, quirks and policy all come from the movie
+        let mut chip8 = pong(99);
+        play(movie, &mut chip8).unwrap();
+
+        assert_eq!(chip8.quirks, Quirks::COSMAC_VIP);
+        assert_eq!(chip8.unknown_opcode_policy, UnknownOpcodePolicy::Halt);
+    }
+
+    #[test]
+    fn detects_a_desync() {
+        let mut movie = record();
+        // Never pressing the key changes the game from the first press on
+        movie.frames.fill(0);
+
+        assert!(matches!(
+            play(movie, &mut pong(0)),
+            Err(MovieError::Desync { frame }) if frame % CHECKSUM_INTERVAL == 0
+        ));
+    }
+
+    #[test]
+    fn rejects_another_rom() {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&[0x12, 0x00]).unwrap();
+
+        assert!(matches!(
+            MoviePlayer::new(record(), &mut chip8),
+            Err(MovieError::RomMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_bad_header() {
+        let mut data = record().to_bytes();
+        // After the magic, version, hash, seed and quirks
+        data[4 + 2 + 20 + 8 + 1] = 7;
+
+        assert!(matches!(
+            Movie::from_bytes(&data),
+            Err(MovieError::Corrupt(_))
+        ));
+        assert!(matches!(
+            Movie::from_bytes(&data[..10]),
+            Err(MovieError::Truncated)
+        ));
+        assert!(matches!(
+            Movie::from_bytes(b"C8SS"),
+            Err(MovieError::NotAMovie)
+        ));
+    }
+}
@@ -0,0 +1,211 @@
+//! Colours for the display, applied only when rendering.
+//!
+//! The machine stores the bitplanes of each pixel (0 to 3) in `display`.
+//! A palette maps each of those values to an RGBA8888 colour.
+
+/// Colours for unlit, plane 1, plane 2 and both planes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub name: &'static str,
+    pub colors: [u32; 4],
+}
+
+impl Palette {
+    /// White on black.
+    pub const CLASSIC: Palette = Palette {
+        name: "classic",
+        colors: [0x000000FF, 0xFFFFFFFF, 0xAAAAAAFF, 0x555555FF],
+    };
+
+    /// Amber monochrome monitor.
+    pub const AMBER: Palette = Palette {
+        name: "amber",
+        colors: [0x1A0F00FF, 0xFFB000FF, 0xB37A00FF, 0x664600FF],
+    };
+
+    /// Green phosphor CRT.
+    pub const GREEN_PHOSPHOR: Palette = Palette {
+        name: "green",
+        colors: [0x001100FF, 0x33FF33FF, 0x22AA22FF, 0x116611FF],
+    };
+
+    /// Handheld LCD, dark pixels on a light green background.
+    pub const LCD: Palette = Palette {
+        name: "lcd",
+        colors: [0x9BBC0FFF, 0x0F380FFF, 0x306230FF, 0x8BAC0FFF],
+    };
+
+    /// Saturated colours that keep the XO-CHIP planes apart.
+    pub const HIGH_CONTRAST: Palette = Palette {
+        name: "high-contrast",
+        colors: [0x000000FF, 0xFFFFFFFF, 0xFFFF00FF, 0x00FFFFFF],
+    };
+
+    pub const PRESETS: [Palette; 5] = [
+        Palette::CLASSIC,
+        Palette::AMBER,
+        Palette::GREEN_PHOSPHOR,
+        Palette::LCD,
+        Palette::HIGH_CONTRAST,
+    ];
+
+    /// Names accepted by [`Palette::from_name`].
+    pub const PRESET_NAMES: [&'static str; 5] =
+        ["classic", "amber", "green", "lcd", "high-contrast"];
+
+    /// Looks up a preset by name (case insensitive).
+    pub fn from_name(name: &str) -> Option<Palette> {
+        let name = name.to_ascii_lowercase();
+        Palette::PRESETS
+            .into_iter()
+            .find(|palette| palette.name == name)
+    }
+
+    /// Parses `background,foreground[,plane2,both]` as `RRGGBB` hex
+    /// colours, `#` optional. Without the last two, the XO-CHIP plane
+    /// colours are blended from the first two.
+    pub fn parse(value: &str) -> Result<Palette, String> {
+        let colors = value
+            .split(',')
+            .map(parse_color)
+            .collect::<Result<Vec<u32>, String>>()?;
+
+        let colors = match colors.as_slice() {
+            [background, foreground] => [
+                *background,
+                *foreground,
+                blend(*background, *foreground, 2, 3),
+                blend(*background, *foreground, 1, 3),
+            ],
+            [background, foreground, plane2, both] => [*background, *foreground, *plane2, *both],
+            _ => return Err(format!("Expected 2 or 4 colours: {}", value)),
+        };
+
+        Ok(Palette {
+            name: "custom",
+            colors,
+        })
+    }
+
+    /// The palettes F7 steps through: this one first, then the presets
+    /// it isn't, in preset order.
+    pub fn cycle(self) -> Vec<Palette> {
+        let mut palettes = vec![self];
+        palettes.extend(
+            Palette::PRESETS
+                .into_iter()
+                .filter(|preset| *preset != self),
+        );
+        palettes
+    }
+
+    /// The RGBA8888 colour of a `display` value.
+    pub fn color(&self, pixel: u8) -> u32 {
+        self.colors[(pixel & 0x3) as usize]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::CLASSIC
+    }
+}
+
+// `RRGGBB` or `#RRGGBB`, as opaque RGBA8888
+fn parse_color(value: &str) -> Result<u32, String> {
+    let hex = value.trim();
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+
+    // from_str_radix alone would also take a sign
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Expected a RRGGBB colour: {}", value));
+    }
+
+    Ok(u32::from_str_radix(hex, 16).unwrap() << 8 | 0xFF)
+}
+
+// `numerator / denominator` of the way from `from` to `to`, per channel
+fn blend(from: u32, to: u32, numerator: u32, denominator: u32) -> u32 {
+    let from = from.to_be_bytes();
+    let to = to.to_be_bytes();
+    let mut mixed = [0xFF; 4];
+
+    for channel in 0..3 {
+        let (a, b) = (from[channel] as u32, to[channel] as u32);
+        mixed[channel] = ((a * (denominator - numerator) + b * numerator) / denominator) as u8;
+    }
+
+    u32::from_be_bytes(mixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_presets_by_name() {
+        for (preset, name) in Palette::PRESETS.iter().zip(Palette::PRESET_NAMES) {
+            assert_eq!(Palette::from_name(name), Some(*preset));
+        }
+        assert_eq!(Palette::from_name("Amber"), Some(Palette::AMBER));
+        assert_eq!(Palette::from_name("sepia"), None);
+    }
+
+    #[test]
+    fn parses_four_custom_colours() {
+        let palette = Palette::parse("#102030,405060, #708090,A0b0C0").unwrap();
+
+        assert_eq!(palette.name, "custom");
+        assert_eq!(
+            palette.colors,
+            [0x102030FF, 0x405060FF, 0x708090FF, 0xA0B0C0FF]
+        );
+    }
+
+    #[test]
+    fn blends_the_plane_colours_from_two() {
+        let palette = Palette::parse("#000000,#FF9930").unwrap();
+
+        assert_eq!(
+            palette.colors,
+            [0x000000FF, 0xFF9930FF, 0xAA6620FF, 0x553310FF]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_colours() {
+        assert_eq!(
+            Palette::parse("#000000"),
+            Err("Expected 2 or 4 colours: #000000".to_string())
+        );
+        assert_eq!(
+            Palette::parse("000000,111111,222222"),
+            Err("Expected 2 or 4 colours: 000000,111111,222222".to_string())
+        );
+        for bad in ["#FFF", "#FFFFFFFF", "GGGGGG", "##FFFFFF", "", "+12345"] {
+            assert_eq!(
+                Palette::parse(&format!("000000,{}", bad)),
+                Err(format!("Expected a RRGGBB colour: {}", bad))
+            );
+        }
+    }
+
+    #[test]
+    fn cycles_from_the_chosen_palette_through_the_other_presets() {
+        let names = |palettes: Vec<Palette>| -> Vec<&str> {
+            palettes.iter().map(|palette| palette.name).collect()
+        };
+
+        assert_eq!(names(Palette::CLASSIC.cycle()), Palette::PRESET_NAMES);
+        assert_eq!(
+            names(Palette::LCD.cycle()),
+            ["lcd", "classic", "amber", "green", "high-contrast"]
+        );
+
+        let custom = Palette::parse("123456,654321").unwrap();
+        let cycle = custom.cycle();
+        assert_eq!(cycle.len(), Palette::PRESETS.len() + 1);
+        assert_eq!(cycle[0], custom);
+        assert_eq!(cycle[1..], Palette::PRESETS);
+    }
+}
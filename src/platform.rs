@@ -1,34 +1,136 @@
-use sdl2::{EventPump, event::Event, keyboard::Keycode, render::{Texture, WindowCanvas}};
+use chip_8::Palette;
+use chip_8::audio::{AudioPattern, Beeper, BeeperSettings, DEFAULT_SAMPLE_RATE};
+use sdl2::{
+    EventPump,
+    audio::{AudioCallback, AudioDevice, AudioSpecDesired},
+    event::{Event, WindowEvent},
+    keyboard::{Keycode, Mod},
+    rect::Rect,
+    render::{Texture, WindowCanvas},
+};
+use std::ops::Range;
+
+/// Frontend actions requested through hotkeys.
+pub enum Command {
+    Quit,
+    SaveState(u8),
+    LoadState(u8),
+    // Backspace held down / released
+    StartRewind,
+    StopRewind,
+    Screenshot,
+    ToggleMute,
+    NextPalette,
+    // The window was uncovered or resized, its contents have to be drawn again
+    Redraw,
+}
 
 pub struct Platform {
     pub canvas: WindowCanvas,
     pub event_pump: EventPump,
+    // None when no audio device could be opened
+    pub audio: Option<AudioDevice<BeeperCallback>>,
+    /// Host key for each CHIP-8 key
+    pub keys: [Keycode; 16],
+}
+
+pub struct BeeperCallback(pub Beeper);
+
+impl AudioCallback for BeeperCallback {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.0.fill(out);
+    }
+}
+
+/// Host key for each CHIP-8 key, indexed by CHIP-8 key.
+pub const DEFAULT_KEYS: [Keycode; 16] = [
+    Keycode::X,
+    Keycode::Num1,
+    Keycode::Num2,
+    Keycode::Num3,
+    Keycode::Q,
+    Keycode::W,
+    Keycode::E,
+    Keycode::A,
+    Keycode::S,
+    Keycode::D,
+    Keycode::Z,
+    Keycode::C,
+    Keycode::Num4,
+    Keycode::R,
+    Keycode::F,
+    Keycode::V,
+];
+
+/// The default keys with some replaced by SDL key names, e.g. from the
+/// config file.
+pub fn key_map(names: &[Option<String>; 16]) -> Result<[Keycode; 16], String> {
+    let mut keys = DEFAULT_KEYS;
+
+    for (key, name) in keys.iter_mut().zip(names) {
+        if let Some(name) = name {
+            *key = Keycode::from_name(name).ok_or_else(|| format!("Unknown key: {}", name))?;
+        }
+    }
+
+    Ok(keys)
+}
+
+pub fn display_to_rgba(display: &[u8], palette: &Palette) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(display.len() * 4);
+
+    for pixel in display {
+        let color = palette.color(*pixel);
+        buffer.extend_from_slice(&color.to_ne_bytes());
+    }
+
+    buffer
 }
 
-pub fn key_map(key: Keycode) -> Option<usize> {
+fn open_audio(
+    sdl_context: &sdl2::Sdl,
+    beeper: BeeperSettings,
+    muted: bool,
+) -> Result<AudioDevice<BeeperCallback>, String> {
+    let audio_subsystem = sdl_context.audio()?;
+    let desired = AudioSpecDesired {
+        freq: Some(DEFAULT_SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: Some(512),
+    };
+
+    let device = audio_subsystem.open_playback(None, &desired, |spec| {
+        let mut callback = BeeperCallback(Beeper::new(beeper, spec.freq as u32));
+        callback.0.muted = muted;
+        callback
+    })?;
+    device.resume();
+
+    Ok(device)
+}
+
+// F1..F4 load save state slots 1..4, with Shift held they save instead
+fn state_slot(key: Keycode) -> Option<u8> {
     match key {
-        Keycode::X => Some(0x0),
-        Keycode::Num1 => Some(0x1),
-        Keycode::Num2 => Some(0x2),
-        Keycode::Num3 => Some(0x3),
-        Keycode::Q => Some(0x4),
-        Keycode::W => Some(0x5),
-        Keycode::E => Some(0x6),
-        Keycode::A => Some(0x7),
-        Keycode::S => Some(0x8),
-        Keycode::D => Some(0x9),
-        Keycode::Z => Some(0xA),
-        Keycode::C => Some(0xB),
-        Keycode::Num4 => Some(0xC),
-        Keycode::R => Some(0xD),
-        Keycode::F => Some(0xE),
-        Keycode::V => Some(0xF),
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
         _ => None,
     }
 }
 
 impl Platform {
-    pub fn new(title: &str, window_width: u32, window_height: u32) -> Result<Self, String> {
+    pub fn new(
+        title: &str,
+        window_width: u32,
+        window_height: u32,
+        beeper: BeeperSettings,
+        muted: bool,
+        keys: [Keycode; 16],
+    ) -> Result<Self, String> {
         let sdl_context = sdl2::init()?;
         let video_subsystem = sdl_context.video()?;
 
@@ -47,28 +149,65 @@ impl Platform {
 
         let event_pump = sdl_context.event_pump()?;
 
-        Ok(Platform { canvas, event_pump })
+        // Running without sound beats not running at all
+        let audio = match open_audio(&sdl_context, beeper, muted) {
+            Ok(device) => Some(device),
+            Err(e) => {
+                eprintln!("Audio disabled: {}", e);
+                None
+            }
+        };
+
+        Ok(Platform {
+            canvas,
+            event_pump,
+            audio,
+            keys,
+        })
+    }
+
+    pub fn set_beeping(&mut self, active: bool, pattern: Option<AudioPattern>) {
+        if let Some(device) = self.audio.as_mut() {
+            let mut callback = device.lock();
+            callback.0.active = active;
+            callback.0.pattern = pattern;
+        }
+    }
+
+    /// Returns whether the beeper is muted now.
+    pub fn toggle_mute(&mut self) -> bool {
+        match self.audio.as_mut() {
+            Some(device) => {
+                let mut callback = device.lock();
+                callback.0.muted = !callback.0.muted;
+                callback.0.muted
+            }
+            None => true,
+        }
     }
 
+    /// Uploads `buffer`, holding the texture `rows`, and presents.
     pub fn update(
         &mut self,
         texture: &mut Texture,
         buffer: &[u8],
         pitch: usize,
+        rows: Range<usize>,
     ) -> Result<(), String> {
+        let area = Rect::new(0, rows.start as i32, (pitch / 4) as u32, rows.len() as u32);
         texture
-            .update(None, buffer, pitch)
+            .update(area, buffer, pitch)
             .map_err(|e| e.to_string())?;
 
         self.canvas.clear();
-        self.canvas.copy(&texture, None, None)?;
+        self.canvas.copy(texture, None, None)?;
         self.canvas.present();
 
         Ok(())
     }
 
-    pub fn process_input(&mut self, keys: &mut [u8; 16]) -> bool {
-        let mut quit = false;
+    pub fn process_input(&mut self, keys: &mut [u8; 16]) -> Vec<Command> {
+        let mut commands = Vec::new();
 
         for event in self.event_pump.poll_iter() {
             match event {
@@ -77,25 +216,62 @@ impl Platform {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => {
-                    quit = true;
+                    commands.push(Command::Quit);
                 }
                 Event::KeyDown {
-                    keycode: Some(key), ..
+                    keycode: Some(key),
+                    keymod,
+                    repeat,
+                    ..
                 } => {
-                    if let Some(idx) = key_map(key) {
+                    if key == Keycode::Backspace {
+                        if !repeat {
+                            commands.push(Command::StartRewind);
+                        }
+                    } else if key == Keycode::F12 {
+                        if !repeat {
+                            commands.push(Command::Screenshot);
+                        }
+                    } else if key == Keycode::F7 {
+                        if !repeat {
+                            commands.push(Command::NextPalette);
+                        }
+                    } else if key == Keycode::F8 {
+                        if !repeat {
+                            commands.push(Command::ToggleMute);
+                        }
+                    } else if let Some(slot) = state_slot(key) {
+                        // Holding the key down must not save or load again
+                        if !repeat {
+                            if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                                commands.push(Command::SaveState(slot));
+                            } else {
+                                commands.push(Command::LoadState(slot));
+                            }
+                        }
+                    } else if let Some(idx) = self.keys.iter().position(|mapped| *mapped == key) {
                         keys[idx] = 1;
                     }
                 }
                 Event::KeyUp {
                     keycode: Some(key), ..
                 } => {
-                    if let Some(idx) = key_map(key) {
+                    if key == Keycode::Backspace {
+                        commands.push(Command::StopRewind);
+                    } else if let Some(idx) = self.keys.iter().position(|mapped| *mapped == key) {
                         keys[idx] = 0;
                     }
                 }
+                Event::Window {
+                    win_event:
+                        WindowEvent::Exposed | WindowEvent::Resized(..) | WindowEvent::SizeChanged(..),
+                    ..
+                } => {
+                    commands.push(Command::Redraw);
+                }
                 _ => {}
             }
         }
-        quit
+        commands
     }
 }
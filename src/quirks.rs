@@ -0,0 +1,111 @@
+/// Behaviour switches for the instructions that different CHIP-8
+/// interpreters disagree on.
+///
+/// Each flag describes the "true" side of the ambiguity; the presets below
+/// pick the combination used by the most common platforms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8xy6`/`8xyE` shift Vy and store the result in Vx, instead of
+    /// shifting Vx in place.
+    pub shift_uses_vy: bool,
+    /// What `Fx55`/`Fx65` do to `index`.
+    pub load_store: LoadStore,
+    /// `Bnnn` behaves as `Bxnn` and jumps to `xnn + Vx` instead of
+    /// `nnn + V0`.
+    pub jump_uses_vx: bool,
+    /// `8xy1`/`8xy2`/`8xy3` reset VF to 0.
+    pub vf_reset: bool,
+    /// Sprites that cross the screen edge wrap around to the other side
+    /// instead of being clipped.
+    pub sprite_wrap: bool,
+    /// `Dxyn` waits for the next vertical blank before drawing, limiting
+    /// the program to one sprite per frame.
+    pub display_wait: bool,
+}
+
+/// How `Fx55`/`Fx65` leave `index` after transferring V0 to Vx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadStore {
+    /// I is left unchanged.
+    Unchanged,
+    /// I += x, one short of the last register (CHIP-48).
+    AddX,
+    /// I += x + 1, pointing past the last register.
+    AddXPlusOne,
+}
+
+impl Quirks {
+    /// What this emulator did before the quirks could be chosen: none of
+    /// them.
+    pub const LEGACY: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store: LoadStore::Unchanged,
+        jump_uses_vx: false,
+        vf_reset: false,
+        sprite_wrap: false,
+        display_wait: false,
+    };
+
+    /// The original COSMAC VIP interpreter.
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store: LoadStore::AddXPlusOne,
+        jump_uses_vx: false,
+        vf_reset: true,
+        sprite_wrap: false,
+        display_wait: true,
+    };
+
+    /// CHIP-48 on the HP-48 calculators.
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store: LoadStore::AddX,
+        jump_uses_vx: true,
+        vf_reset: false,
+        sprite_wrap: false,
+        display_wait: false,
+    };
+
+    /// SUPER-CHIP 1.1, which fixed the CHIP-48 `Fx55`/`Fx65` increment by
+    /// dropping it.
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store: LoadStore::Unchanged,
+        jump_uses_vx: true,
+        vf_reset: false,
+        sprite_wrap: false,
+        display_wait: false,
+    };
+
+    /// Octo, and with it XO-CHIP.
+    pub const OCTO: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store: LoadStore::AddXPlusOne,
+        jump_uses_vx: false,
+        vf_reset: false,
+        sprite_wrap: true,
+        display_wait: false,
+    };
+
+    /// Names accepted by [`Quirks::from_name`].
+    pub const PRESET_NAMES: [&'static str; 5] = ["legacy", "vip", "chip48", "schip", "octo"];
+
+    /// Looks up a preset by name (case insensitive).
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name.to_ascii_lowercase().as_str() {
+            "legacy" => Some(Quirks::LEGACY),
+            "vip" | "cosmac" | "chip8" => Some(Quirks::COSMAC_VIP),
+            "chip48" => Some(Quirks::CHIP_48),
+            "schip" | "superchip" => Some(Quirks::SUPER_CHIP),
+            "octo" | "xochip" => Some(Quirks::OCTO),
+            _ => None,
+        }
+    }
+}
+
+/// [`Quirks::LEGACY`], so ROMs keep running the way they always have here.
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::LEGACY
+    }
+}
@@ -0,0 +1,305 @@
+//! Animated GIF recording of the display.
+//!
+//! Frames are captured at the 60 Hz display rate. A frame identical to the
+//! previous one only makes the previous one last longer, and a changed frame
+//! is stored as the rectangle that differs, so idle screens cost almost
+//! nothing.
+//!
+//! Palette changes are recorded too: frames drawn with another palette than
+//! the one the recording started with carry their own colour table.
+//!
+//! The GIF always has the size of the high resolution display, low
+//! resolution frames are drawn at twice the scale, as in the window.
+
+use std::io::{self, Write};
+
+use gif::{DisposalMethod, Encoder, Frame, Repeat};
+
+use crate::chip8::Chip8;
+use crate::constants::{HIRES_VIDEO_HEIGHT, HIRES_VIDEO_WIDTH, TIMER_FREQUENCY};
+use crate::palette::Palette;
+
+/// The largest scale whose frames still fit the 16-bit GIF dimensions.
+pub const MAX_GIF_SCALE: u32 = u16::MAX as u32 / HIRES_VIDEO_WIDTH as u32;
+
+// Viewers stretch delays under 2/100 s to 1/10 s, so a frame is kept for at
+// least that long and changes in between are dropped
+const MIN_FRAME_DELAY: u64 = 2;
+
+pub struct GifRecorder<W: Write> {
+    encoder: Encoder<W>,
+    scale: usize,
+    width: usize,
+    height: usize,
+    /// Used for the frames captured from now on
+    pub palette: Palette,
+    // The palette in the GIF header
+    global_palette: Palette,
+    // Last frame written to the file
+    written: Option<GifFrame>,
+    // Frame waiting for its delay to be known, and when it was captured
+    pending: Option<(GifFrame, u64)>,
+    captured_frames: u64,
+}
+
+#[derive(PartialEq)]
+struct GifFrame {
+    // Palette indices
+    pixels: Vec<u8>,
+    palette: Palette,
+}
+
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    io::Error::other(e)
+}
+
+// Time of a 60 Hz frame in hundredths of a second, rounded
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + TIMER_FREQUENCY as u64 / 2) / TIMER_FREQUENCY as u64
+}
+
+impl<W: Write> GifRecorder<W> {
+    /// Fails if `scale` is above [`MAX_GIF_SCALE`].
+    pub fn new(writer: W, scale: u32, palette: Palette) -> io::Result<Self> {
+        if scale > MAX_GIF_SCALE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("GIF scale {} is above {}", scale, MAX_GIF_SCALE),
+            ));
+        }
+
+        let scale = scale.max(1) as usize;
+        let width = HIRES_VIDEO_WIDTH as usize * scale;
+        let height = HIRES_VIDEO_HEIGHT as usize * scale;
+
+        let mut encoder = Encoder::new(writer, width as u16, height as u16, &color_table(palette))
+            .map_err(gif_error)?;
+        encoder.set_repeat(Repeat::Infinite).map_err(gif_error)?;
+
+        Ok(GifRecorder {
+            encoder,
+            scale,
+            width,
+            height,
+            palette,
+            global_palette: palette,
+            written: None,
+            pending: None,
+            captured_frames: 0,
+        })
+    }
+
+    /// Call once per 60 Hz frame.
+    pub fn capture(&mut self, chip8: &Chip8) -> io::Result<()> {
+        let frame = GifFrame {
+            pixels: self.render(chip8),
+            palette: self.palette,
+        };
+        let now = self.captured_frames;
+        self.captured_frames += 1;
+
+        match self.pending.take() {
+            Some((pending, start)) if pending == frame => self.pending = Some((pending, start)),
+            Some((_, start)) if centiseconds(now) - centiseconds(start) < MIN_FRAME_DELAY => {
+                self.pending = Some((frame, start));
+            }
+            Some((pending, start)) => {
+                self.write_frame(pending, centiseconds(now) - centiseconds(start))?;
+                self.pending = Some((frame, now));
+            }
+            None => self.pending = Some((frame, now)),
+        }
+
+        Ok(())
+    }
+
+    /// Writes the last frame and the GIF trailer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some((pending, start)) = self.pending.take() {
+            let delay = centiseconds(self.captured_frames) - centiseconds(start);
+            self.write_frame(pending, delay.max(MIN_FRAME_DELAY))?;
+        }
+
+        self.encoder.into_inner().map_err(gif_error)
+    }
+
+    // The display as palette indices at GIF resolution
+    fn render(&self, chip8: &Chip8) -> Vec<u8> {
+        let scale = self.scale * HIRES_VIDEO_WIDTH as usize / chip8.video_width();
+        let mut pixels = Vec::with_capacity(self.width * self.height);
+
+        for row in chip8.active_display().chunks(chip8.video_width()) {
+            let scaled: Vec<u8> = row
+                .iter()
+                .flat_map(|pixel| std::iter::repeat_n(*pixel & 0x3, scale))
+                .collect();
+
+            for _ in 0..scale {
+                pixels.extend_from_slice(&scaled);
+            }
+        }
+
+        pixels
+    }
+
+    fn write_frame(&mut self, gif_frame: GifFrame, delay: u64) -> io::Result<()> {
+        let pixels = &gif_frame.pixels;
+
+        // Only the rectangle that changed since the last written frame, unless
+        // the colours changed everywhere
+        let (left, top, right, bottom) = match &self.written {
+            Some(written) if written.palette == gif_frame.palette => {
+                changed_area(&written.pixels, pixels, self.width).unwrap_or((0, 0, 0, 0))
+            }
+            _ => (0, 0, self.width - 1, self.height - 1),
+        };
+
+        let width = right - left + 1;
+        let height = bottom - top + 1;
+        let mut area = Vec::with_capacity(width * height);
+        for y in top..=bottom {
+            area.extend_from_slice(&pixels[y * self.width + left..][..width]);
+        }
+
+        let mut frame = Frame::from_indexed_pixels(width as u16, height as u16, area, None);
+        frame.left = left as u16;
+        frame.top = top as u16;
+        frame.delay = delay.min(u16::MAX as u64) as u16;
+        frame.dispose = DisposalMethod::Keep;
+        if gif_frame.palette != self.global_palette {
+            frame.palette = Some(color_table(gif_frame.palette));
+        }
+
+        self.encoder.write_frame(&frame).map_err(gif_error)?;
+        self.written = Some(gif_frame);
+
+        Ok(())
+    }
+}
+
+// Palette as the `[r, g, b, ...]` table of a GIF
+fn color_table(palette: Palette) -> Vec<u8> {
+    palette
+        .colors
+        .iter()
+        .flat_map(|color| {
+            let [r, g, b, _] = color.to_be_bytes();
+            [r, g, b]
+        })
+        .collect()
+}
+
+// Bounding box (left, top, right, bottom) of the pixels that differ
+fn changed_area(old: &[u8], new: &[u8], width: usize) -> Option<(usize, usize, usize, usize)> {
+    let mut area: Option<(usize, usize, usize, usize)> = None;
+
+    for (i, (old, new)) in old.iter().zip(new).enumerate() {
+        if old != new {
+            let (x, y) = (i % width, i / width);
+            area = Some(match area {
+                Some((left, top, right, bottom)) => {
+                    (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                }
+                None => (x, y, x, y),
+            });
+        }
+    }
+
+    area
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Delay, left, top, width and height of every frame in a GIF
+    fn frames(data: &[u8]) -> Vec<(u16, u16, u16, u16, u16)> {
+        let mut decoder = gif::DecodeOptions::new().read_info(data).unwrap();
+        let mut frames = Vec::new();
+
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((
+                frame.delay,
+                frame.left,
+                frame.top,
+                frame.width,
+                frame.height,
+            ));
+        }
+
+        frames
+    }
+
+    // Captures `chip8` for `count` frames
+    fn capture(recorder: &mut GifRecorder<Vec<u8>>, chip8: &Chip8, count: usize) {
+        for _ in 0..count {
+            recorder.capture(chip8).unwrap();
+        }
+    }
+
+    #[test]
+    fn stores_how_long_each_frame_lasted() {
+        let mut chip8 = Chip8::new();
+        let mut recorder = GifRecorder::new(Vec::new(), 1, Palette::CLASSIC).unwrap();
+
+        capture(&mut recorder, &chip8, 10);
+        chip8.display.draw_row(0, 0, 1 << 127);
+        capture(&mut recorder, &chip8, 5);
+
+        let data = recorder.finish().unwrap();
+        // 10/60 s is 17/100, 15/60 s is 25/100
+        assert_eq!(frames(&data), [(17, 0, 0, 128, 64), (8, 0, 0, 2, 2)]);
+    }
+
+    #[test]
+    fn drops_frames_shorter_than_the_minimum_delay() {
+        let mut chip8 = Chip8::new();
+        let mut recorder = GifRecorder::new(Vec::new(), 1, Palette::CLASSIC).unwrap();
+
+        capture(&mut recorder, &chip8, 1);
+        chip8.display.draw_row(0, 0, 1 << 127);
+        capture(&mut recorder, &chip8, 1);
+        chip8.display.draw_row(0, 0, 1 << 127);
+        capture(&mut recorder, &chip8, 3);
+
+        let data = recorder.finish().unwrap();
+        // The lit frame only lasted 1/100 s and is replaced by the blank
+        // one, which leaves nothing changed but a single pixel
+        assert_eq!(frames(&data), [(2, 0, 0, 128, 64), (6, 0, 0, 1, 1)]);
+    }
+
+    #[test]
+    fn stores_only_the_changed_rectangle() {
+        let mut chip8 = Chip8::new();
+        let mut recorder = GifRecorder::new(Vec::new(), 2, Palette::CLASSIC).unwrap();
+
+        capture(&mut recorder, &chip8, 6);
+        // Two low resolution pixels, each 4x4 at this scale
+        chip8.display.draw_row(0, 2, 1 << (127 - 3));
+        chip8.display.draw_row(0, 5, 1 << (127 - 10));
+        capture(&mut recorder, &chip8, 6);
+        // A new palette redraws the whole frame
+        recorder.palette = Palette::AMBER;
+        capture(&mut recorder, &chip8, 6);
+
+        let data = recorder.finish().unwrap();
+        assert_eq!(
+            frames(&data),
+            [
+                (10, 0, 0, 256, 128),
+                (10, 12, 8, 32, 16),
+                (10, 0, 0, 256, 128)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_scales_past_the_gif_size_limit() {
+        assert!(GifRecorder::new(Vec::new(), MAX_GIF_SCALE, Palette::CLASSIC).is_ok());
+
+        let error = GifRecorder::new(Vec::new(), MAX_GIF_SCALE + 1, Palette::CLASSIC)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::VecDeque;
+
+use crate::chip8::Chip8;
+use crate::savestate::{StateReader, StateWriter, read_machine, write_machine};
+
+/// Bounded history of machine states for rewinding.
+///
+/// Only the newest snapshot is stored whole. Every older frame is kept as
+/// the run-length encoded XOR of itself and the frame after it, which is
+/// almost all zeros since a frame rarely touches more than a few bytes of
+/// memory and display.
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    // Oldest first, `deltas.back()` turns `latest` into the frame before it
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /// Creates a buffer able to step back `capacity` frames.
+    pub fn new(capacity: usize) -> Self {
+        RewindBuffer {
+            capacity,
+            latest: None,
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records the state at the end of a frame.
+    pub fn push(&mut self, chip8: &Chip8) {
+        let mut writer = StateWriter::new();
+        write_machine(chip8, &mut writer);
+        let snapshot = writer.buffer;
+
+        if let Some(latest) = &self.latest {
+            if self.capacity == 0 {
+                return;
+            }
+
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(encode_delta(latest, &snapshot));
+        }
+
+        self.latest = Some(snapshot);
+    }
+
+    /// Steps `chip8` back one frame. Returns false once the history is used
+    /// up. The keypad is left alone so keys held during the rewind don't
+    /// get stuck.
+    pub fn rewind(&mut self, chip8: &mut Chip8) -> bool {
+        let (Some(latest), Some(delta)) = (self.latest.as_mut(), self.deltas.pop_back()) else {
+            return false;
+        };
+
+        apply_delta(latest, &delta);
+
+        let keypad = chip8.keypad;
+        let mut restored = chip8.clone();
+        if read_machine(&mut restored, &mut StateReader::new(latest)).is_err() {
+            return false;
+        }
+        *chip8 = restored;
+        chip8.keypad = keypad;
+
+        true
+    }
+
+    /// Number of frames that can be rewound.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    /// Bytes held by the snapshots, for reporting.
+    pub fn memory_usage(&self) -> usize {
+        let latest = self.latest.as_ref().map_or(0, Vec::len);
+        latest + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+// Encodes `a ^ b` as (zero run, literal length, literal bytes) groups,
+// both lengths as LEB128 varints.
+fn encode_delta(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+
+    while i < a.len() {
+        let zeros_start = i;
+        while i < a.len() && a[i] == b[i] {
+            i += 1;
+        }
+
+        let literal_start = i;
+        while i < a.len() && a[i] != b[i] {
+            i += 1;
+        }
+
+        write_varint(&mut encoded, literal_start - zeros_start);
+        write_varint(&mut encoded, i - literal_start);
+        encoded.extend((literal_start..i).map(|j| a[j] ^ b[j]));
+    }
+
+    encoded
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut position = 0;
+    let mut i = 0;
+
+    while i < delta.len() {
+        let zeros = read_varint(delta, &mut i);
+        let literals = read_varint(delta, &mut i);
+
+        position += zeros;
+        for byte in &mut state[position..position + literals] {
+            *byte ^= delta[i];
+            i += 1;
+        }
+        position += literals;
+    }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(buffer: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = buffer[*position];
+        *position += 1;
+
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    const INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+    fn machine() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.rng = Rng::new(1);
+        chip8.load_program(include_bytes!("../Pong.ch8")).unwrap();
+        chip8
+    }
+
+    // Runs `frames` frames, pushing each one. Returns the state after every
+    // frame, oldest first.
+    fn record(chip8: &mut Chip8, buffer: &mut RewindBuffer, frames: usize) -> Vec<Vec<u8>> {
+        let mut states = Vec::new();
+
+        for _ in 0..frames {
+            chip8.run_frame(INSTRUCTIONS_PER_FRAME).unwrap();
+            buffer.push(chip8);
+            states.push(chip8.save_state());
+        }
+
+        states
+    }
+
+    #[test]
+    fn rewinds_to_each_recorded_frame() {
+        let mut chip8 = machine();
+        let mut buffer = RewindBuffer::new(100);
+        let states = record(&mut chip8, &mut buffer, 50);
+        assert_eq!(buffer.len(), 49);
+        assert!(states[0] != states[49]);
+
+        for expected in states.iter().rev().skip(1) {
+            assert!(buffer.rewind(&mut chip8));
+            assert!(chip8.save_state() == *expected);
+        }
+
+        assert!(!buffer.rewind(&mut chip8));
+        assert!(chip8.save_state() == states[0]);
+    }
+
+    #[test]
+    fn keeps_only_the_newest_frames() {
+        let mut chip8 = machine();
+        let mut buffer = RewindBuffer::new(10);
+        let states = record(&mut chip8, &mut buffer, 50);
+        assert_eq!(buffer.len(), 10);
+
+        for expected in states[39..49].iter().rev() {
+            assert!(buffer.rewind(&mut chip8));
+            assert!(chip8.save_state() == *expected);
+        }
+
+        // Past the limit the machine is left on the oldest frame kept
+        assert!(!buffer.rewind(&mut chip8));
+        assert!(chip8.save_state() == states[39]);
+    }
+
+    #[test]
+    fn recording_after_a_rewind_continues_from_there() {
+        let mut chip8 = machine();
+        let mut buffer = RewindBuffer::new(100);
+        let states = record(&mut chip8, &mut buffer, 20);
+
+        for _ in 0..5 {
+            buffer.rewind(&mut chip8);
+        }
+        record(&mut chip8, &mut buffer, 1);
+        assert_eq!(buffer.len(), 15);
+
+        assert!(buffer.rewind(&mut chip8));
+        assert!(chip8.save_state() == states[14]);
+    }
+
+    #[test]
+    fn rewind_keeps_the_keypad() {
+        let mut chip8 = machine();
+        let mut buffer = RewindBuffer::new(10);
+        record(&mut chip8, &mut buffer, 3);
+
+        chip8.keypad[4] = 1;
+        assert!(buffer.rewind(&mut chip8));
+
+        assert_eq!(chip8.keypad[4], 1);
+    }
+
+    #[test]
+    fn delta_round_trips() {
+        let a = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut b = a.clone();
+        b[0] = 0xFF;
+        b[5] = 0x10;
+        b[6] = 0x11;
+
+        let delta = encode_delta(&a, &b);
+        let mut restored = b.clone();
+        apply_delta(&mut restored, &delta);
+
+        assert_eq!(restored, a);
+    }
+}
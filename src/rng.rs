@@ -0,0 +1,106 @@
+/// Random source for `Cxkk`.
+///
+/// A xorshift64* generator: the whole state is one `u64`, so it can be
+/// seeded from the command line, saved with the rest of the machine and
+/// restored to replay a run exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Spread the seed with splitmix64 so that small seeds (0, 1, 2...)
+        // still give unrelated sequences, and the state is never zero.
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        Rng::from_state(z)
+    }
+
+    /// Seeds from the operating system, for runs that don't need to be
+    /// reproducible.
+    pub fn from_entropy() -> Self {
+        Rng::new(rand::random())
+    }
+
+    /// Restores a generator saved with [`Rng::state`].
+    pub fn from_state(state: u64) -> Self {
+        Rng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+
+    // RND V0, 0xFF then JP 0x200
+    const RANDOM_LOOP: [u8; 4] = [0xC0, 0xFF, 0x12, 0x00];
+
+    fn machine(seed: u64) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.rng = Rng::new(seed);
+        chip8.load_program(&RANDOM_LOOP).unwrap();
+        chip8
+    }
+
+    // The next `count` values of V0
+    fn random_bytes(chip8: &mut Chip8, count: usize) -> Vec<u8> {
+        (0..count)
+            .map(|_| {
+                chip8.step().unwrap();
+                chip8.step().unwrap();
+                chip8.registers[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_results() {
+        let expected = random_bytes(&mut machine(42), 100);
+
+        assert_eq!(random_bytes(&mut machine(42), 100), expected);
+        assert_ne!(random_bytes(&mut machine(43), 100), expected);
+    }
+
+    #[test]
+    fn restoring_a_state_continues_the_sequence() {
+        let mut chip8 = machine(7);
+        random_bytes(&mut chip8, 10);
+        let state = chip8.save_state();
+        let expected = random_bytes(&mut chip8, 100);
+
+        let mut restored = machine(0);
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(random_bytes(&mut restored, 100), expected);
+    }
+
+    #[test]
+    fn zero_state_is_never_used() {
+        let mut rng = Rng::from_state(0);
+
+        assert_eq!(rng.state(), 1);
+        assert_ne!(rng.next_u64(), 0);
+    }
+}
@@ -0,0 +1,404 @@
+//! Save states: a snapshot of the whole machine in a versioned binary format.
+//!
+//! Layout (all integers little endian):
+//!
+//! ```text
+//! magic      4 bytes  "C8SS"
+//! version    u16
+//! rom hash   20 bytes SHA-1 of the ROM the state was taken from
+//! machine    see `write_machine`
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::chip8::Chip8;
+use crate::constants::{MEMORY_SIZE, STACK_SIZE};
+use crate::framebuffer::FrameBuffer;
+use crate::quirks::{LoadStore, Quirks};
+use crate::rng::Rng;
+
+pub const SAVE_STATE_MAGIC: [u8; 4] = *b"C8SS";
+pub const SAVE_STATE_VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    /// The data doesn't start with [`SAVE_STATE_MAGIC`].
+    NotASaveState,
+    /// The state was written by a different version of the format.
+    UnsupportedVersion(u16),
+    /// The state belongs to another ROM.
+    RomMismatch,
+    /// The data ends before the machine state is complete.
+    Truncated,
+    /// A value in the machine state is out of range.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::Io(e) => write!(f, "{}", e),
+            SaveStateError::NotASaveState => write!(f, "not a save state file"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} is not supported (expected {})",
+                version, SAVE_STATE_VERSION
+            ),
+            SaveStateError::RomMismatch => write!(f, "save state was made with a different ROM"),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::Corrupt(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SaveStateError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> Self {
+        SaveStateError::Io(e)
+    }
+}
+
+pub(crate) struct StateWriter {
+    pub(crate) buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> Self {
+        StateWriter { buffer: Vec::new() }
+    }
+
+    pub(crate) fn put_u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    pub(crate) fn put_bool(&mut self, value: bool) {
+        self.buffer.push(value as u8);
+    }
+
+    pub(crate) fn put_u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn put_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn put_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn put_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        StateReader { data, position: 0 }
+    }
+
+    pub(crate) fn get_bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        let end = self.position + len;
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or(SaveStateError::Truncated)?;
+        self.position = end;
+
+        Ok(bytes)
+    }
+
+    pub(crate) fn get_array<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.get_bytes(N)?);
+
+        Ok(array)
+    }
+
+    pub(crate) fn get_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+    pub(crate) fn get_bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.get_u8()? != 0)
+    }
+
+    pub(crate) fn get_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.get_array()?))
+    }
+
+    pub(crate) fn get_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.get_array()?))
+    }
+
+    pub(crate) fn get_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.get_array()?))
+    }
+}
+
+// Bit 1 is I += x + 1, as when the quirk was a flag, and bit 6 is I += x
+pub(crate) fn quirks_to_bits(quirks: &Quirks) -> u8 {
+    (quirks.shift_uses_vy as u8)
+        | ((quirks.load_store == LoadStore::AddXPlusOne) as u8) << 1
+        | (quirks.jump_uses_vx as u8) << 2
+        | (quirks.vf_reset as u8) << 3
+        | (quirks.sprite_wrap as u8) << 4
+        | (quirks.display_wait as u8) << 5
+        | ((quirks.load_store == LoadStore::AddX) as u8) << 6
+}
+
+pub(crate) fn quirks_from_bits(bits: u8) -> Quirks {
+    Quirks {
+        shift_uses_vy: bits & 0x01 != 0,
+        load_store: if bits & 0x02 != 0 {
+            LoadStore::AddXPlusOne
+        } else if bits & 0x40 != 0 {
+            LoadStore::AddX
+        } else {
+            LoadStore::Unchanged
+        },
+        jump_uses_vx: bits & 0x04 != 0,
+        vf_reset: bits & 0x08 != 0,
+        sprite_wrap: bits & 0x10 != 0,
+        display_wait: bits & 0x20 != 0,
+    }
+}
+
+// The machine state without the header, shared with the rewind buffer
+pub(crate) fn write_machine(chip8: &Chip8, writer: &mut StateWriter) {
+    writer.put_bytes(&chip8.registers);
+    writer.put_bytes(&chip8.memory);
+    writer.put_u32(chip8.index);
+    writer.put_u16(chip8.pc);
+    for address in chip8.stack {
+        writer.put_u16(address);
+    }
+    writer.put_u16(chip8.sp);
+    writer.put_u8(chip8.delay_timer);
+    writer.put_u8(chip8.sound_timer);
+    writer.put_bytes(&chip8.keypad);
+    // One byte per pixel, as the format had before the display was packed
+    writer.put_bytes(
+        &chip8
+            .display
+            .to_bytes(chip8.video_width(), chip8.video_height()),
+    );
+    writer.put_bool(chip8.hires);
+    writer.put_u8(chip8.selected_planes);
+    writer.put_bytes(&chip8.audio_pattern);
+    writer.put_u8(chip8.pitch);
+    writer.put_bytes(&chip8.rpl_flags);
+    writer.put_bool(chip8.halted);
+    writer.put_u16(chip8.opcode);
+    writer.put_u8(quirks_to_bits(&chip8.quirks));
+    writer.put_u64(chip8.rng.state());
+    writer.put_bool(chip8.vblank);
+    writer.put_bool(chip8.audio_pattern_loaded);
+}
+
+pub(crate) fn read_machine(
+    chip8: &mut Chip8,
+    reader: &mut StateReader,
+) -> Result<(), SaveStateError> {
+    chip8.registers = reader.get_array()?;
+    chip8.memory = reader.get_array()?;
+    chip8.invalidate_code(0..MEMORY_SIZE);
+    chip8.index = reader.get_u32()?;
+    chip8.pc = reader.get_u16()?;
+    for address in chip8.stack.iter_mut() {
+        *address = reader.get_u16()?;
+    }
+    chip8.sp = reader.get_u16()?;
+    chip8.delay_timer = reader.get_u8()?;
+    chip8.sound_timer = reader.get_u8()?;
+    chip8.keypad = reader.get_array()?;
+    let display = reader.get_array()?;
+    chip8.hires = reader.get_bool()?;
+    chip8.display = FrameBuffer::from_bytes(&display, chip8.video_width(), chip8.video_height());
+    chip8.selected_planes = reader.get_u8()?;
+    chip8.audio_pattern = reader.get_array()?;
+    chip8.pitch = reader.get_u8()?;
+    chip8.rpl_flags = reader.get_array()?;
+    chip8.halted = reader.get_bool()?;
+    chip8.opcode = reader.get_u16()?;
+    chip8.quirks = quirks_from_bits(reader.get_u8()?);
+    chip8.rng = Rng::from_state(reader.get_u64()?);
+    chip8.vblank = reader.get_bool()?;
+    chip8.audio_pattern_loaded = reader.get_bool()?;
+
+    // No instruction fits at the last byte of memory, and the handlers
+    // index the stack and the planes without checking
+    if chip8.pc as usize + 1 >= MEMORY_SIZE {
+        return Err(SaveStateError::Corrupt("pc"));
+    }
+    if chip8.sp as usize > STACK_SIZE {
+        return Err(SaveStateError::Corrupt("stack pointer"));
+    }
+    if chip8.selected_planes & !0x3 != 0 {
+        return Err(SaveStateError::Corrupt("plane mask"));
+    }
+
+    Ok(())
+}
+
+impl Chip8 {
+    /// Serializes the whole machine, tagged with the hash of the loaded ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        writer.put_bytes(&SAVE_STATE_MAGIC);
+        writer.put_u16(SAVE_STATE_VERSION);
+        writer.put_bytes(&self.rom_hash);
+        write_machine(self, &mut writer);
+
+        writer.buffer
+    }
+
+    /// Restores a state made by [`Chip8::save_state`]. On error the machine
+    /// is left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(data);
+
+        if reader.get_array::<4>().ok() != Some(SAVE_STATE_MAGIC) {
+            return Err(SaveStateError::NotASaveState);
+        }
+
+        let version = reader.get_u16()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+
+        if reader.get_array::<20>()? != self.rom_hash {
+            return Err(SaveStateError::RomMismatch);
+        }
+
+        let mut restored = self.clone();
+        read_machine(&mut restored, &mut reader)?;
+        *self = restored;
+
+        Ok(())
+    }
+
+    pub fn save_state_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SaveStateError> {
+        fs::write(path, self.save_state())?;
+
+        Ok(())
+    }
+
+    pub fn load_state_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveStateError> {
+        let data = fs::read(path)?;
+
+        self.load_state(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::DISPLAY_BYTES;
+
+    // Offset of `sp` in a state: header, registers, memory, I, pc, stack
+    const SP_OFFSET: usize = 4 + 2 + 20 + 16 + MEMORY_SIZE + 4 + 2 + STACK_SIZE * 2;
+
+    fn machine() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&[0x00, 0xEE]).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut chip8 = machine();
+        chip8.registers[3] = 42;
+        chip8.sp = 2;
+        let state = chip8.save_state();
+
+        let mut restored = machine();
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn rejects_a_stack_pointer_past_the_stack() {
+        let mut state = machine().save_state();
+        state[SP_OFFSET..SP_OFFSET + 2].copy_from_slice(&200u16.to_le_bytes());
+
+        let mut chip8 = machine();
+        let result = chip8.load_state(&state);
+
+        assert!(matches!(result, Err(SaveStateError::Corrupt(_))));
+        // Left untouched
+        assert_eq!(chip8.sp, 0);
+    }
+
+    #[test]
+    fn rejects_a_pc_at_the_last_byte() {
+        let mut state = machine().save_state();
+        // pc comes right before the stack
+        let offset = SP_OFFSET - STACK_SIZE * 2 - 2;
+        assert_eq!(state[offset..offset + 2], 0x200u16.to_le_bytes());
+        state[offset..offset + 2].copy_from_slice(&0xFFFFu16.to_le_bytes());
+
+        let mut chip8 = machine();
+        let result = chip8.load_state(&state);
+
+        assert!(matches!(result, Err(SaveStateError::Corrupt("pc"))));
+        assert_eq!(chip8.pc, 0x200);
+    }
+
+    #[test]
+    fn rejects_a_bad_plane_mask() {
+        let mut state = machine().save_state();
+        // sp, timers, keypad, display and the hires flag
+        let offset = SP_OFFSET + 2 + 2 + 16 + DISPLAY_BYTES + 1;
+        assert_eq!(state[offset], 0x1);
+        state[offset] = 0xFF;
+
+        assert!(matches!(
+            machine().load_state(&state),
+            Err(SaveStateError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_a_truncated_state() {
+        let state = machine().save_state();
+
+        assert!(matches!(
+            machine().load_state(&state[..state.len() - 1]),
+            Err(SaveStateError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn quirks_round_trip_through_bits() {
+        for name in Quirks::PRESET_NAMES {
+            let quirks = Quirks::from_name(name).unwrap();
+
+            assert_eq!(
+                quirks_from_bits(quirks_to_bits(&quirks)),
+                quirks,
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::{Duration, Instant};
+
+// Past this many late frames we stop catching up and resync instead,
+// so a stalled host (window drag, debugger) doesn't fast-forward the game.
+const MAX_CATCH_UP_FRAMES: u32 = 5;
+
+/// Fixed rate frame clock for frontends.
+///
+/// Each frame the frontend should call [`Chip8::run_frame`] once, which
+/// runs the configured number of instructions and ticks the timers, so the
+/// timers run at exactly `frames_per_second` whatever the instruction rate.
+///
+/// [`Chip8::run_frame`]: crate::Chip8::run_frame
+pub struct FrameScheduler {
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl FrameScheduler {
+    pub fn new(frames_per_second: u32) -> Self {
+        FrameScheduler {
+            frame_duration: Duration::from_secs(1) / frames_per_second,
+            next_frame: Instant::now(),
+        }
+    }
+
+    /// How many frames should be run now. Deadlines are absolute, so
+    /// oversleeping one frame is made up for on the next call.
+    pub fn frames_due(&mut self, now: Instant) -> u32 {
+        let mut frames = 0;
+
+        while now >= self.next_frame {
+            frames += 1;
+            self.next_frame += self.frame_duration;
+
+            if frames > MAX_CATCH_UP_FRAMES {
+                self.next_frame = now + self.frame_duration;
+                return 1;
+            }
+        }
+
+        frames
+    }
+
+    pub fn time_until_next_frame(&self, now: Instant) -> Duration {
+        self.next_frame.saturating_duration_since(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_the_frames_that_are_due() {
+        let mut scheduler = FrameScheduler::new(50);
+        let start = scheduler.next_frame;
+
+        assert_eq!(scheduler.frames_due(start), 1);
+        assert_eq!(scheduler.frames_due(start + Duration::from_millis(10)), 0);
+        // Oversleeping is made up for
+        assert_eq!(scheduler.frames_due(start + Duration::from_millis(45)), 2);
+        assert_eq!(
+            scheduler.time_until_next_frame(start + Duration::from_millis(45)),
+            Duration::from_millis(15)
+        );
+    }
+
+    #[test]
+    fn resyncs_after_a_stall() {
+        let mut scheduler = FrameScheduler::new(50);
+        let start = scheduler.next_frame;
+        let stalled = start + Duration::from_secs(1);
+
+        assert_eq!(scheduler.frames_due(stalled), 1);
+        assert_eq!(
+            scheduler.time_until_next_frame(stalled),
+            Duration::from_millis(20)
+        );
+    }
+}
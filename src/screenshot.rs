@@ -0,0 +1,294 @@
+//! Screenshots of the active display.
+//!
+//! PNG keeps the palette colours. PBM and PGM are written in their plain
+//! text variants, so two screenshots can be compared with `diff`.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chip8::Chip8;
+use crate::palette::Palette;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    /// Black and white, any lit plane is black
+    Pbm,
+    /// Grey levels from the brightness of the palette colours
+    Pgm,
+}
+
+impl ImageFormat {
+    pub const NAMES: [&'static str; 3] = ["png", "pbm", "pgm"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "pbm" => Some(ImageFormat::Pbm),
+            "pgm" => Some(ImageFormat::Pgm),
+            _ => None,
+        }
+    }
+
+    /// The format matching the extension of `path`.
+    pub fn from_path(path: &str) -> Option<Self> {
+        Self::from_name(Path::new(path).extension()?.to_str()?)
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Pbm => "pbm",
+            ImageFormat::Pgm => "pgm",
+        }
+    }
+}
+
+/// How a screenshot is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Screenshot {
+    pub format: ImageFormat,
+    /// Every emulated pixel becomes a `scale` x `scale` square
+    pub scale: u32,
+    pub palette: Palette,
+}
+
+impl Screenshot {
+    /// Writes the active display of `chip8` to `path`.
+    pub fn save(&self, chip8: &Chip8, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(chip8, &mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write<W: Write>(&self, chip8: &Chip8, writer: W) -> io::Result<()> {
+        let scale = self.scale.max(1) as usize;
+        let width = chip8.video_width() * scale;
+        let height = chip8.video_height() * scale;
+        let pixels = scale_display(chip8, scale);
+
+        match self.format {
+            ImageFormat::Png => {
+                let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+
+                let data: Vec<u8> = pixels
+                    .iter()
+                    .flat_map(|pixel| {
+                        let [r, g, b, _] = self.palette.color(*pixel).to_be_bytes();
+                        [r, g, b]
+                    })
+                    .collect();
+
+                let mut png = encoder.write_header()?;
+                png.write_image_data(&data)?;
+                png.finish()?;
+                Ok(())
+            }
+            ImageFormat::Pbm => {
+                // In PBM 1 is black, which is what a lit pixel usually is on paper
+                write_plain(writer, "P1", width, height, None, &pixels, |pixel| {
+                    if pixel != 0 { "1" } else { "0" }.to_string()
+                })
+            }
+            ImageFormat::Pgm => {
+                let levels = self.palette.colors.map(luma);
+                write_plain(writer, "P2", width, height, Some(255), &pixels, |pixel| {
+                    levels[pixel as usize].to_string()
+                })
+            }
+        }
+    }
+}
+
+// The active display with every pixel repeated `scale` times in both
+// directions, as plane values 0..=3
+fn scale_display(chip8: &Chip8, scale: usize) -> Vec<u8> {
+    let mut pixels = Vec::new();
+
+    for row in chip8.active_display().chunks(chip8.video_width()) {
+        let scaled: Vec<u8> = row
+            .iter()
+            .flat_map(|pixel| std::iter::repeat_n(*pixel & 0x3, scale))
+            .collect();
+
+        for _ in 0..scale {
+            pixels.extend_from_slice(&scaled);
+        }
+    }
+
+    pixels
+}
+
+// Rec. 601 brightness of an RGBA8888 colour
+fn luma(color: u32) -> u8 {
+    let [r, g, b, _] = color.to_be_bytes();
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+}
+
+// Plain netpbm, one image row per line
+fn write_plain<W: Write>(
+    mut writer: W,
+    magic: &str,
+    width: usize,
+    height: usize,
+    max_value: Option<u32>,
+    pixels: &[u8],
+    sample: impl Fn(u8) -> String,
+) -> io::Result<()> {
+    writeln!(writer, "{}", magic)?;
+    writeln!(writer, "{} {}", width, height)?;
+    if let Some(max_value) = max_value {
+        writeln!(writer, "{}", max_value)?;
+    }
+
+    for row in pixels.chunks(width) {
+        let samples: Vec<String> = row.iter().map(|pixel| sample(*pixel)).collect();
+        writeln!(writer, "{}", samples.join(" "))?;
+    }
+
+    Ok(())
+}
+
+/// `<ROM name>-<UTC date>-<time>.<ext>` next to the ROM, e.g.
+/// `Pong-20240131-235959.123.png`.
+pub fn screenshot_path(rom_filename: &str, format: ImageFormat) -> String {
+    let path = Path::new(rom_filename);
+    let stem = path
+        .file_stem()
+        .map_or("screenshot".into(), |stem| stem.to_string_lossy());
+    let name = format!("{}-{}.{}", stem, timestamp(), format.extension());
+
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            parent.join(name).to_string_lossy().into_owned()
+        }
+        _ => name,
+    }
+}
+
+// Current UTC time as `YYYYMMDD-HHMMSS.mmm`
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = now.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time = seconds % 86400;
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}.{:03}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        now.subsec_millis()
+    )
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian date (Howard Hinnant's
+// algorithm)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(chip8: &Chip8, format: ImageFormat, scale: u32) -> Vec<u8> {
+        let screenshot = Screenshot {
+            format,
+            scale,
+            palette: Palette::CLASSIC,
+        };
+        let mut data = Vec::new();
+        screenshot.write(chip8, &mut data).unwrap();
+
+        data
+    }
+
+    // A plain netpbm row of `width` samples, all `background` except `set`
+    fn row(width: usize, background: &str, set: &[(usize, &str)]) -> String {
+        let mut samples = vec![background; width];
+        for (x, sample) in set {
+            samples[*x] = sample;
+        }
+
+        samples.join(" ")
+    }
+
+    #[test]
+    fn writes_lores_pbm() {
+        let mut chip8 = Chip8::new();
+        chip8.display.draw_row(0, 0, 1 << 127 | 1 << 64);
+        chip8.display.draw_row(1, 31, 1 << 126);
+
+        let data = String::from_utf8(write(&chip8, ImageFormat::Pbm, 1)).unwrap();
+
+        let mut expected = vec!["P1".to_string(), "64 32".to_string()];
+        expected.push(row(64, "0", &[(0, "1"), (63, "1")]));
+        expected.extend((1..31).map(|_| row(64, "0", &[])));
+        expected.push(row(64, "0", &[(1, "1")]));
+        assert_eq!(data, expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn scales_pbm_pixels_into_squares() {
+        let mut chip8 = Chip8::new();
+        chip8.display.draw_row(0, 0, 1 << 127);
+
+        let data = String::from_utf8(write(&chip8, ImageFormat::Pbm, 2)).unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+
+        assert_eq!(lines[..2], ["P1", "128 64"]);
+        assert_eq!(lines.len(), 2 + 64);
+        let lit = row(128, "0", &[(0, "1"), (1, "1")]);
+        assert_eq!(lines[2..4], [lit.as_str(), lit.as_str()]);
+        assert_eq!(lines[4], row(128, "0", &[]));
+    }
+
+    #[test]
+    fn writes_hires_pgm_from_the_palette_brightness() {
+        let mut chip8 = Chip8::new();
+        chip8.OP_00FF().unwrap();
+        chip8.display.draw_row(1, 0, 1 << 127);
+        chip8.display.draw_row(0, 63, 1);
+        chip8.display.draw_row(1, 63, 1 | 1 << 1);
+
+        let data = String::from_utf8(write(&chip8, ImageFormat::Pgm, 1)).unwrap();
+
+        let mut expected = vec!["P2".to_string(), "128 64".to_string(), "255".to_string()];
+        expected.push(row(128, "0", &[(0, "170")]));
+        expected.extend((1..63).map(|_| row(128, "0", &[])));
+        expected.push(row(128, "0", &[(126, "170"), (127, "85")]));
+        assert_eq!(data, expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn writes_png_at_the_active_resolution() {
+        let mut chip8 = Chip8::new();
+        chip8.OP_00FF().unwrap();
+
+        let data = write(&chip8, ImageFormat::Png, 3);
+
+        assert_eq!(data[..8], *b"\x89PNG\r\n\x1a\n");
+        // The IHDR width and height
+        assert_eq!(data[16..24], [0, 0, 1, 128, 0, 0, 0, 192]);
+    }
+}
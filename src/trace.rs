@@ -0,0 +1,190 @@
+//! Instruction traces, one line per executed instruction.
+//!
+//! Every line has the same fixed-width layout, so traces of two runs can be
+//! compared with `diff`:
+//!
+//! ```text
+//! cycle      pc   op   V0 .. VF                                        I        SP DT ST  mnemonic
+//! 0000000000 0200 6A02 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00000000 00 00 00  LD VA, 0x02
+//! ```
+//!
+//! The registers are the state before the instruction runs. Numbers are
+//! hexadecimal, except for the cycle, which counts executed instructions
+//! from 0 in decimal. The mnemonic is what was executed, so opcodes the
+//! interpreter accepts loosely (e.g. `9xy1`) show as the instruction they
+//! ran as.
+
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use crate::chip8::Chip8;
+use crate::instruction::Instruction;
+
+/// Which instructions end up in the trace. `None` lets everything through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub addresses: Option<RangeInclusive<u16>>,
+    pub cycles: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, cycle: u64, pc: u16) -> bool {
+        self.addresses
+            .as_ref()
+            .is_none_or(|addresses| addresses.contains(&pc))
+            && self
+                .cycles
+                .as_ref()
+                .is_none_or(|cycles| cycles.contains(&cycle))
+    }
+}
+
+/// The trace line for the instruction at `pc`, without a newline.
+pub fn trace_line(cycle: u64, chip8: &Chip8) -> String {
+    let pc = chip8.pc as usize;
+    let opcode = match chip8.memory.get(pc..pc + 2) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    };
+
+    let registers: Vec<String> = chip8
+        .registers
+        .iter()
+        .map(|value| format!("{:02X}", value))
+        .collect();
+
+    format!(
+        "{:010} {:04X} {:04X} {} {:08X} {:02X} {:02X} {:02X}  {}",
+        cycle,
+        chip8.pc,
+        opcode,
+        registers.join(" "),
+        chip8.index,
+        chip8.sp,
+        chip8.delay_timer,
+        chip8.sound_timer,
+        Instruction::decode_lenient(opcode)
+    )
+}
+
+/// Writes trace lines for the instructions that pass its filter.
+pub struct Tracer<W: Write> {
+    writer: W,
+    pub filter: TraceFilter,
+    cycle: u64,
+    // Tracing stops at the first write error, reported by `finish`
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(writer: W, filter: TraceFilter) -> Self {
+        Tracer {
+            writer,
+            filter,
+            cycle: 0,
+            error: None,
+        }
+    }
+
+    /// Call right before each instruction, e.g. from
+    /// [`Chip8::run_frame_traced`].
+    pub fn trace(&mut self, chip8: &Chip8) {
+        let cycle = self.cycle;
+        self.cycle += 1;
+
+        if self.error.is_some() || !self.filter.matches(cycle, chip8.pc) {
+            return;
+        }
+
+        if let Err(e) = writeln!(self.writer, "{}", trace_line(cycle, chip8)) {
+            self.error = Some(e);
+        }
+    }
+
+    /// Instructions seen so far, traced or not.
+    pub fn cycles(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Flushes the trace and returns the first error hit while writing it.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(program: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(program).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn formats_fixed_width_lines() {
+        let mut chip8 = machine(&[0x6A, 0x02]);
+        chip8.registers[0xA] = 0x7F;
+        chip8.index = 0x1_0234;
+        chip8.sp = 1;
+        chip8.delay_timer = 0x3C;
+        chip8.sound_timer = 2;
+
+        assert_eq!(
+            trace_line(42, &chip8),
+            "0000000042 0200 6A02 00 00 00 00 00 00 00 00 00 00 7F 00 00 00 00 00 \
+             00010234 01 3C 02  LD VA, 0x02"
+        );
+    }
+
+    #[test]
+    fn shows_what_loosely_decoded_opcodes_ran_as() {
+        let chip8 = machine(&[0x91, 0x21]);
+
+        assert!(trace_line(0, &chip8).ends_with("  SNE V1, V2"));
+    }
+
+    #[test]
+    fn filters_by_address_and_cycle() {
+        let filter = TraceFilter {
+            addresses: Some(0x202..=0x204),
+            cycles: Some(10..=20),
+        };
+
+        assert!(filter.matches(10, 0x202));
+        assert!(filter.matches(20, 0x204));
+        assert!(!filter.matches(9, 0x202));
+        assert!(!filter.matches(21, 0x202));
+        assert!(!filter.matches(15, 0x200));
+        assert!(!filter.matches(15, 0x206));
+        assert!(TraceFilter::default().matches(u64::MAX, 0xFFFF));
+    }
+
+    #[test]
+    fn traces_only_what_passes_the_filter() {
+        // ADD V0, 1 then JP 0x200
+        let mut chip8 = machine(&[0x70, 0x01, 0x12, 0x00]);
+        let filter = TraceFilter {
+            addresses: Some(0x200..=0x200),
+            cycles: Some(2..=5),
+        };
+        let mut trace = Vec::new();
+        let mut tracer = Tracer::new(&mut trace, filter);
+
+        for _ in 0..8 {
+            tracer.trace(&chip8);
+            chip8.step().unwrap();
+        }
+        assert_eq!(tracer.cycles(), 8);
+        tracer.finish().unwrap();
+
+        let trace = String::from_utf8(trace).unwrap();
+        let cycles: Vec<&str> = trace.lines().map(|line| &line[..10]).collect();
+        assert_eq!(cycles, ["0000000002", "0000000004"]);
+    }
+}
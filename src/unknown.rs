@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// What the machine does when it fetches an opcode with no handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownOpcodePolicy {
+    /// Stop with `Chip8Error::IllegalOpcode`.
+    Halt,
+    /// Record the opcode and carry on as if it were a no-op, which is what
+    /// this emulator always did before the policy could be chosen.
+    #[default]
+    Continue,
+    /// Like `Halt`, except `0nnn` machine code calls (which only ran on the
+    /// original hardware) are recorded and skipped. `0000` still halts, it
+    /// almost always means the program ran into empty memory.
+    IgnoreMachineCode,
+}
+
+impl UnknownOpcodePolicy {
+    /// Names accepted by [`UnknownOpcodePolicy::from_name`].
+    pub const NAMES: [&'static str; 3] = ["halt", "continue", "ignore-sys"];
+
+    pub fn from_name(name: &str) -> Option<UnknownOpcodePolicy> {
+        match name.to_ascii_lowercase().as_str() {
+            "halt" => Some(UnknownOpcodePolicy::Halt),
+            "continue" => Some(UnknownOpcodePolicy::Continue),
+            "ignore-sys" => Some(UnknownOpcodePolicy::IgnoreMachineCode),
+            _ => None,
+        }
+    }
+}
+
+/// Every unknown opcode the program hit, keyed by address and opcode.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnknownOpcodeReport {
+    hits: BTreeMap<(u16, u16), u64>,
+}
+
+impl UnknownOpcodeReport {
+    pub fn record(&mut self, pc: u16, opcode: u16) {
+        *self.hits.entry((pc, opcode)).or_insert(0) += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.hits.clear();
+    }
+
+    /// `(pc, opcode, times executed)`, ordered by address.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16, u64)> + '_ {
+        self.hits
+            .iter()
+            .map(|(&(pc, opcode), &count)| (pc, opcode, count))
+    }
+}
+
+impl fmt::Display for UnknownOpcodeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (pc, opcode, count) in self.iter() {
+            writeln!(f, "{:#05X}: {:04X} (executed {} times)", pc, opcode, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_address_and_opcode() {
+        let mut report = UnknownOpcodeReport::default();
+        report.record(0x204, 0x5AB1);
+        report.record(0x200, 0xE0FF);
+        report.record(0x204, 0x5AB1);
+        report.record(0x204, 0x5AB4);
+
+        assert_eq!(
+            report.iter().collect::<Vec<_>>(),
+            [(0x200, 0xE0FF, 1), (0x204, 0x5AB1, 2), (0x204, 0x5AB4, 1)]
+        );
+        assert_eq!(
+            report.to_string(),
+            "0x200: E0FF (executed 1 times)\n\
+             0x204: 5AB1 (executed 2 times)\n\
+             0x204: 5AB4 (executed 1 times)\n"
+        );
+
+        report.clear();
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn looks_up_policies_by_name() {
+        for name in UnknownOpcodePolicy::NAMES {
+            assert!(UnknownOpcodePolicy::from_name(name).is_some(), "{}", name);
+        }
+        assert_eq!(
+            UnknownOpcodePolicy::from_name("HALT"),
+            Some(UnknownOpcodePolicy::Halt)
+        );
+        assert_eq!(UnknownOpcodePolicy::from_name("stop"), None);
+    }
+}